@@ -0,0 +1,13 @@
+use vergen::{generate_cargo_keys, ConstantsFlags};
+
+fn main() {
+    // Embeds git and build metadata for `henix info`.
+    // Anything that can't be determined (e.g. when building outside of a git checkout)
+    // is set to `UNKNOWN`.
+    let flags = ConstantsFlags::SHA_SHORT
+        | ConstantsFlags::SEMVER_LIGHTWEIGHT
+        | ConstantsFlags::BUILD_DATE
+        | ConstantsFlags::TARGET_TRIPLE
+        | ConstantsFlags::REBUILD_ON_HEAD_CHANGE;
+    generate_cargo_keys(flags).expect("Unable to generate the cargo keys!");
+}
@@ -0,0 +1,105 @@
+/// Timing the phases of deployments, for `henix benchmark`.
+use crate::deploy::NodeOutcome;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// The name of the row with the duration of the whole deployment of a node.
+const TOTAL: &str = "total";
+
+/// How long one phase of one deployment took.
+struct Sample {
+    iteration: usize,
+    node: String,
+    phase: &'static str,
+    duration: Duration,
+}
+
+/// The phase timings of the nodes over all iterations.
+#[derive(Default)]
+pub struct Benchmark {
+    samples: Vec<Sample>,
+}
+
+impl Benchmark {
+    /// Records the phase timings of a node that was deployed to in `iteration`.
+    pub fn record(&mut self, iteration: usize, outcome: &NodeOutcome) {
+        let phases = outcome
+            .phase_timings
+            .iter()
+            .map(|(phase, duration)| (phase.name(), *duration))
+            .chain(std::iter::once((TOTAL, outcome.duration)));
+        for (phase, duration) in phases {
+            self.samples.push(Sample {
+                iteration,
+                node: outcome.name.clone(),
+                phase,
+                duration,
+            });
+        }
+    }
+
+    /// Prints the minimum, maximum and mean duration of each phase of each node across the
+    /// iterations, as a table.
+    pub fn print_table(&self) {
+        // By node, then by phase in the order they were first entered.
+        let mut nodes = BTreeMap::<&str, Vec<(&str, Vec<Duration>)>>::new();
+        for sample in &self.samples {
+            let phases = nodes.entry(&sample.node).or_default();
+            match phases.iter_mut().find(|(phase, _)| *phase == sample.phase) {
+                Some((_, durations)) => durations.push(sample.duration),
+                None => phases.push((sample.phase, vec![sample.duration])),
+            }
+        }
+        println!(
+            "{:<24} {:<11} {:>5} {:>9} {:>9} {:>9}",
+            "NODE", "PHASE", "RUNS", "MIN", "MAX", "MEAN"
+        );
+        for (node, phases) in nodes {
+            for (phase, durations) in phases {
+                let min = durations.iter().min().unwrap();
+                let max = durations.iter().max().unwrap();
+                let mean = durations.iter().sum::<Duration>() / durations.len() as u32;
+                println!(
+                    "{:<24} {:<11} {:>5} {:>9} {:>9} {:>9}",
+                    node,
+                    phase,
+                    durations.len(),
+                    format_secs(*min),
+                    format_secs(*max),
+                    format_secs(mean)
+                );
+            }
+        }
+    }
+
+    /// Writes every sample to `path` as CSV, with the columns `iteration,node,phase,duration_ms`.
+    pub fn write_csv(&self, path: &Path) -> Result<()> {
+        let mut csv = "iteration,node,phase,duration_ms\n".to_owned();
+        for sample in &self.samples {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                sample.iteration,
+                csv_field(&sample.node),
+                sample.phase,
+                sample.duration.as_millis()
+            ));
+        }
+        std::fs::write(path, csv).context(format!("Could not write `{}`", path.display()))
+    }
+}
+
+/// Formats `d` in seconds, with millisecond precision.
+fn format_secs(d: Duration) -> String {
+    format!("{:.3}s", d.as_secs_f64())
+}
+
+/// Quotes `field` for CSV if it needs to be.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
@@ -0,0 +1,75 @@
+/// Building the systems of nodes locally, for `henix build`.
+use crate::{
+    git,
+    nix::{self, NixOpts},
+    output::OutputSink,
+    util::LoggingRunner,
+    NodeCfg,
+};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+/// Returns the nodes whose system differs from the one at the Git revision `rev`, including the
+/// nodes that didn't exist yet. Nodes are compared by the derivation of their system, so only
+/// changes that affect what would be built count.
+pub async fn changed_nodes(
+    cfg_dir: &Path,
+    nodes: &[(String, NodeCfg)],
+    rev: &str,
+    override_input: &[String],
+) -> Result<BTreeSet<String>> {
+    // With `--sources`, every flake is compared with its own revision.
+    let mut by_dir = BTreeMap::<&Path, Vec<&str>>::new();
+    for (name, node_cfg) in nodes {
+        by_dir
+            .entry(node_cfg.cfg_dir(cfg_dir))
+            .or_default()
+            .push(name);
+    }
+    let mut changed = BTreeSet::new();
+    for (dir, names) in by_dir {
+        let opts = NixOpts::in_dir(dir).override_input(override_input);
+        let base_url = git::flake_url_at(dir, rev).await?;
+        info!(
+            "Comparing the systems of {} nodes with {}",
+            names.len(),
+            base_url
+        );
+        let current = nix::eval_drv_paths(&opts, ".", &names)
+            .await
+            .context("Could not evaluate the current systems")?;
+        let base = nix::eval_drv_paths(&opts, &base_url, &names)
+            .await
+            .context(format!("Could not evaluate the systems at `{}`", rev))?;
+        for name in names {
+            let current = current.get(name).cloned().flatten();
+            if current.is_none() || current != base.get(name).cloned().flatten() {
+                changed.insert(name.to_owned());
+            }
+        }
+    }
+    Ok(changed)
+}
+
+/// Builds the system of the node `name` from the flake in `flake_dir`, without deploying it,
+/// logging the build output. Returns the store path of the system.
+#[tracing::instrument(
+    name = "local_build",
+    skip(name, flake_dir, override_input),
+    fields(node = name)
+)]
+pub async fn build_node(name: &str, flake_dir: &Path, override_input: &[String]) -> Result<String> {
+    info!("Building system locally");
+    let mut opts = NixOpts::in_dir(flake_dir).override_input(override_input);
+    opts.extra_args.push("--print-build-logs".to_owned());
+    opts.runner = Arc::new(LoggingRunner(OutputSink::Log));
+    let results = nix::build(&opts, &[&nix::toplevel_installable(name)]).await?;
+    results
+        .into_iter()
+        .next()
+        .and_then(|result| result.outputs.get("out").cloned())
+        .ok_or_else(|| anyhow!("`nix build` did not print the store path of the system"))
+}
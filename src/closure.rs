@@ -0,0 +1,70 @@
+/// Measuring the closures of the systems on nodes, for `--report-closure-size`.
+use crate::{meta, remote::RemoteExecutor, NodeCfg};
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// The size of the closure of a store path.
+#[derive(Debug, Clone, Copy)]
+pub struct ClosureSize {
+    pub bytes: u64,
+    pub paths: usize,
+}
+
+/// Measures the closure of `path` (e.g. `/run/current-system`) on the node, with
+/// `nix path-info`.
+pub async fn measure(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    path: &str,
+) -> Result<ClosureSize> {
+    // Every path in the closure, with the size of its own closure.
+    let out = meta::remote_output(
+        remote,
+        node_cfg,
+        "nix",
+        &["path-info", "--recursive", "--closure-size", path],
+    )
+    .await?
+    .ok_or_else(|| anyhow!("`nix path-info` failed for {}", path))?;
+    let mut size = ClosureSize { bytes: 0, paths: 0 };
+    for line in out.lines() {
+        let bytes = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|bytes| bytes.parse::<u64>().ok())
+            .ok_or_else(|| anyhow!("Unexpected output of `nix path-info`: {}", line))?;
+        // `path` itself depends on every other path, so its closure is the largest.
+        size.bytes = size.bytes.max(bytes);
+        size.paths += 1;
+    }
+    Ok(size)
+}
+
+/// How the closure of a node's system changed with a deployment.
+#[derive(Debug, Clone, Copy)]
+pub struct ClosureChange {
+    pub before: ClosureSize,
+    pub after: ClosureSize,
+}
+
+impl ClosureChange {
+    /// How many bytes the closure grew by, negative if it shrank.
+    pub fn growth(&self) -> i64 {
+        self.after.bytes as i64 - self.before.bytes as i64
+    }
+}
+
+impl fmt::Display for ClosureChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} bytes ({:+}), {} -> {} paths ({:+})",
+            self.before.bytes,
+            self.after.bytes,
+            self.growth(),
+            self.before.paths,
+            self.after.paths,
+            self.after.paths as i64 - self.before.paths as i64
+        )
+    }
+}
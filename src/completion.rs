@@ -0,0 +1,75 @@
+/// Shell completion scripts, for `henix completion`.
+use crate::util;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use structopt::clap::{App, Shell};
+
+/// The zsh function that completes node names, from the cache of the configuration in the
+/// current directory. Evaluating them on every completion would be too slow, so they are only
+/// evaluated (with `henix completion --cache-nodes`) if they aren't cached yet.
+const ZSH_NODES_FUNCTION: &str = r#"
+_henix_nodes() {
+    local cache="${XDG_CACHE_HOME:-$HOME/.cache}/henix/completions/nodes-${${PWD:A}//\//%}"
+    [[ -r $cache ]] || henix completion --cache-nodes >/dev/null 2>&1
+    local -a nodes
+    [[ -r $cache ]] && nodes=(${(f)"$(<$cache)"})
+    _describe 'node' nodes
+}
+"#;
+
+/// Returns the file that the names of the nodes of the configuration in `cfg_dir` are cached in.
+fn nodes_cache_path(cfg_dir: &Path) -> Result<PathBuf> {
+    let cfg_dir = cfg_dir
+        .canonicalize()
+        .unwrap_or_else(|_| cfg_dir.to_owned());
+    // The same name as in `ZSH_NODES_FUNCTION`.
+    let name = format!("nodes-{}", cfg_dir.display().to_string().replace('/', "%"));
+    Ok(util::cache_dir()?.join("completions").join(name))
+}
+
+/// Caches the names of the nodes of the configuration in `cfg_dir`, for the zsh completion of
+/// `--target`. Returns the cache file.
+pub fn cache_nodes<'a>(cfg_dir: &Path, nodes: impl Iterator<Item = &'a String>) -> Result<PathBuf> {
+    let path = nodes_cache_path(cfg_dir)?;
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(dir).context(format!("Could not create `{}`", dir.display()))?;
+    let contents = nodes.map(|name| format!("{}\n", name)).collect::<String>();
+    std::fs::write(&path, contents).context(format!("Could not write `{}`", path.display()))?;
+    Ok(path)
+}
+
+/// Returns the completion script of `app` for `shell`.
+pub fn script(mut app: App, shell: Shell) -> Result<String> {
+    let mut script = Vec::new();
+    app.gen_completions_to("henix", shell, &mut script);
+    let mut script =
+        String::from_utf8(script).context("The completion script is not valid UTF-8")?;
+    if let Shell::Zsh = shell {
+        // clap can't complete values with a function, so it is added to every `--target`.
+        script = script
+            .lines()
+            .map(|line| {
+                let is_target = line.starts_with("'*-t+[") || line.starts_with("'*--target=[");
+                match line.strip_suffix("]' \\") {
+                    Some(option) if is_target => format!("{}]: :_henix_nodes' \\\n", option),
+                    _ => format!("{}\n", line),
+                }
+            })
+            .collect();
+        script.push_str(ZSH_NODES_FUNCTION);
+    }
+    Ok(script)
+}
+
+/// How the completion script for `shell` is usually installed.
+pub fn install_hint(shell: Shell) -> &'static str {
+    match shell {
+        Shell::Bash => {
+            "henix completion --shell bash > ~/.local/share/bash-completion/completions/henix"
+        }
+        Shell::Zsh => "henix completion --shell zsh > \"${fpath[1]}/_henix\"",
+        Shell::Fish => "henix completion --shell fish > ~/.config/fish/completions/henix.fish",
+        Shell::PowerShell => "henix completion --shell powershell >> $PROFILE",
+        Shell::Elvish => "henix completion --shell elvish >> ~/.elvish/rc.elv",
+    }
+}
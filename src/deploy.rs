@@ -1,121 +1,2531 @@
 /// Does the actual deployment.
-use crate::{nix, ssh, util, DeployOpts, NodeCfg};
+use crate::{
+    closure::{self, ClosureChange, ClosureSize},
+    error::HenixError,
+    events::{self, EventKind, EventStream, NodeEvents, Phase},
+    history, meta, nix,
+    output::{self, NodeLog, OutputMode, OutputSink},
+    remote::{self, RemoteExecutor},
+    ssh, state,
+    util::{self, CommandRunner, LoggingRunner},
+    verify, Compress, CopyOpts, DeployOpts, HostKeyOpts, NodeCfg, RebuildOpts,
+};
 use anyhow::{anyhow, Context, Result};
 use std::path::Path;
-use tokio::process;
-use tracing::{error, info, warn};
+use std::process::ExitStatus;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Barrier, Notify};
+use tokio::{process, time};
+use tracing::{debug, error, info, warn};
 
+/// The phases of a deployment that `--from-phase` can start from, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeployPhase {
+    Copy,
+    Build,
+    /// Recording the metadata and linking `/etc/henix/latest`.
+    Link,
+}
+
+impl DeployPhase {
+    pub const VARIANTS: &'static [&'static str] = &["copy", "build", "link"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DeployPhase::Copy => "copy",
+            DeployPhase::Build => "build",
+            DeployPhase::Link => "link",
+        }
+    }
+}
+
+impl FromStr for DeployPhase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "copy" => Ok(DeployPhase::Copy),
+            "build" => Ok(DeployPhase::Build),
+            "link" => Ok(DeployPhase::Link),
+            _ => Err(anyhow!("Unknown phase `{}`", s)),
+        }
+    }
+}
+
+/// The actions of a system's `switch-to-configuration`, for `--switch-action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchAction {
+    Switch,
+    Test,
+    Boot,
+    DryActivate,
+}
+
+impl SwitchAction {
+    pub const VARIANTS: &'static [&'static str] = &["switch", "test", "boot", "dry-activate"];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SwitchAction::Switch => "switch",
+            SwitchAction::Test => "test",
+            SwitchAction::Boot => "boot",
+            SwitchAction::DryActivate => "dry-activate",
+        }
+    }
+}
+
+impl FromStr for SwitchAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "switch" => Ok(SwitchAction::Switch),
+            "test" => Ok(SwitchAction::Test),
+            "boot" => Ok(SwitchAction::Boot),
+            "dry-activate" => Ok(SwitchAction::DryActivate),
+            _ => Err(anyhow!("Unknown action `{}`", s)),
+        }
+    }
+}
+
+/// The local configuration that is deployed.
+#[derive(Clone, Copy)]
+pub struct LocalCfg<'a> {
+    pub dir: &'a Path,
+    pub hash: &'a str,
+    /// The store path of the node's system, if it was evaluated locally (`--eval-locally`).
+    pub toplevel: Option<&'a str>,
+    /// The Git revision of the configuration, recorded in the metadata on the node.
+    pub git_rev: Option<&'a str>,
+    /// Flake input overrides, as input names and flake URLs (`--override-input`).
+    pub override_input: &'a [String],
+    /// The directory the local state is kept for, which differs from `dir` with `--sources`.
+    pub state_dir: &'a Path,
+    /// Asks before building each node, with `--confirm-per-node`.
+    pub confirmation: Option<&'a Confirmation>,
+    /// Cancels the node once the deployment is aborted (`--fail-fast`), unless it is already
+    /// activating the new system.
+    pub abort: Option<&'a Abort>,
+}
+
+/// Asks whether to deploy to each node before it is built and activated, for
+/// `--confirm-per-node`. Nodes are deployed to one at a time then, so only one asks at a time.
+#[derive(Default)]
+pub struct Confirmation {
+    /// Whether all remaining nodes were confirmed at once.
+    all: AtomicBool,
+    /// Whether the deployment was quit.
+    quit: AtomicBool,
+}
+
+impl Confirmation {
+    /// Whether the deployment was quit, so that no other node should be deployed to.
+    pub fn quit(&self) -> bool {
+        self.quit.load(Ordering::SeqCst)
+    }
+
+    /// Asks whether to deploy to the node, and fails with `NotConfirmed` if not.
+    async fn ask(&self, name: &str, node_cfg: &NodeCfg) -> Result<()> {
+        if self.all.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if self.quit() {
+            return Err(NotConfirmed.into());
+        }
+        let question = format!(
+            "Deploy to {} ({})? [y/N/a(ll)/q(uit)]",
+            name, node_cfg.location
+        );
+        let answer = tokio::task::spawn_blocking(move || util::ask(&question))
+            .await
+            .context("Could not ask for confirmation")??;
+        match answer.as_str() {
+            "y" | "yes" => Ok(()),
+            "a" | "all" => {
+                self.all.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+            "q" | "quit" => {
+                self.quit.store(true, Ordering::SeqCst);
+                Err(NotConfirmed.into())
+            }
+            _ => Err(NotConfirmed.into()),
+        }
+    }
+}
+
+/// The deployment to a node was declined, with `--confirm-per-node`.
+#[derive(thiserror::Error, Debug)]
+#[error("The deployment to the node was not confirmed")]
+struct NotConfirmed;
+
+/// The deployment to a node stopped at a phase boundary, since the deployment was terminated.
+#[derive(thiserror::Error, Debug)]
+#[error("The deployment was terminated before the node finished")]
+struct Stopped;
+
+/// How long the nodes may take to finish their current phase once the deployment is terminated.
+pub const TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Tells the nodes that are still being deployed to that the deployment was aborted, for
+/// `--fail-fast`, or terminated (on SIGTERM).
+#[derive(Default)]
+pub struct Abort {
+    aborted: AtomicBool,
+    /// Whether the nodes may finish their current phase first.
+    graceful: AtomicBool,
+    notify: Notify,
+}
+
+impl Abort {
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    /// Aborts the deployment, but lets the nodes finish their current phase (for up to
+    /// `TERMINATE_GRACE_PERIOD`) and stop before starting the next one.
+    pub fn terminate(&self) {
+        self.graceful.store(true, Ordering::SeqCst);
+        self.abort();
+    }
+
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the deployment is aborted.
+    async fn wait(&self) {
+        // Registered before checking, so that an `abort` in between isn't missed.
+        let notified = self.notify.notified();
+        if self.aborted.load(Ordering::SeqCst) {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// Checks the node's `rsyncArgs`, which must each be a single option, so that they can't add
+/// another source or destination. Options that would make rsync act on the local
+/// configuration, or run as something other than a client, are rejected too.
+fn check_rsync_args(args: &[String]) -> Result<()> {
+    const REJECTED: &[&str] = &["--remove-source-files", "--server", "--sender", "--daemon"];
+    for arg in args {
+        if !arg.starts_with('-') {
+            return Err(anyhow!(
+                "rsyncArgs must only contain options (with their value as e.g. `--chown=user`), but `{}` is not one",
+                arg
+            ));
+        }
+        let name = arg.split('=').next().unwrap_or(arg);
+        if REJECTED.contains(&name) {
+            return Err(anyhow!("`{}` is not allowed in rsyncArgs", name));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `cfg_dir` has a `flake.nix`, so that an empty or wrong directory isn't copied over
+/// the nodes' copies of the configuration and deployed.
+pub fn check_cfg_dir(cfg_dir: &Path) -> Result<()> {
+    if cfg_dir.join("flake.nix").is_file() {
+        return Ok(());
+    }
+    let empty = match std::fs::read_dir(cfg_dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) => {
+            return Err(e).context(format!(
+                "Could not read the configuration directory `{}`",
+                cfg_dir.display()
+            ))
+        }
+    };
+    Err(anyhow!(
+        "The configuration directory `{}` {}, refusing to copy it to the nodes (pass --allow-empty to do so anyway)",
+        cfg_dir.display(),
+        if empty { "is empty" } else { "has no `flake.nix`" }
+    ))
+}
+
+/// The rsync arguments that select which files of the configuration directory are copied,
+/// including the patterns in `exclude_from` (`--exclude-from`), which is checked to be readable.
+fn rsync_filter_args(exclude_from: Option<&Path>) -> Result<Vec<String>> {
+    let mut args = vec![
+        "--exclude=.git/".to_owned(),
+        // Also excludes the history's lock and temporary files.
+        format!("--exclude=/{}*", history::DEFAULT_FILE_NAME),
+        format!("--exclude=/{}/", output::DEFAULT_LOG_DIR),
+        "-F".to_owned(), // Allow `.rsync-filter` files to be used
+    ];
+    if let Some(path) = exclude_from {
+        // rsync would only warn about it, and copy the files that should have been excluded.
+        std::fs::File::open(path)
+            .context(format!("Could not read exclude file `{}`", path.display()))?;
+        args.push(format!("--exclude-from={}", path.display()));
+    }
+    Ok(args)
+}
+
+/// The rsync arguments that decide what ends up in the node's copy of the configuration, other
+/// than the node's `rsyncArgs`, which go after all of henix's own arguments. `copy_config` and
+/// `copied_files_hash` both use them, so that the hash is of exactly what is copied.
+fn rsync_copy_args(node_cfg: &NodeCfg, copy_opts: &CopyOpts, delete: bool) -> Result<Vec<String>> {
+    check_rsync_args(&node_cfg.rsync_args)?;
+    let mut args = rsync_filter_args(copy_opts.exclude_from.as_deref())?;
+    args.push("-a".to_owned()); // Archive mode, preserve symlinks, permissions, devices, etc.
+    if delete {
+        args.push("--delete".to_owned()); // Remove files on the remote not present locally
+    }
+    Ok(args)
+}
+
+/// Copies `cfg_dir` with rsync and `args` to a private temporary directory, and returns the
+/// `nix-hash` of the copy.
+async fn staged_hash(cfg_dir: &Path, args: &[String]) -> Result<String> {
+    let staging = tempfile::tempdir().context("Could not create a temporary directory")?;
+    let mut cfg_dir_with_slash = cfg_dir.to_owned();
+    cfg_dir_with_slash.push("");
+    let out = process::Command::new(util::bin("rsync"))
+        .args(args)
+        .arg(cfg_dir_with_slash)
+        .arg(staging.path())
+        .output()
+        .await
+        .context("Could not execute rsync to collect the copied files")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "Could not collect the copied files, with stderr:\n{}",
+            String::from_utf8_lossy(&out.stderr)
+        ));
+    }
+    Ok(nix::hash(&nix::NixOpts::default(), staging.path()).await?)
+}
+
+/// Returns the configuration hash of `cfg_dir`, i.e. the `nix-hash` of the files that are copied
+/// to nodes by default, so that the history, its lock, the logs and `.git` don't change it.
+pub async fn cfg_hash(cfg_dir: &Path) -> Result<String> {
+    let mut args = rsync_filter_args(None)?;
+    args.push("-a".to_owned());
+    staged_hash(cfg_dir, &args).await
+}
+
+/// Returns the `nix-hash` of what copying `cfg_dir` to the node puts into an empty
+/// `/etc/henix/{hash}`, i.e. what `nix-hash` of the copy on the node should be.
+/// Unlike the configuration hash, this also leaves out the files excluded with `--exclude-from`
+/// or the node's `rsyncArgs`.
+pub async fn copied_files_hash(
+    cfg_dir: &Path,
+    node_cfg: &NodeCfg,
+    copy_opts: &CopyOpts,
+    delete: bool,
+) -> Result<String> {
+    let mut args = rsync_copy_args(node_cfg, copy_opts, delete)?;
+    args.extend(node_cfg.rsync_args.iter().cloned());
+    staged_hash(cfg_dir, &args).await
+}
+
+/// With `--compress auto`, configurations up to this size (in bytes) are never compressed.
+const AUTO_COMPRESS_THRESHOLD: u64 = 1 << 20;
+
+/// Returns the total size of the files in `dir`, leaving out `.git`.
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if entry.file_name() != ".git" {
+                size += dir_size(&entry.path())?;
+            }
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Whether rsync should compress the configuration while copying it to the node.
+async fn should_compress(compress: Compress, node_cfg: &NodeCfg, cfg_dir: &Path) -> bool {
+    match compress {
+        Compress::Off => false,
+        Compress::On => true,
+        Compress::Auto => {
+            if node_cfg.is_local() {
+                return false;
+            }
+            match dir_size(cfg_dir) {
+                Ok(size) if size <= AUTO_COMPRESS_THRESHOLD => return false,
+                Ok(_) => {}
+                Err(e) => warn!("Could not determine the size of the config: {:?}", e),
+            }
+            let host = match ssh::RemoteTarget::of(node_cfg) {
+                Ok(target) => target.host,
+                Err(_) => return true,
+            };
+            match tokio::net::lookup_host((host, 0)).await {
+                Ok(mut addrs) => !addrs.any(|addr| util::is_on_local_subnet(addr.ip())),
+                // It may be an alias from the SSH config, which only SSH resolves.
+                Err(_) => true,
+            }
+        }
+    }
+}
+
+/// Returns the number in an rsync `--stats` line such as `Total file size: 1,234 bytes`.
+fn parse_stat(lines: &[String], name: &str) -> Option<u64> {
+    let line = lines.iter().find_map(|line| line.strip_prefix(name))?;
+    let number = line
+        .trim_start_matches(':')
+        .trim()
+        .split(' ')
+        .next()?
+        .replace(',', "");
+    number.parse().ok()
+}
+
+/// Logs how much less was transferred because of `--link-dest`, from rsync's `--stats` output.
+fn log_link_dest_savings(link_dest: &str, stats: &[String]) {
+    let total = parse_stat(stats, "Total file size");
+    let transferred = parse_stat(stats, "Total transferred file size");
+    match (total, transferred) {
+        (Some(total), Some(transferred)) => info!(
+            "Transferred {} of {} bytes, the rest is hardlinked from {} ({}% saved)",
+            transferred,
+            total,
+            link_dest,
+            (transferred * 100)
+                .checked_div(total)
+                .map_or(0, |percent| 100u64.saturating_sub(percent))
+        ),
+        _ => debug!("Could not find the transfer size in rsync's output"),
+    }
+}
+
+/// Returns the config `/etc/henix/latest` points to, which unchanged files of the config with
+/// hash `cfg_hash` can be hardlinked from. Returns `None` if there is no such config (e.g. if
+/// `latest` doesn't exist or is dangling), or if it is the same config.
+async fn previous_config(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+) -> Result<Option<String>> {
+    // rsync needs the real path, since it resolves `--link-dest` relative to the destination.
+    let latest =
+        match meta::remote_output(remote, node_cfg, "readlink", &["-f", "/etc/henix/latest"])
+            .await?
+        {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+    if latest == format!("/etc/henix/{}", cfg_hash)
+        || !remote_path_exists(remote, node_cfg, "-d", &latest).await?
+    {
+        return Ok(None);
+    }
+    Ok(Some(latest))
+}
+
+/// How `copy_config` connects to the node and treats what is already on it.
+struct CopyMode<'a> {
+    /// Unchanged files are hardlinked from the config at this path, if given.
+    link_dest: Option<&'a str>,
+    /// Whether files on the node that aren't in the local config are removed (rsync's `--delete`).
+    delete: bool,
+    /// How rsync checks the host key of the node.
+    host_keys: &'a HostKeyOpts,
+}
+
+/// Copies the config to `/etc/henix/{cfg_hash}` on the node, running rsync with `runner`.
+#[tracing::instrument(
+    name = "deploy.copy",
+    skip(node_name, node_cfg, cfg_dir, cfg_hash, copy_opts, mode, runner),
+    fields(node = node_name, hash = cfg_hash, phase = "copy")
+)]
 async fn copy_config(
-    node_location: &str,
-    ssh_port: Option<u16>,
+    node_name: &str,
+    node_cfg: &NodeCfg,
     cfg_dir: &Path,
     cfg_hash: &str,
+    copy_opts: &CopyOpts,
+    mode: &CopyMode<'_>,
+    runner: &dyn CommandRunner,
 ) -> Result<()> {
     info!("Copying files");
+    let copy_args =
+        rsync_copy_args(node_cfg, copy_opts, mode.delete).context(HenixError::RsyncArgs {
+            node: node_name.to_owned(),
+        })?;
     info!("Using rsync to copy config");
     // We need to add a slash after `cfg_dir`,
     // so that rsync copies the *contents* of the directory,
     // rather than the directory itself.
     let mut cfg_dir_with_slash = cfg_dir.to_owned();
     cfg_dir_with_slash.push("");
-    let mut rsync = process::Command::new("rsync");
+    let escalation = remote::escalation(node_cfg);
+    let mut rsync = match escalation {
+        Some(tool) if node_cfg.is_local() => {
+            let mut cmd = process::Command::new(tool);
+            cmd.arg(util::bin("rsync"));
+            cmd
+        }
+        _ => process::Command::new(util::bin("rsync")),
+    };
+    rsync
+        .kill_on_drop(true) // Don't keep copying if the deployment is cancelled
+        .args(copy_args)
+        .arg("--mkpath"); // Equivalent of `mkdir -p` on the remote path
+    if !mode.delete {
+        debug!("Keeping files on the node that aren't in the local config");
+    }
+    let compress = node_cfg.compress.unwrap_or(copy_opts.compress);
+    let compress_level = node_cfg.compress_level.or(copy_opts.compress_level);
+    if should_compress(compress, node_cfg, cfg_dir).await {
+        debug!(
+            "Compressing the copy ({:?}, level {:?})",
+            compress, compress_level
+        );
+        rsync.arg("-z");
+        if let Some(level) = compress_level {
+            rsync.arg(format!("--compress-level={}", level));
+        }
+    } else {
+        debug!("Not compressing the copy ({:?})", compress);
+    }
+    if node_cfg.rsync_partial {
+        // Outside of `/etc/henix/{hash}`, so that partial files never end up in the config.
+        rsync.arg(format!("--partial-dir={}", partial_dir(cfg_hash)));
+    }
+    // `--delete` only ever removes files from `/etc/henix/{cfg_hash}`, never from `link_dest`,
+    // which is only read from.
+    if let Some(link_dest) = mode.link_dest {
+        info!("Hardlinking unchanged files from {}", link_dest);
+        rsync
+            .arg(format!("--link-dest={}", link_dest))
+            .arg("--stats");
+    }
+    let destination = format!("/etc/henix/{}", cfg_hash);
+    let destination = if node_cfg.is_local() {
+        destination
+    } else {
+        if let Some(tool) = escalation {
+            // Run rsync on the remote through the escalation program.
+            rsync.arg(format!("--rsync-path={} -n rsync", tool));
+        }
+        rsync
+            .arg("-e") // Use...
+            .arg(ssh::rsync_ssh_command(node_cfg, mode.host_keys)?); // ...this ssh command
+        ssh::RemoteTarget::of(node_cfg)?.rsync_destination(&destination)
+    };
+    // After henix's own arguments, so that they can override them.
+    rsync.args(&node_cfg.rsync_args);
     rsync
-        .arg("--exclude=.git/")
-        .arg("-a") // Archive mode, preserve symlinks, permissions, devices, etc.
-        .arg("-F") // Allow `.rsync-filter` files to be used
-        .arg("--delete") // Remove files on the remote not present locally
-        .arg("--mkpath") // Equivalent of `mkdir -p` on the remote path
-        .arg("-e") // Use...
-        .arg(ssh_port.map_or_else(|| "ssh".to_owned(), |port| format!("ssh -p {}", port))) // ...this ssh command
         .arg(cfg_dir_with_slash) // Copy the contents of the current directory...
-        .arg(format!("root@{}:/etc/henix/{}", node_location, cfg_hash)); // to `/etc/henix/{hash}` on the remote
-    let rsync = util::proxy_output_to_logging("rsync", rsync)
+        .arg(destination); // to `/etc/henix/{hash}` on the node
+    debug!("Running {:?}", rsync.as_std());
+    let rsync = runner
+        .output(rsync)
         .await
         .context("Could not execute rsync to copy files")?;
-    if !rsync.success() {
-        return Err(anyhow!(format!(
-            "Could not rsync files to location `{}` (rsync exited with {})",
-            node_location,
-            rsync
-                .code()
-                .map_or_else(|| "<unknown>".to_owned(), |x| i32::to_string(&x)),
-        )));
+    let lines = |out: &[u8]| {
+        String::from_utf8_lossy(out)
+            .lines()
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+    };
+    if !rsync.status.success() {
+        return Err(HenixError::Copy {
+            node: node_name.to_owned(),
+            exit_code: rsync.status.code(),
+            stderr_tail: lines(&rsync.stderr),
+        }
+        .into());
+    }
+    if let Some(link_dest) = mode.link_dest {
+        log_link_dest_savings(link_dest, &lines(&rsync.stdout));
     }
     info!("Copying finished");
     Ok(())
 }
 
-async fn build_config(
-    dep_opts: &DeployOpts,
-    remote: &mut openssh::Session,
+/// The arguments of `nixos-rebuild` to build and activate the configuration on the node.
+fn rebuild_args(
+    rebuild_opts: &RebuildOpts,
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    override_input: &[String],
+) -> Result<Vec<String>> {
+    let mut args = vec![
+        (if rebuild_opts.boot { "boot" } else { "switch" }).to_owned(),
+        "--flake".to_owned(),
+        format!("/etc/henix/{}#{}", cfg_hash, node_name), // FIXME this doesn't escape quotes in the name.
+    ];
+    if let Some(specialisation) = rebuild_opts.specialisation(node_cfg)? {
+        args.push("--specialisation".to_owned());
+        args.push(specialisation.to_owned());
+    }
+    if node_cfg.no_build_nix {
+        args.push("--no-build-nix".to_owned());
+    }
+    if rebuild_opts.show_trace {
+        args.push("--show-trace".to_owned());
+    }
+    push_override_input(&mut args, override_input);
+    Ok(args)
+}
+
+/// The arguments of `nix` to build the system of the node, linked from `out_link`.
+fn build_toplevel_args(
+    rebuild_opts: &RebuildOpts,
     node_name: &str,
     cfg_hash: &str,
-) -> Result<()> {
-    info!("Building config on remote");
-    let mut rebuild = remote.command("nixos-rebuild");
-    rebuild
-        .arg(if dep_opts.boot { "boot" } else { "switch" })
-        .arg("--flake")
-        .arg(format!("/etc/henix/{}#{}", cfg_hash, node_name)); // FIXME this doesn't escape quotes in the name.
-    if dep_opts.show_trace {
-        rebuild.arg("--show-trace");
+    out_link: &str,
+    override_input: &[String],
+) -> Vec<String> {
+    let mut args = vec![
+        "build".to_owned(),
+        "--out-link".to_owned(),
+        out_link.to_owned(),
+        format!(
+            "/etc/henix/{}#nixosConfigurations.\"{}\".config.system.build.toplevel",
+            cfg_hash, node_name
+        ), // FIXME this doesn't escape quotes in the name.
+    ];
+    if rebuild_opts.show_trace {
+        args.push("--show-trace".to_owned());
+    }
+    push_override_input(&mut args, override_input);
+    args
+}
+
+/// The program that activates `toplevel`, or its `specialisation`.
+fn switch_program(toplevel: &str, specialisation: Option<&str>) -> String {
+    match specialisation {
+        Some(specialisation) => format!(
+            "{}/specialisation/{}/bin/switch-to-configuration",
+            toplevel, specialisation
+        ),
+        None => format!("{}/bin/switch-to-configuration", toplevel),
+    }
+}
+
+/// The commands that deploying the configuration to the node runs on it after copying it to
+/// `/etc/henix/{cfg_hash}`, for `henix plan`. `toplevel` is the system that is expected to be
+/// built.
+pub fn planned_commands(
+    rebuild_opts: &RebuildOpts,
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    toplevel: &str,
+    override_input: &[String],
+) -> Result<Vec<String>> {
+    let command = |program: &str, args: &[String]| {
+        std::iter::once(program)
+            .chain(args.iter().map(String::as_str))
+            .map(util::shell_quote)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    if rebuild_opts.switch_action.is_none() && !rebuild_opts.staged {
+        let args = rebuild_args(rebuild_opts, node_name, node_cfg, cfg_hash, override_input)?;
+        return Ok(vec![command(node_cfg.nixos_rebuild(), &args)]);
+    }
+    let out_link = format!("/etc/henix/{}.system", cfg_hash);
+    let args = build_toplevel_args(rebuild_opts, node_name, cfg_hash, &out_link, override_input);
+    let mut commands = vec![command("nix", &args)];
+    let specialisation = rebuild_opts.specialisation(node_cfg)?;
+    let mut switch = |specialisation, action: SwitchAction| {
+        if let SwitchAction::Switch | SwitchAction::Boot = action {
+            let args = ["-p", "/nix/var/nix/profiles/system", "--set", toplevel];
+            let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+            commands.push(command("nix-env", &args));
+        }
+        let program = switch_program(toplevel, specialisation);
+        commands.push(command(&program, &[action.name().to_owned()]));
+    };
+    if let Some(action) = rebuild_opts.switch_action {
+        switch(specialisation, action);
+    }
+    if rebuild_opts.staged {
+        switch(None, SwitchAction::Boot);
+        switch(None, SwitchAction::Test);
     }
-    let rebuild = ssh::proxy_output_to_logging("nixos-rebuild", rebuild)
+    Ok(commands)
+}
+
+#[tracing::instrument(
+    name = "deploy.build",
+    skip(rebuild_opts, remote, node_name, node_cfg, cfg_hash, override_input, sink),
+    fields(node = node_name, hash = cfg_hash, phase = "build")
+)]
+async fn build_config(
+    rebuild_opts: &RebuildOpts,
+    remote: &dyn RemoteExecutor,
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    override_input: &[String],
+    sink: &OutputSink,
+) -> Result<Option<String>> {
+    if rebuild_opts.switch_action.is_some() || rebuild_opts.staged {
+        return build_toplevel(
+            rebuild_opts,
+            remote,
+            node_name,
+            node_cfg,
+            cfg_hash,
+            override_input,
+            sink,
+        )
         .await
-        .context("Rebuild execution failed")?;
+        .map(Some);
+    }
+    if !rebuild_opts.skip_nix_check {
+        check_installed(remote, node_cfg, node_cfg.nixos_rebuild()).await?;
+    }
+    info!("Building config on remote");
+    let args = rebuild_args(rebuild_opts, node_name, node_cfg, cfg_hash, override_input)?;
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let (sink, stderr_tail) = output::StderrTail::wrap(sink);
+    let nixos_rebuild = node_cfg.nixos_rebuild();
+    // `nixos-rebuild` builds and activates in one go, so the timeout covers both.
+    let timeout = node_cfg.activation_timeout_secs;
+    let rebuild = run_logged_with_timeout(
+        remote,
+        node_cfg,
+        "nixos-rebuild",
+        nixos_rebuild,
+        &args,
+        timeout,
+        &sink,
+    )
+    .await
+    .context("Rebuild execution failed")?;
+    let rebuild = match rebuild {
+        Some(rebuild) => rebuild,
+        None => {
+            return Err(HenixError::BuildTimeout {
+                node: node_name.to_owned(),
+                program: nixos_rebuild.to_owned(),
+                after_secs: timeout.unwrap_or_default(),
+            }
+            .into())
+        }
+    };
     if !rebuild.success() {
-        return Err(anyhow!("Rebuild failed"));
+        return Err(HenixError::Build {
+            node: node_name.to_owned(),
+            program: nixos_rebuild.to_owned(),
+            exit_code: rebuild.code(),
+            stderr_tail: stderr_tail.lines(),
+        }
+        .into());
     }
     info!("Finished building config on remote");
+    Ok(None)
+}
+
+/// How long `timeout` on the node waits for a program that timed out to stop after asking it to,
+/// before killing it.
+const REMOTE_KILL_AFTER_SECS: u64 = 10;
+
+/// Runs `program args...` on the node like `RemoteExecutor::run_logged`, stopping it after
+/// `timeout_secs` if given, in which case `None` is returned. It is run under `timeout` on the
+/// node, since only giving up on it locally would leave it running there.
+async fn run_logged_with_timeout(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    name: &str,
+    program: &str,
+    args: &[&str],
+    timeout_secs: Option<u64>,
+    sink: &OutputSink,
+) -> Result<Option<ExitStatus>> {
+    let secs = match timeout_secs {
+        Some(secs) => secs,
+        None => {
+            return remote
+                .run_logged(node_cfg, name, program, args, sink)
+                .await
+                .map(Some)
+        }
+    };
+    let kill_after = format!("--kill-after={}", REMOTE_KILL_AFTER_SECS);
+    let duration = secs.to_string();
+    let mut timeout_args = vec![kill_after.as_str(), duration.as_str(), program];
+    timeout_args.extend(args);
+    let run = remote.run_logged(node_cfg, name, "timeout", &timeout_args, sink);
+    // Only gives up locally if the node doesn't answer anymore.
+    let local_secs = secs + REMOTE_KILL_AFTER_SECS + 30;
+    match time::timeout(Duration::from_secs(local_secs), run).await {
+        // `timeout` exits with 124 if it stopped the program, and 137 if it had to kill it.
+        Ok(Ok(status)) if matches!(status.code(), Some(124) | Some(137)) => Ok(None),
+        Ok(status) => status.map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Only builds the system of the node, without activating it, for `--switch-action` and
+/// `--staged`.
+/// Returns the store path of the built system.
+async fn build_toplevel(
+    rebuild_opts: &RebuildOpts,
+    remote: &dyn RemoteExecutor,
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    override_input: &[String],
+    sink: &OutputSink,
+) -> Result<String> {
+    if !rebuild_opts.skip_nix_check {
+        check_installed(remote, node_cfg, "nix").await?;
+    }
+    info!("Building system on remote");
+    // The link keeps the system from being garbage collected before it is activated.
+    let out_link = format!("/etc/henix/{}.system", cfg_hash);
+    let args = build_toplevel_args(rebuild_opts, node_name, cfg_hash, &out_link, override_input);
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    let (sink, stderr_tail) = output::StderrTail::wrap(sink);
+    let build = remote
+        .run_logged(node_cfg, "nix", "nix", &args, &sink)
+        .await
+        .context("Build execution failed")?;
+    if !build.success() {
+        return Err(HenixError::Build {
+            node: node_name.to_owned(),
+            program: "nix build".to_owned(),
+            exit_code: build.code(),
+            stderr_tail: stderr_tail.lines(),
+        }
+        .into());
+    }
+    let toplevel = meta::remote_output(remote, node_cfg, "readlink", &["-f", &out_link])
+        .await?
+        .ok_or_else(|| anyhow!("Could not resolve {}", out_link))?;
+    info!("Finished building system {} on remote", toplevel);
+    Ok(toplevel)
+}
+
+/// Adds `--override-input {input} {flake-url}` to `args` for each override.
+fn push_override_input(args: &mut Vec<String>, override_input: &[String]) {
+    for input in override_input.chunks(2) {
+        args.push("--override-input".to_owned());
+        args.extend(input.iter().cloned());
+    }
+}
+
+/// Activates the system at `toplevel` (or its `specialisation`) by running its
+/// `switch-to-configuration` with `action`, giving up after the node's `activationTimeoutSecs`.
+async fn switch_to_configuration(
+    remote: &dyn RemoteExecutor,
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    toplevel: &str,
+    specialisation: Option<&str>,
+    action: SwitchAction,
+    sink: &OutputSink,
+) -> Result<()> {
+    if let SwitchAction::Switch | SwitchAction::Boot = action {
+        // As `nixos-rebuild` does, since the boot entries are made from the system profile.
+        let set_profile = remote
+            .run_logged(
+                node_cfg,
+                "nix-env",
+                "nix-env",
+                &["-p", "/nix/var/nix/profiles/system", "--set", toplevel],
+                sink,
+            )
+            .await
+            .context("Could not execute nix-env to set the system profile")?;
+        if !set_profile.success() {
+            return Err(anyhow!("Could not set the system profile to {}", toplevel));
+        }
+    }
+    info!("Running switch-to-configuration {}", action.name());
+    let program = switch_program(toplevel, specialisation);
+    let args = [action.name()];
+    let timeout = node_cfg.activation_timeout_secs;
+    let switch = run_logged_with_timeout(
+        remote,
+        node_cfg,
+        "switch-to-configuration",
+        &program,
+        &args,
+        timeout,
+        sink,
+    )
+    .await
+    .context("switch-to-configuration execution failed")?;
+    let switch = match switch {
+        Some(switch) => switch,
+        None => {
+            return Err(HenixError::ActivationTimeout {
+                node: node_name.to_owned(),
+                after_secs: timeout.unwrap_or_default(),
+            }
+            .into())
+        }
+    };
+    if !switch.success() {
+        return Err(anyhow!("switch-to-configuration {} failed", action.name()));
+    }
+    Ok(())
+}
+
+/// The systemd unit that rolls the node back if the activation isn't confirmed in time, for
+/// `--confirm-timeout`.
+const ROLLBACK_UNIT: &str = "henix-rollback";
+
+/// Schedules rolling the node back to the system it runs now after `secs` seconds, with a
+/// transient systemd timer on the node, so that it also rolls back if henix can't reach it
+/// anymore after activating the new system. `confirm_activation` cancels it.
+async fn schedule_rollback(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    secs: u64,
+) -> Result<()> {
+    let current = meta::remote_output(remote, node_cfg, "readlink", &["-f", "/run/current-system"])
+        .await?
+        .ok_or_else(|| anyhow!("Could not resolve /run/current-system"))?;
+    let profile = meta::system_toplevel(remote, node_cfg).await?;
+    // As `nixos-rebuild switch --rollback` would, but to the exact systems from before.
+    let script = format!(
+        "nix-env -p /nix/var/nix/profiles/system --set {} && {}/bin/switch-to-configuration switch",
+        util::shell_quote(&profile),
+        util::shell_quote(&current)
+    );
+    // A timer left over from an earlier deployment would make `systemd-run` fail.
+    let timer = format!("{}.timer", ROLLBACK_UNIT);
+    meta::remote_output(remote, node_cfg, "systemctl", &["stop", &timer]).await?;
+    meta::remote_output(
+        remote,
+        node_cfg,
+        "systemctl",
+        &["reset-failed", &format!("{}.service", ROLLBACK_UNIT)],
+    )
+    .await?;
+    meta::remote_output(
+        remote,
+        node_cfg,
+        "systemd-run",
+        &[
+            &format!("--unit={}", ROLLBACK_UNIT),
+            "--description=Roll back the deployment of henix, unless it is confirmed",
+            &format!("--on-active={}s", secs),
+            "--timer-property=AccuracySec=1s",
+            "/bin/sh",
+            "-c",
+            &script,
+        ],
+    )
+    .await?
+    .ok_or_else(|| anyhow!("Could not schedule the rollback with `systemd-run`"))?;
+    info!(
+        "Scheduled a rollback to {} in {}s, unless the activation is confirmed",
+        current, secs
+    );
+    Ok(())
+}
+
+/// Checks that the node can still be connected to after activating the new system, and cancels
+/// the rollback that `schedule_rollback` scheduled.
+async fn confirm_activation(
+    dep_opts: &DeployOpts,
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    secs: u64,
+) -> Result<()> {
+    // A new connection, since the new system may e.g. have a firewall that blocks new ones.
+    let remote = time::timeout(
+        Duration::from_secs(secs),
+        remote::reconnect(node_name, node_cfg, &dep_opts.host_keys),
+    )
+    .await
+    .map_err(|_| anyhow!("Timed out"))
+    .and_then(|remote| remote)
+    .context("Could not connect to the node again after activating, it will roll back")?;
+    let timer = format!("{}.timer", ROLLBACK_UNIT);
+    meta::remote_output(&remote, node_cfg, "systemctl", &["stop", &timer])
+        .await?
+        .ok_or_else(|| {
+            anyhow!(
+                "Could not cancel the rollback (`systemctl stop {}`), the node may roll back",
+                timer
+            )
+        })?;
+    info!("Confirmed the activation, cancelled the rollback");
+    Ok(())
+}
+
+/// Runs the node's `identityCheckCmd` and checks that it printed `identityCheckExpected`, so
+/// that nothing is deployed to a machine that only pretends to be the node.
+async fn check_identity(remote: &dyn RemoteExecutor, node_cfg: &NodeCfg) -> Result<()> {
+    let (cmd, expected) = match (
+        &node_cfg.identity_check_cmd,
+        &node_cfg.identity_check_expected,
+    ) {
+        (Some(cmd), Some(expected)) => (cmd, expected),
+        (None, None) => return Ok(()),
+        _ => {
+            return Err(anyhow!(
+                "identityCheckCmd and identityCheckExpected must be set together"
+            ))
+        }
+    };
+    let actual = meta::remote_output(remote, node_cfg, "sh", &["-c", cmd])
+        .await
+        .context("Could not check the identity of the node")?
+        .ok_or_else(|| anyhow!("Identity check `{}` failed on the node", cmd))?;
+    if actual != expected.trim() {
+        return Err(anyhow!(
+            "Identity check `{}` printed `{}`, but `{}` was expected; is this the right machine?",
+            cmd,
+            actual,
+            expected.trim()
+        ));
+    }
+    info!("Identity check passed");
+    Ok(())
+}
+
+/// Marks `e` as a failure to activate the node, unless it already is a more specific one.
+fn activation_failed(node_name: &str, e: anyhow::Error) -> anyhow::Error {
+    if HenixError::find(&e).is_some() {
+        return e;
+    }
+    e.context(HenixError::Activation {
+        node: node_name.to_owned(),
+    })
+}
+
+/// Checks that `program` exists on the node, since running it on a fresh machine without
+/// NixOS fails with a confusing error.
+async fn check_installed(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    program: &str,
+) -> Result<()> {
+    let found = meta::remote_output(
+        remote,
+        node_cfg,
+        "sh",
+        &["-c", &format!("command -v {}", util::shell_quote(program))],
+    )
+    .await
+    .context(format!("Could not check whether {} is installed", program))?;
+    if found.is_none() && program.contains('/') {
+        return Err(anyhow!(
+            "`{}` was not found on the node, or is not executable",
+            program
+        ));
+    }
+    if found.is_none() {
+        return Err(anyhow!("`{program}` was not found on the node. Is NixOS installed on it? If it is, but `{program}` is not on the PATH, pass --skip-nix-check", program = program));
+    }
     Ok(())
 }
 
+/// How far the deployment of a node got.
+struct Progress {
+    current: Phase,
+    completed: Vec<DeployPhase>,
+    /// When the current phase was entered, if any was yet.
+    entered: Option<Instant>,
+    /// How long each phase that was left took, in order.
+    timings: Vec<(Phase, Duration)>,
+    /// How the closure of the system changed, with `--report-closure-size`.
+    closure: Option<ClosureChange>,
+    /// Whether the node waited for the other nodes at the activation barrier.
+    reached_barrier: bool,
+}
+
+impl Progress {
+    /// Enters `phase`, timing the one that is left.
+    fn enter(&mut self, phase: Phase) {
+        let now = Instant::now();
+        if let Some(entered) = self.entered.replace(now) {
+            self.timings.push((self.current, now - entered));
+        }
+        self.current = phase;
+    }
+}
+
 /// Does the actual deployment, doesn't rollback on failure.
+/// How far the deployment got is kept in `progress`.
 async fn process_node_raw(
     dep_opts: &DeployOpts,
-    remote: &mut openssh::Session,
     name: &str,
     node_cfg: &NodeCfg,
-    cfg_dir: &Path,
+    cfg: LocalCfg<'_>,
+    sink: &OutputSink,
+    progress: &Mutex<Progress>,
+    barrier: Option<&Barrier>,
 ) -> Result<()> {
-    let cfg_hash = nix::hash(cfg_dir).await.context("Could not get hash")?;
-    info!("Configuration hash is {}", cfg_hash);
-    copy_config(&node_cfg.location, node_cfg.ssh_port, cfg_dir, &cfg_hash)
-        .await
-        .context("Could not copy config")?;
-    build_config(dep_opts, remote, name, &cfg_hash)
+    let (cfg_dir, cfg_hash) = (cfg.dir, cfg.hash);
+    let enter = |phase| {
+        if matches!(cfg.abort, Some(abort) if abort.is_aborted()) {
+            return Err(Stopped);
+        }
+        progress.lock().unwrap().enter(phase);
+        sink.phase(phase);
+        Ok(())
+    };
+    let complete = |phase| progress.lock().unwrap().completed.push(phase);
+    enter(Phase::Connecting)?;
+    let remote = &remote::connect(name, node_cfg, &dep_opts.host_keys).await?;
+    check_identity(remote, node_cfg).await?;
+    let closure_before = if dep_opts.report_closure_size || dep_opts.max_closure_growth_mb.is_some()
+    {
+        match closure::measure(remote, node_cfg, "/run/current-system").await {
+            Ok(size) => Some(size),
+            Err(e) => {
+                warn!(
+                    "Could not measure the closure of the current system: {:#}",
+                    e
+                );
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if dep_opts.from_phase <= DeployPhase::Copy {
+        enter(Phase::Copying)?;
+        let marker = copied_marker(cfg_hash);
+        let copied_before = remote_path_exists(remote, node_cfg, "-e", &marker).await?;
+        let already_copied = copied_before && !dep_opts.force_copy;
+        let delete = delete_on_copy(&dep_opts.copy, node_cfg, cfg_hash, copied_before);
+        if already_copied && !dep_opts.force {
+            info!(
+                "Config {} already present on remote, not copying it",
+                cfg_hash
+            );
+        } else {
+            if already_copied {
+                info!(
+                    "Config {} already present on remote, copying it again since the deployment is forced",
+                    cfg_hash
+                );
+            }
+            let link_dest = if dep_opts.no_link_dest {
+                None
+            } else {
+                previous_config(remote, node_cfg, cfg_hash)
+                    .await
+                    .context("Could not find the previous config to hardlink from")?
+            };
+            let mode = CopyMode {
+                link_dest: link_dest.as_deref(),
+                delete,
+                host_keys: &dep_opts.host_keys,
+            };
+            let runner = LoggingRunner(sink.clone());
+            util::retry(node_cfg.retry_policy(), HenixError::is_retryable, || {
+                copy_config(
+                    name,
+                    node_cfg,
+                    cfg_dir,
+                    cfg_hash,
+                    &dep_opts.copy,
+                    &mode,
+                    &runner,
+                )
+            })
+            .await
+            .context("Could not copy config")?;
+            mark_copied(remote, node_cfg, cfg_hash).await?;
+        }
+        if dep_opts.verify_copy && !delete {
+            // Files from earlier copies may have been left there.
+            warn!("Not verifying the copied config, since files on the node that aren't in the local config weren't removed");
+        } else if dep_opts.verify_copy {
+            let copied_hash = copied_files_hash(cfg_dir, node_cfg, &dep_opts.copy, delete)
+                .await
+                .context("Could not hash the files to copy")?;
+            verify_copy(remote, node_cfg, cfg_hash, &copied_hash)
+                .await
+                .context("Could not verify the copied config")?;
+        }
+        complete(DeployPhase::Copy);
+    } else {
+        check_config_copied(remote, node_cfg, cfg_hash).await?;
+    }
+    if let Some(confirmation) = cfg.confirmation {
+        confirmation.ask(name, node_cfg).await?;
+    }
+    let specialisation = dep_opts.rebuild.specialisation(node_cfg)?;
+    let built = if dep_opts.from_phase <= DeployPhase::Build {
+        enter(Phase::Building)?;
+        let built = build_config(
+            &dep_opts.rebuild,
+            remote,
+            name,
+            node_cfg,
+            cfg_hash,
+            cfg.override_input,
+            sink,
+        )
         .await
         .context("Could not build config")?;
-    // Link the latest config
-    let link_res = remote
-        .command("ln")
-        .arg("-s")
-        .arg("-f") // Overwite existing destination files
-        .arg(format!("/etc/henix/{}", cfg_hash))
-        .arg("/etc/henix/latest")
-        .status()
-        .await;
-    if let Ok(link_status) = link_res {
-        if link_status.success() {
+        complete(DeployPhase::Build);
+        built
+    } else {
+        None
+    };
+    // Checked before activating, if the new system was built separately.
+    if let (Some(before), Some(toplevel)) = (closure_before, &built) {
+        check_closure_growth(dep_opts, remote, node_cfg, before, toplevel, progress).await?;
+    }
+    enter(Phase::Activating)?;
+    let confirm_timeout = dep_opts.confirm_timeout;
+    if let (Some(toplevel), Some(action)) = (&built, dep_opts.rebuild.switch_action) {
+        if let Some(secs) = confirm_timeout {
+            schedule_rollback(remote, node_cfg, secs)
+                .await
+                .context("Could not schedule the rollback for --confirm-timeout")?;
+        }
+        switch_to_configuration(
+            remote,
+            name,
+            node_cfg,
+            toplevel,
+            specialisation,
+            action,
+            sink,
+        )
+        .await
+        .map_err(|e| activation_failed(name, e))?;
+        if action == SwitchAction::DryActivate {
+            info!("Only did a dry activation, not recording the deployment");
             return Ok(());
         }
     }
-    warn!("Could not symlink /etc/henix/latest to /etc/henix/{hash}. This is more for convenience, but you may not be able to easily find the current configuration if it is not symlinked. Recommended command: ln -s -f /etc/henix/{hash} /etc/henix/latest", hash = cfg_hash);
+    if let (Some(toplevel), true) = (&built, dep_opts.rebuild.staged) {
+        switch_to_configuration(
+            remote,
+            name,
+            node_cfg,
+            toplevel,
+            None,
+            SwitchAction::Boot,
+            sink,
+        )
+        .await
+        .map_err(|e| activation_failed(name, e))?;
+        info!("Staged {}", toplevel);
+        if let Some(barrier) = barrier {
+            info!("Waiting for the other nodes to be staged");
+            progress.lock().unwrap().reached_barrier = true;
+            barrier.wait().await;
+        }
+        if let Some(secs) = confirm_timeout {
+            schedule_rollback(remote, node_cfg, secs)
+                .await
+                .context("Could not schedule the rollback for --confirm-timeout")?;
+        }
+        switch_to_configuration(
+            remote,
+            name,
+            node_cfg,
+            toplevel,
+            None,
+            SwitchAction::Test,
+            sink,
+        )
+        .await
+        .map_err(|e| activation_failed(name, e))?;
+    }
+    if let Some(secs) = confirm_timeout {
+        confirm_activation(dep_opts, name, node_cfg, secs)
+            .await
+            .map_err(|e| activation_failed(name, e))?;
+    }
+    // The node runs the specialisation rather than the system that was built, so that is
+    // recorded instead, and it can't be compared with the system evaluated locally.
+    let (built, expected) = match specialisation {
+        Some(_) => (None, None),
+        None => (built, cfg.toplevel),
+    };
+    let origin = meta::Origin::new(
+        dep_opts.label.as_deref(),
+        cfg.git_rev,
+        dep_opts.rebuild.activation(),
+    );
+    let toplevel = activate(remote, name, node_cfg, cfg_hash, built, origin).await;
+    if let (Some(expected), Some(toplevel)) = (expected, &toplevel) {
+        if expected != toplevel {
+            warn!(
+                "The node built {}, but {} was evaluated locally; are its flake inputs different?",
+                toplevel, expected
+            );
+        }
+    }
+    // `nixos-rebuild` builds and activates in one go, so it can only be checked now.
+    let measured = progress.lock().unwrap().closure.is_some();
+    if let (Some(before), Some(toplevel), false) = (closure_before, &toplevel, measured) {
+        check_closure_growth(dep_opts, remote, node_cfg, before, toplevel, progress).await?;
+    }
+    complete(DeployPhase::Link);
+    if !dep_opts.no_state {
+        save_state(cfg.state_dir, name, cfg_hash, toplevel).await;
+    }
+    // When the new system only runs after a reboot, there is nothing to check yet.
+    if dep_opts.canary.iter().any(|canary| canary == name) && dep_opts.rebuild.activates_now() {
+        check_canary(remote, node_cfg)
+            .await
+            .context(HenixError::HealthCheck {
+                node: name.to_owned(),
+            })?;
+    }
+    Ok(())
+}
+
+/// Where rsync keeps partially copied files of the config with hash `cfg_hash` on the node,
+/// with `rsyncPartial`.
+fn partial_dir(cfg_hash: &str) -> String {
+    format!("/etc/henix/{}.partial", cfg_hash)
+}
+
+/// The file that marks that the config with hash `cfg_hash` was completely copied to the node.
+/// It can't be stored inside `/etc/henix/{hash}`, since that would change the flake.
+fn copied_marker(cfg_hash: &str) -> String {
+    format!("/etc/henix/{}.copied", cfg_hash)
+}
+
+/// Whether copying the config with hash `cfg_hash` to the node removes files from its copy that
+/// aren't in the local one. With `--no-delete-on-first-deploy`, only if it was completely copied
+/// `copied_before`.
+fn delete_on_copy(
+    copy_opts: &CopyOpts,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    copied_before: bool,
+) -> bool {
+    let delete = copy_opts.delete_extraneous(node_cfg);
+    if delete && copy_opts.no_delete_on_first_deploy && !copied_before {
+        info!(
+            "Config {} was never copied to the node before, not removing files from /etc/henix/{} (--no-delete-on-first-deploy)",
+            cfg_hash, cfg_hash
+        );
+        return false;
+    }
+    delete
+}
+
+/// Records that the config with hash `cfg_hash` was completely copied to the node.
+async fn mark_copied(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+) -> Result<()> {
+    let marker = copied_marker(cfg_hash);
+    // Only marked once rsync finished, since an interrupted rsync leaves an incomplete copy.
+    if meta::remote_output(remote, node_cfg, "touch", &[&marker])
+        .await?
+        .is_none()
+    {
+        warn!(
+            "Could not create {}, the config will be copied again next time",
+            marker
+        );
+    }
+    Ok(())
+}
+
+/// Runs `test {test} {path}` on the node, e.g. with `-d` to check whether `path` is a directory.
+async fn remote_path_exists(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    test: &str,
+    path: &str,
+) -> Result<bool> {
+    Ok(meta::remote_output(remote, node_cfg, "test", &[test, path])
+        .await
+        .context(format!("Could not check whether {} exists", path))?
+        .is_some())
+}
+
+/// Checks that the config copied to `/etc/henix/{cfg_hash}` has the hash `copied_hash`, i.e. that
+/// it contains exactly the files that were meant to be copied, for `--verify-copy`.
+async fn verify_copy(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    copied_hash: &str,
+) -> Result<()> {
+    let path = format!("/etc/henix/{}", cfg_hash);
+    let remote_hash = meta::remote_output(remote, node_cfg, "nix-hash", &[&path])
+        .await?
+        .ok_or_else(|| anyhow!("`nix-hash {}` failed on the node", path))?;
+    if remote_hash != copied_hash {
+        return Err(anyhow!(
+            "{} on the node has hash {}, but the copied files have hash {}",
+            path,
+            remote_hash,
+            copied_hash
+        ));
+    }
+    info!("Copied config matches the local files");
     Ok(())
 }
 
-/// Handles the errors, logging, and rollback; `process_node_raw` does the actual deployment.
-#[tracing::instrument(skip(dep_opts, node_cfg, cfg_dir))]
-pub async fn process_node(dep_opts: &DeployOpts, name: &str, node_cfg: NodeCfg, cfg_dir: &Path) {
-    let mut remote;
-    match ssh::connect_to_node(name, &node_cfg).await {
-        Ok(r) => remote = r,
+/// Checks that the configuration was already copied to the node, when skipping the copy phase.
+async fn check_config_copied(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+) -> Result<()> {
+    let path = format!("/etc/henix/{}", cfg_hash);
+    if !remote_path_exists(remote, node_cfg, "-d", &path).await? {
+        return Err(anyhow!(
+            "{} does not exist on the node, deploy from the copy phase first",
+            path
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the hash of the configuration that `/etc/henix/latest` links to on the node.
+async fn latest_config_hash(remote: &dyn RemoteExecutor, node_cfg: &NodeCfg) -> Result<String> {
+    let latest = meta::remote_output(remote, node_cfg, "readlink", &["/etc/henix/latest"])
+        .await?
+        .ok_or_else(|| {
+            anyhow!("/etc/henix/latest does not exist on the node, deploy to it first")
+        })?;
+    let hash = latest.trim_start_matches("/etc/henix/");
+    if hash.is_empty() || hash.contains('/') {
+        return Err(anyhow!(
+            "/etc/henix/latest links to {}, which is not a configuration copied by henix",
+            latest
+        ));
+    }
+    Ok(hash.to_owned())
+}
+
+/// Checks that the canary node is running the system that was just deployed.
+async fn check_canary(remote: &dyn RemoteExecutor, node_cfg: &NodeCfg) -> Result<()> {
+    info!("Checking canary");
+    match verify::check(remote, node_cfg).await? {
+        verify::NodeStatus::InSync { .. } => {
+            info!("Canary is running the deployed system");
+            Ok(())
+        }
+        status => Err(anyhow!("Canary is {}", status)),
+    }
+}
+
+/// Measures the closure of the new system `toplevel` and records how it changed since `before`
+/// in `progress`. Fails if it grew by more than `--max-closure-growth-mb`.
+async fn check_closure_growth(
+    dep_opts: &DeployOpts,
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    before: ClosureSize,
+    toplevel: &str,
+    progress: &Mutex<Progress>,
+) -> Result<()> {
+    let after = match closure::measure(remote, node_cfg, toplevel).await {
+        Ok(after) => after,
         Err(e) => {
-            error!("{:?}", e);
+            warn!("Could not measure the closure of the new system: {:#}", e);
+            return Ok(());
+        }
+    };
+    let change = ClosureChange { before, after };
+    info!("Closure of the system: {}", change);
+    progress.lock().unwrap().closure = Some(change);
+    if let Some(max_mb) = dep_opts.max_closure_growth_mb {
+        if change.growth() > (max_mb * 1024 * 1024) as i64 {
+            return Err(anyhow!(
+                "The closure of the new system grew by {} bytes, more than --max-closure-growth-mb {}",
+                change.growth(),
+                max_mb
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Saves the deployed system of the node to the local state.
+async fn save_state(cfg_dir: &Path, name: &str, cfg_hash: &str, toplevel: Option<String>) {
+    let toplevel = match toplevel {
+        Some(toplevel) => toplevel,
+        None => return,
+    };
+    let node_state = state::NodeState {
+        hash: cfg_hash.to_owned(),
+        toplevel,
+        deployed_at: chrono::Utc::now(),
+    };
+    let nodes = std::iter::once((name.to_owned(), node_state)).collect();
+    if let Err(e) = state::merge(cfg_dir, nodes).await {
+        warn!(
+            "Could not save the deployed system to the local state: {:?}",
+            e
+        );
+    }
+}
+
+/// Records the metadata of the config with hash `cfg_hash` and links `/etc/henix/latest` (and
+/// `/etc/henix/labels/{label}`, if it has a label) to it.
+/// Failure is only warned about, since neither is needed for the configuration to work.
+/// `built` is the store path of the system, if the build step already knows it.
+/// Returns the store path of the built system, if it could be determined.
+#[tracing::instrument(
+    name = "deploy.activate",
+    skip(remote, node_name, node_cfg, cfg_hash, built, origin),
+    fields(node = node_name, hash = cfg_hash, phase = "activate")
+)]
+async fn activate(
+    remote: &dyn RemoteExecutor,
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    built: Option<String>,
+    origin: meta::Origin,
+) -> Option<String> {
+    let toplevel = match built {
+        Some(toplevel) => Ok(toplevel),
+        None => meta::system_toplevel(remote, node_cfg).await,
+    };
+    let meta_res = match &toplevel {
+        Ok(toplevel) => {
+            let meta = meta::RemoteMeta {
+                toplevel: toplevel.clone(),
+                origin: origin.clone(),
+            };
+            meta::write(remote, node_cfg, cfg_hash, &meta).await
+        }
+        Err(e) => Err(anyhow!("{:#}", e)),
+    };
+    if let Err(e) = meta_res {
+        warn!("Could not record deployment metadata, `henix verify` will not be able to check this node: {:?}", e);
+    }
+    link_latest(remote, node_cfg, cfg_hash).await;
+    if let Some(label) = &origin.label {
+        if let Err(e) = link_label(remote, node_cfg, cfg_hash, label).await {
+            warn!("Could not link the label `{}`: {:?}", label, e);
+        }
+    }
+    toplevel.ok()
+}
+
+/// Links `/etc/henix/labels/{label}` to the config with hash `cfg_hash`, replacing the link of
+/// an earlier deployment with the same label.
+async fn link_label(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    label: &str,
+) -> Result<()> {
+    let link = format!("/etc/henix/labels/{}", label);
+    let target = format!("/etc/henix/{}", cfg_hash);
+    meta::remote_output(remote, node_cfg, "mkdir", &["-p", "/etc/henix/labels"])
+        .await?
+        .ok_or_else(|| anyhow!("Could not create /etc/henix/labels"))?;
+    // `-n` replaces an existing link to a directory, rather than creating the link inside it.
+    meta::remote_output(remote, node_cfg, "ln", &["-s", "-f", "-n", &target, &link])
+        .await?
+        .ok_or_else(|| anyhow!("Could not symlink {} to {}", link, target))?;
+    Ok(())
+}
+
+/// Links `/etc/henix/latest` to the config with hash `cfg_hash`.
+async fn link_latest(remote: &dyn RemoteExecutor, node_cfg: &NodeCfg, cfg_hash: &str) {
+    let link_res = remote
+        .output(
+            node_cfg,
+            "ln",
+            &[
+                "-s",
+                "-f", // Overwite existing destination files
+                &format!("/etc/henix/{}", cfg_hash),
+                "/etc/henix/latest",
+            ],
+        )
+        .await;
+    if let Ok(link_out) = link_res {
+        if link_out.status.success() {
             return;
         }
     }
-    if let Err(e) = process_node_raw(dep_opts, &mut remote, name, &node_cfg, cfg_dir).await {
-        error!("Did not deploy configuration: {:?}", e);
+    warn!("Could not symlink /etc/henix/latest to /etc/henix/{hash}. This is more for convenience, but you may not be able to easily find the current configuration if it is not symlinked. Recommended command: ln -s -f /etc/henix/{hash} /etc/henix/latest", hash = cfg_hash);
+}
+
+/// The result of deploying to a single node, with `deploy_node`.
+pub struct NodeOutcome {
+    pub name: String,
+    pub cfg_hash: String,
+    pub duration: Duration,
+    /// The phases that finished, in order.
+    pub phases_completed: Vec<DeployPhase>,
+    /// How long each phase the node went through took, in order, e.g. for `henix benchmark`.
+    pub phase_timings: Vec<(Phase, Duration)>,
+    /// How the closure of the node's system changed, with `--report-closure-size`.
+    pub closure: Option<ClosureChange>,
+    /// Why the deployment failed, if it did.
+    pub error: Option<anyhow::Error>,
+    /// Whether the node couldn't be reached (`--skip-unreachable` or `--preflight`) or wasn't
+    /// confirmed (`--confirm-per-node`), and was skipped. It doesn't count as failed then.
+    pub skipped: bool,
+    /// Whether the node was cancelled because the deployment was aborted (`--fail-fast`). It
+    /// doesn't count as failed either.
+    pub aborted: bool,
+}
+
+impl NodeOutcome {
+    pub fn history_result(&self) -> history::NodeResult {
+        if self.skipped {
+            return history::NodeResult::Skipped;
+        }
+        if self.aborted {
+            return history::NodeResult::Aborted;
+        }
+        history::NodeResult::from_success(self.error.is_none())
+    }
+
+    /// The `nodeResult` event of the node, for `--events`.
+    pub fn event(&self) -> EventKind {
+        EventKind::NodeResult {
+            result: self.history_result(),
+            duration_ms: events::millis(self.duration),
+            error: self.error.as_ref().map(|e| format!("{:#}", e)),
+        }
+    }
+
+    /// The typed error the node failed with, if it is one.
+    pub fn henix_error(&self) -> Option<&HenixError> {
+        self.error.as_ref().and_then(HenixError::find)
+    }
+}
+
+/// The results of the nodes that were deployed to, in the order they finished.
+#[derive(Default)]
+pub struct DeployResult {
+    pub nodes: Vec<NodeOutcome>,
+}
+
+impl DeployResult {
+    pub fn succeeded(&self) -> impl Iterator<Item = &NodeOutcome> {
+        self.nodes
+            .iter()
+            .filter(|node| node.error.is_none() && !node.skipped && !node.aborted)
+    }
+
+    pub fn skipped(&self) -> impl Iterator<Item = &NodeOutcome> {
+        self.nodes.iter().filter(|node| node.skipped)
+    }
+
+    pub fn aborted(&self) -> impl Iterator<Item = &NodeOutcome> {
+        self.nodes.iter().filter(|node| node.aborted)
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &NodeOutcome> {
+        self.nodes.iter().filter(|node| node.error.is_some())
+    }
+
+    /// Logs one line per node, with how long it took and how far it got.
+    pub fn log_summary(&self) {
+        for node in &self.nodes {
+            let phases = node
+                .phases_completed
+                .iter()
+                .map(|phase| phase.name())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let result = if node.error.is_some() {
+                "failed"
+            } else if node.skipped {
+                "skipped"
+            } else if node.aborted {
+                "aborted"
+            } else {
+                "deployed"
+            };
+            let closure = match &node.closure {
+                Some(change) => format!("; closure {}", change),
+                None => String::new(),
+            };
+            info!(
+                "{}: {} after {} (config {}, completed phases: {}{})",
+                node.name,
+                result,
+                util::format_duration(node.duration),
+                node.cfg_hash,
+                if phases.is_empty() { "none" } else { &phases },
+                closure
+            );
+        }
+    }
+}
+
+/// Deploys `cfg` to a single node, as `henix deploy` does for each of them, so that callers can
+/// schedule nodes themselves. Progress is written to `events`, and the full output to
+/// `log_file`, if given. Failures are returned in the outcome, after they were logged.
+///
+/// With `--staged`, the node waits at `barrier` (if given) once the new system is staged, before
+/// switching to it. A node that fails before that still waits at it, so that the other nodes
+/// aren't kept waiting forever; they switch without it.
+///
+/// Dropping the returned future stops the deployment: local commands (like rsync) are killed and
+/// the SSH session is closed. Commands that were already started on the node may keep running
+/// there though, e.g. `nixos-rebuild` may still finish activating the new system, and a rollback
+/// scheduled for `--confirm-timeout` still happens, since it isn't cancelled. A copy that was
+/// interrupted is done again next time, since it is only marked as complete once rsync finished.
+///
+/// `dep_opts` is most easily made from the arguments of `henix deploy`, so that the other
+/// options keep their defaults:
+///
+/// ```no_run
+/// # async fn example(node_cfg: &henix::NodeCfg) -> anyhow::Result<()> {
+/// use henix::deploy::{self, LocalCfg};
+/// use std::path::Path;
+/// use structopt::StructOpt;
+///
+/// let mut dep_opts = henix::DeployOpts::from_iter(&["deploy", "--switch-action", "test"]);
+/// dep_opts.force_copy = true;
+/// let dir = Path::new("/home/ops/servers");
+/// let hash = deploy::cfg_hash(dir).await?;
+/// let cfg = LocalCfg {
+///     dir,
+///     hash: &hash,
+///     toplevel: None,
+///     git_rev: None,
+///     override_input: &[],
+///     state_dir: dir,
+///     confirmation: None,
+///     abort: None,
+/// };
+/// let outcome = deploy::deploy_node(&dep_opts, "web-01", node_cfg, cfg, None, None, None).await;
+/// if let Some(e) = outcome.error {
+///     return Err(e);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// This handles the errors and logging; `process_node_raw` does the actual deployment.
+#[tracing::instrument(
+    name = "deploy",
+    skip(dep_opts, name, node_cfg, cfg, events, log_file, barrier),
+    fields(node = name, log_level = node_cfg.log_level.as_deref())
+)]
+pub async fn deploy_node(
+    dep_opts: &DeployOpts,
+    name: &str,
+    node_cfg: &NodeCfg,
+    cfg: LocalCfg<'_>,
+    events: Option<Arc<EventStream>>,
+    log_file: Option<&Path>,
+    barrier: Option<&Barrier>,
+) -> NodeOutcome {
+    let start = Instant::now();
+    let output_sink = OutputSink::for_mode(dep_opts.output);
+    let mut sink = output_sink.clone();
+    if let Some(path) = log_file {
+        match NodeLog::create(path) {
+            Ok(log) => sink = OutputSink::File(Box::new(sink), Arc::new(log)),
+            Err(e) => warn!(
+                "Could not create log file {}, not saving the output: {:?}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    if let Some(events) = events {
+        sink = OutputSink::Events(Box::new(sink), NodeEvents::new(events, name));
+    }
+    let progress = Mutex::new(Progress {
+        current: Phase::Connecting,
+        completed: Vec::new(),
+        entered: None,
+        timings: Vec::new(),
+        closure: None,
+        reached_barrier: false,
+    });
+    let deployment =
+        process_node_with_sink(dep_opts, name, node_cfg, cfg, &sink, &progress, barrier);
+    let (res, aborted) = match cfg.abort {
+        Some(abort) => abortable(dep_opts, deployment, abort, &progress).await,
+        None => (deployment.await, false),
+    };
+    // The node stopped before its next phase.
+    let (res, aborted) = match res {
+        Err(e) if e.downcast_ref::<Stopped>().is_some() => (Ok(()), true),
+        res => (res, aborted),
+    };
+    if let Some(barrier) = barrier {
+        let reached_barrier = progress.lock().unwrap().reached_barrier;
+        if !reached_barrier {
+            barrier.wait().await;
+        }
+    }
+    let skipped = matches!(&res, Err(e) if skip_reason(dep_opts, name, e).is_some());
+    // A skipped node didn't fail.
+    let res = if skipped { Ok(()) } else { res };
+    let success = res.is_ok() && !skipped && !aborted;
+    let duration = start.elapsed();
+    let mut progress = progress.into_inner().unwrap();
+    let last = progress.current;
+    progress.enter(last);
+    if success {
+        info!("Deployed in {}", util::format_duration(duration));
+    } else if aborted {
+        sink.note("Cancelled, since the deployment was aborted");
+        warn!(
+            "Cancelled after {}, since the deployment was aborted",
+            util::format_duration(duration)
+        );
+    } else if !skipped {
+        info!("Failed after {}", util::format_duration(duration));
+    }
+    sink.phase(if skipped {
+        Phase::Skipped
+    } else if aborted {
+        Phase::Aborted
+    } else if success {
+        Phase::Done
+    } else {
+        Phase::Failed
+    });
+    if let OutputSink::Buffer(buf) = &output_sink {
+        let result = if skipped {
+            "skipped"
+        } else if aborted {
+            "aborted"
+        } else if success {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        if let Err(e) = buf.lock().unwrap().print_block(name, result) {
+            error!("Could not print buffered output: {:?}", e);
+        }
+    }
+    NodeOutcome {
+        name: name.to_owned(),
+        cfg_hash: cfg.hash.to_owned(),
+        duration,
+        phases_completed: progress.completed,
+        phase_timings: progress.timings,
+        closure: progress.closure,
+        error: res.err(),
+        skipped,
+        aborted,
+    }
+}
+
+/// Whether the deployment to a node in `phase` can still be cancelled when the deployment is
+/// aborted, which is only the case until it starts activating the new system. `nixos-rebuild`
+/// activates while building, unless only building with `--switch-action` or `--staged`.
+fn can_abort(dep_opts: &DeployOpts, phase: Phase) -> bool {
+    match phase {
+        Phase::Connecting | Phase::Copying => true,
+        Phase::Building => dep_opts.rebuild.switch_action.is_some() || dep_opts.rebuild.staged,
+        _ => false,
+    }
+}
+
+/// Runs `deployment` until it finishes, or until `abort` while it can still be cancelled (see
+/// `can_abort`). If the deployment was terminated, it may still finish its current phase.
+/// Returns its result, and whether it was cancelled.
+async fn abortable(
+    dep_opts: &DeployOpts,
+    deployment: impl std::future::Future<Output = Result<()>>,
+    abort: &Abort,
+    progress: &Mutex<Progress>,
+) -> (Result<()>, bool) {
+    tokio::pin!(deployment);
+    tokio::select! {
+        res = &mut deployment => return (res, false),
+        _ = abort.wait() => {}
+    }
+    let phase = progress.lock().unwrap().current;
+    if can_abort(dep_opts, phase) && abort.graceful.load(Ordering::SeqCst) {
+        info!(
+            "The deployment was terminated, letting the node finish {} for up to {}s",
+            phase.name(),
+            TERMINATE_GRACE_PERIOD.as_secs()
+        );
+        // It stops before its next phase, or is cancelled once the time is up.
+        return match time::timeout(TERMINATE_GRACE_PERIOD, deployment).await {
+            Ok(res) => (res, false),
+            Err(_) => (Ok(()), true),
+        };
+    }
+    if can_abort(dep_opts, phase) {
+        // Dropping the deployment cancels it.
+        return (Ok(()), true);
+    }
+    info!(
+        "The deployment was aborted, but the node is already {}, so it is allowed to finish",
+        phase.name()
+    );
+    (deployment.await, false)
+}
+
+/// Why the node is skipped rather than failed, if the deployment to it failed with `e` only
+/// because it wasn't confirmed (`--confirm-per-node`) or couldn't be reached
+/// (`--skip-unreachable`, which never skips canaries).
+fn skip_reason(dep_opts: &DeployOpts, name: &str, e: &anyhow::Error) -> Option<String> {
+    if e.downcast_ref::<NotConfirmed>().is_some() {
+        return Some("it wasn't confirmed".to_owned());
+    }
+    if dep_opts.skip_unreachable
+        && !dep_opts.canary.iter().any(|canary| canary == name)
+        && matches!(HenixError::find(e), Some(HenixError::Connect { .. }))
+    {
+        return Some(format!("it can't be reached: {:#}", e));
+    }
+    None
+}
+
+/// Logs the error, if the deployment failed.
+async fn process_node_with_sink(
+    dep_opts: &DeployOpts,
+    name: &str,
+    node_cfg: &NodeCfg,
+    cfg: LocalCfg<'_>,
+    sink: &OutputSink,
+    progress: &Mutex<Progress>,
+    barrier: Option<&Barrier>,
+) -> Result<()> {
+    let deployment = process_node_raw(dep_opts, name, node_cfg, cfg, sink, progress, barrier);
+    // The node's timeout takes precedence over `--total-timeout`.
+    let res = match node_cfg.total_timeout_secs.or(dep_opts.total_timeout) {
+        Some(secs) => match time::timeout(Duration::from_secs(secs), deployment).await {
+            Ok(res) => res,
+            Err(_) => Err(anyhow!(
+                "Timed out after {}s while {}",
+                secs,
+                progress.lock().unwrap().current.name()
+            )),
+        },
+        None => deployment.await,
+    };
+    let res = res.map_err(|e| match &node_cfg.source {
+        Some(source) => e.context(format!("`{}` is defined in {}", name, source)),
+        None => e,
+    });
+    if let Err(e) = &res {
+        if e.downcast_ref::<Stopped>().is_some() {
+            return res;
+        }
+        if let Some(reason) = skip_reason(dep_opts, name, e) {
+            sink.note(&format!("Skipped, since {}", reason));
+            warn!("Skipped, since {}", reason);
+            return res;
+        }
+        sink.note(&format!("Did not deploy configuration: {:?}", e));
+        if dep_opts.output == OutputMode::Grouped {
+            // The full output only shows up once the node finishes,
+            // so keep this to one line.
+            error!("Did not deploy configuration: {:#}", e);
+        } else {
+            error!("Did not deploy configuration: {:?}", e);
+        }
+    }
+    res
+}
+
+/// Only copies the configuration to the node, for `henix copy-config`. Failures are returned
+/// after they were logged.
+#[tracing::instrument(
+    name = "copy",
+    skip(name, node_cfg, cfg_dir, cfg_hash, copy_opts, host_key_opts),
+    fields(node = name, log_level = node_cfg.log_level.as_deref())
+)]
+pub async fn copy_node(
+    name: &str,
+    node_cfg: &NodeCfg,
+    cfg_dir: &Path,
+    cfg_hash: &str,
+    copy_opts: &CopyOpts,
+    host_key_opts: &HostKeyOpts,
+) -> Result<()> {
+    let res = copy_node_raw(name, node_cfg, cfg_dir, cfg_hash, copy_opts, host_key_opts).await;
+    if let Err(e) = &res {
+        error!("Could not copy config: {:?}", e);
+    }
+    res
+}
+
+async fn copy_node_raw(
+    name: &str,
+    node_cfg: &NodeCfg,
+    cfg_dir: &Path,
+    cfg_hash: &str,
+    copy_opts: &CopyOpts,
+    host_key_opts: &HostKeyOpts,
+) -> Result<()> {
+    let sink = &OutputSink::Log;
+    // rsync connects by itself, so the node is only connected to for
+    // `--no-delete-on-first-deploy`, to check and record whether the config was copied before.
+    let remote = if copy_opts.no_delete_on_first_deploy && copy_opts.delete_extraneous(node_cfg) {
+        Some(remote::connect(name, node_cfg, host_key_opts).await?)
+    } else {
+        None
+    };
+    let delete = match &remote {
+        Some(remote) => {
+            let marker = copied_marker(cfg_hash);
+            let copied_before = remote_path_exists(remote, node_cfg, "-e", &marker).await?;
+            delete_on_copy(copy_opts, node_cfg, cfg_hash, copied_before)
+        }
+        None => copy_opts.delete_extraneous(node_cfg),
+    };
+    let mode = CopyMode {
+        link_dest: None,
+        delete,
+        host_keys: host_key_opts,
+    };
+    let runner = LoggingRunner(sink.clone());
+    let copy = || copy_config(name, node_cfg, cfg_dir, cfg_hash, copy_opts, &mode, &runner);
+    util::retry(node_cfg.retry_policy(), HenixError::is_retryable, copy)
+        .await
+        .context("Could not copy config")?;
+    if let Some(remote) = &remote {
+        mark_copied(remote, node_cfg, cfg_hash).await?;
+    }
+    Ok(())
+}
+
+/// Only builds an already copied configuration on the node, for `henix build-config` and
+/// `henix remote-build`. Without `cfg_hash`, the configuration `/etc/henix/latest` links to is
+/// built. The local state of the configuration in `cfg_dir` is updated, if given. Failures are
+/// returned after they were logged.
+#[tracing::instrument(
+    name = "build",
+    skip(rebuild_opts, host_key_opts, name, node_cfg, cfg_hash, override_input, cfg_dir),
+    fields(node = name, log_level = node_cfg.log_level.as_deref())
+)]
+pub async fn build_node(
+    rebuild_opts: &RebuildOpts,
+    host_key_opts: &HostKeyOpts,
+    name: &str,
+    node_cfg: &NodeCfg,
+    cfg_hash: Option<&str>,
+    override_input: &[String],
+    cfg_dir: Option<&Path>,
+) -> Result<()> {
+    let res = build_node_raw(
+        rebuild_opts,
+        host_key_opts,
+        name,
+        node_cfg,
+        cfg_hash,
+        override_input,
+        cfg_dir,
+    )
+    .await;
+    if let Err(e) = &res {
+        error!("{:?}", e);
+    }
+    res
+}
+
+async fn build_node_raw(
+    rebuild_opts: &RebuildOpts,
+    host_key_opts: &HostKeyOpts,
+    name: &str,
+    node_cfg: &NodeCfg,
+    cfg_hash: Option<&str>,
+    override_input: &[String],
+    cfg_dir: Option<&Path>,
+) -> Result<()> {
+    let remote = remote::connect(name, node_cfg, host_key_opts).await?;
+    let cfg_hash = match cfg_hash {
+        Some(cfg_hash) => cfg_hash.to_owned(),
+        None => latest_config_hash(&remote, node_cfg).await?,
+    };
+    let cfg_hash = cfg_hash.as_str();
+    let specialisation = rebuild_opts.specialisation(node_cfg)?;
+    let built = build_config(
+        rebuild_opts,
+        &remote,
+        name,
+        node_cfg,
+        cfg_hash,
+        override_input,
+        &OutputSink::Log,
+    )
+    .await
+    .context("Could not build config")?;
+    if let (Some(toplevel), Some(action)) = (&built, rebuild_opts.switch_action) {
+        switch_to_configuration(
+            &remote,
+            name,
+            node_cfg,
+            toplevel,
+            specialisation,
+            action,
+            &OutputSink::Log,
+        )
+        .await
+        .context("Could not activate config")?;
+        if action == SwitchAction::DryActivate {
+            return Ok(());
+        }
+    }
+    if let (Some(toplevel), true) = (&built, rebuild_opts.staged) {
+        for action in &[SwitchAction::Boot, SwitchAction::Test] {
+            switch_to_configuration(
+                &remote,
+                name,
+                node_cfg,
+                toplevel,
+                None,
+                *action,
+                &OutputSink::Log,
+            )
+            .await
+            .context("Could not activate config")?;
+        }
+    }
+    // The node runs the specialisation rather than the system that was built.
+    let built = built.filter(|_| specialisation.is_none());
+    let origin = meta::Origin::new(None, None, rebuild_opts.activation());
+    let toplevel = activate(&remote, name, node_cfg, cfg_hash, built, origin).await;
+    if let Some(cfg_dir) = cfg_dir {
+        save_state(cfg_dir, name, cfg_hash, toplevel).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::BoxFuture;
+    use serde_json::json;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Output;
+    use structopt::StructOpt;
+
+    const HASH: &str = "4a8ff2c035228043c3dd2c017b6dca55";
+    const TOPLEVEL: &str = "/nix/store/h8k2d5yw3r9f1m6l0k7j4x8c2v5b1n9z-nixos-system-web-01";
+
+    /// Records the commands run on the node, and answers them with scripted results.
+    #[derive(Default)]
+    struct MockRemote {
+        commands: Mutex<Vec<String>>,
+        /// The exit code and stdout of the commands that start with each prefix. Other commands
+        /// succeed without printing anything.
+        results: Vec<(&'static str, i32, &'static str)>,
+    }
+
+    impl MockRemote {
+        fn with(results: Vec<(&'static str, i32, &'static str)>) -> Self {
+            MockRemote {
+                results,
+                ..MockRemote::default()
+            }
+        }
+
+        fn run(&self, program: &str, args: &[&str]) -> Output {
+            let command = std::iter::once(program)
+                .chain(args.iter().copied())
+                .map(util::shell_quote)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let (code, stdout) = self
+                .results
+                .iter()
+                .find(|(prefix, ..)| command.starts_with(prefix))
+                .map_or((0, ""), |(_, code, stdout)| (*code, *stdout));
+            self.commands.lock().unwrap().push(command);
+            Output {
+                status: ExitStatus::from_raw(code << 8),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            }
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    impl RemoteExecutor for MockRemote {
+        fn output<'a>(
+            &'a self,
+            _node_cfg: &'a NodeCfg,
+            program: &'a str,
+            args: &'a [&'a str],
+        ) -> BoxFuture<'a, Result<Output>> {
+            let out = self.run(program, args);
+            Box::pin(async move { Ok(out) })
+        }
+
+        fn run_logged<'a>(
+            &'a self,
+            _node_cfg: &'a NodeCfg,
+            _name: &'a str,
+            program: &'a str,
+            args: &'a [&'a str],
+            _sink: &'a OutputSink,
+        ) -> BoxFuture<'a, Result<ExitStatus>> {
+            let status = self.run(program, args).status;
+            Box::pin(async move { Ok(status) })
+        }
+    }
+
+    /// Runs no local command, answering every one with the same output, and records them.
+    #[derive(Debug)]
+    struct FakeRunner {
+        commands: Mutex<Vec<String>>,
+        exit_code: i32,
+        stdout: &'static str,
+        stderr: &'static str,
+    }
+
+    impl FakeRunner {
+        fn new(exit_code: i32, stdout: &'static str, stderr: &'static str) -> Self {
+            FakeRunner {
+                commands: Mutex::new(Vec::new()),
+                exit_code,
+                stdout,
+                stderr,
+            }
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn output(&self, cmd: process::Command) -> BoxFuture<'static, std::io::Result<Output>> {
+            let cmd = cmd.as_std();
+            let command = std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|arg| util::shell_quote(&arg.to_string_lossy()))
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.commands.lock().unwrap().push(command);
+            let out = Output {
+                status: ExitStatus::from_raw(self.exit_code << 8),
+                stdout: self.stdout.as_bytes().to_vec(),
+                stderr: self.stderr.as_bytes().to_vec(),
+            };
+            Box::pin(async move { Ok(out) })
+        }
+    }
+
+    fn node_cfg(cfg: serde_json::Value) -> NodeCfg {
+        serde_json::from_value(cfg).unwrap()
+    }
+
+    fn rebuild_opts(args: &[&str]) -> RebuildOpts {
+        RebuildOpts::from_iter(std::iter::once("henix").chain(args.iter().copied()))
+    }
+
+    #[tokio::test]
+    async fn copy_commands() {
+        let remote = MockRemote::with(vec![(
+            "readlink -f /etc/henix/latest",
+            0,
+            "/etc/henix/0123456789abcdef0123456789abcdef\n",
+        )]);
+        let node_cfg = node_cfg(json!({ "location": "web-01.example.com" }));
+        let previous = previous_config(&remote, &node_cfg, HASH).await.unwrap();
+        assert_eq!(
+            previous.as_deref(),
+            Some("/etc/henix/0123456789abcdef0123456789abcdef")
+        );
+        mark_copied(&remote, &node_cfg, HASH).await.unwrap();
+        assert_eq!(
+            remote.commands(),
+            [
+                "readlink -f /etc/henix/latest".to_owned(),
+                "test -d /etc/henix/0123456789abcdef0123456789abcdef".to_owned(),
+                format!("touch /etc/henix/{}.copied", HASH),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_of_the_same_config_has_no_previous_config() {
+        let remote = MockRemote::with(vec![(
+            "readlink -f /etc/henix/latest",
+            0,
+            "/etc/henix/4a8ff2c035228043c3dd2c017b6dca55\n",
+        )]);
+        let node_cfg = node_cfg(json!({ "location": "web-01.example.com" }));
+        assert_eq!(
+            previous_config(&remote, &node_cfg, HASH).await.unwrap(),
+            None
+        );
+        assert_eq!(remote.commands(), ["readlink -f /etc/henix/latest"]);
+    }
+
+    #[tokio::test]
+    async fn copy_with_rsync() {
+        let runner = FakeRunner::new(
+            0,
+            "Number of files: 12 (reg: 10, dir: 2)\nTotal file size: 4,096 bytes\nTotal transferred file size: 1,024 bytes\n",
+            "",
+        );
+        let node_cfg = node_cfg(json!({
+            "location": "local",
+            "escalation": "none",
+            "rsyncArgs": ["--checksum"],
+        }));
+        let mode = CopyMode {
+            link_dest: Some("/etc/henix/0123456789abcdef0123456789abcdef"),
+            delete: true,
+            host_keys: &HostKeyOpts::from_iter(&["henix"]),
+        };
+        let copy_opts = CopyOpts::from_iter(&["henix"]);
+        copy_config(
+            "me",
+            &node_cfg,
+            Path::new("/home/ops/servers"),
+            HASH,
+            &copy_opts,
+            &mode,
+            &runner,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            runner.commands(),
+            [format!(
+                "rsync --exclude=.git/ '--exclude=/.henix-history*' --exclude=/.henix-logs/ -F -a --delete --mkpath --link-dest=/etc/henix/0123456789abcdef0123456789abcdef --stats --checksum /home/ops/servers/ /etc/henix/{}",
+                HASH
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_with_rsync_fails() {
+        let runner = FakeRunner::new(
+            23,
+            "",
+            "rsync: [receiver] mkstemp \"/etc/henix/x/.flake.nix.Xa1b2c\" failed: Permission denied (13)\nrsync error: some files/attrs were not transferred (see previous errors) (code 23) at main.c(1338) [sender=3.2.7]\n",
+        );
+        let node_cfg = node_cfg(json!({
+            "location": "web-01.example.com",
+            "sshPort": 2222,
+            "escalation": "sudo",
+        }));
+        let mode = CopyMode {
+            link_dest: None,
+            delete: false,
+            host_keys: &HostKeyOpts::from_iter(&["henix", "--no-update-known-hosts"]),
+        };
+        let copy_opts = CopyOpts::from_iter(&["henix", "--compress", "on"]);
+        let e = copy_config(
+            "web-01",
+            &node_cfg,
+            Path::new("/home/ops/servers"),
+            HASH,
+            &copy_opts,
+            &mode,
+            &runner,
+        )
+        .await
+        .unwrap_err();
+        match HenixError::find(&e) {
+            Some(HenixError::Copy {
+                exit_code,
+                stderr_tail,
+                ..
+            }) => {
+                assert_eq!(*exit_code, Some(23));
+                assert_eq!(stderr_tail.len(), 2);
+                assert!(stderr_tail[0].ends_with("Permission denied (13)"));
+            }
+            _ => panic!("Not a copy error: {:?}", e),
+        }
+        assert_eq!(
+            runner.commands(),
+            [format!(
+                "rsync --exclude=.git/ '--exclude=/.henix-history*' --exclude=/.henix-logs/ -F -a --mkpath -z '--rsync-path=sudo -n rsync' -e 'ssh -p 2222 -o ServerAliveInterval=30 -o ServerAliveCountMax=3 -o StrictHostKeyChecking=yes' /home/ops/servers/ root@web-01.example.com:/etc/henix/{}",
+                HASH
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn build_with_nixos_rebuild() {
+        let remote = MockRemote::default();
+        let node_cfg = node_cfg(json!({
+            "location": "web-01.example.com",
+            "noBuildNix": true,
+            "activationTimeoutSecs": 600,
+            "specialisation": "web",
+        }));
+        let override_input = ["nixpkgs".to_owned(), "github:me/nixpkgs/fix".to_owned()];
+        let built = build_config(
+            &rebuild_opts(&["--show-trace"]),
+            &remote,
+            "web-01",
+            &node_cfg,
+            HASH,
+            &override_input,
+            &OutputSink::Log,
+        )
+        .await
+        .unwrap();
+        assert_eq!(built, None);
+        assert_eq!(
+            remote.commands(),
+            [
+                "sh -c 'command -v nixos-rebuild'".to_owned(),
+                format!(
+                    "timeout --kill-after=10 600 nixos-rebuild switch --flake '/etc/henix/{}#web-01' --specialisation web --no-build-nix --show-trace --override-input nixpkgs github:me/nixpkgs/fix",
+                    HASH
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn build_with_nixos_rebuild_fails() {
+        let remote = MockRemote::with(vec![("/run/current-system/sw/bin/nixos-rebuild", 1, "")]);
+        let node_cfg = node_cfg(json!({
+            "location": "web-01.example.com",
+            "nixosRebuildPath": "/run/current-system/sw/bin/nixos-rebuild",
+        }));
+        let e = build_config(
+            &rebuild_opts(&["--boot", "--skip-nix-check"]),
+            &remote,
+            "web-01",
+            &node_cfg,
+            HASH,
+            &[],
+            &OutputSink::Log,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            HenixError::find(&e),
+            Some(HenixError::Build {
+                exit_code: Some(1),
+                ..
+            })
+        ));
+        assert_eq!(
+            remote.commands(),
+            [format!(
+                "/run/current-system/sw/bin/nixos-rebuild boot --flake '/etc/henix/{}#web-01'",
+                HASH
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn build_with_switch_action() {
+        let remote = MockRemote::with(vec![(
+            "readlink -f",
+            0,
+            "/nix/store/h8k2d5yw3r9f1m6l0k7j4x8c2v5b1n9z-nixos-system-web-01\n",
+        )]);
+        let node_cfg = node_cfg(json!({ "location": "web-01.example.com" }));
+        let built = build_config(
+            &rebuild_opts(&["--switch-action", "test"]),
+            &remote,
+            "web-01",
+            &node_cfg,
+            HASH,
+            &[],
+            &OutputSink::Log,
+        )
+        .await
+        .unwrap();
+        assert_eq!(built.as_deref(), Some(TOPLEVEL));
+        assert_eq!(
+            remote.commands(),
+            [
+                "sh -c 'command -v nix'".to_owned(),
+                format!(
+                    "nix build --out-link /etc/henix/{hash}.system '/etc/henix/{hash}#nixosConfigurations.\"web-01\".config.system.build.toplevel'",
+                    hash = HASH
+                ),
+                format!("readlink -f /etc/henix/{}.system", HASH),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn activate_specialisation() {
+        let remote = MockRemote::default();
+        let node_cfg = node_cfg(json!({
+            "location": "web-01.example.com",
+            "activationTimeoutSecs": 300,
+        }));
+        switch_to_configuration(
+            &remote,
+            "web-01",
+            &node_cfg,
+            TOPLEVEL,
+            Some("web"),
+            SwitchAction::Switch,
+            &OutputSink::Log,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            remote.commands(),
+            [
+                format!("nix-env -p /nix/var/nix/profiles/system --set {}", TOPLEVEL),
+                format!(
+                    "timeout --kill-after=10 300 {}/specialisation/web/bin/switch-to-configuration switch",
+                    TOPLEVEL
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn activate_with_test_leaves_the_profile() {
+        let remote = MockRemote::default();
+        let node_cfg = node_cfg(json!({ "location": "web-01.example.com" }));
+        switch_to_configuration(
+            &remote,
+            "web-01",
+            &node_cfg,
+            TOPLEVEL,
+            None,
+            SwitchAction::Test,
+            &OutputSink::Log,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            remote.commands(),
+            [format!("{}/bin/switch-to-configuration test", TOPLEVEL)]
+        );
+    }
+
+    #[tokio::test]
+    async fn activation_timeout() {
+        let remote = MockRemote::with(vec![("timeout", 124, "")]);
+        let node_cfg = node_cfg(json!({
+            "location": "web-01.example.com",
+            "activationTimeoutSecs": 300,
+        }));
+        let e = switch_to_configuration(
+            &remote,
+            "web-01",
+            &node_cfg,
+            TOPLEVEL,
+            None,
+            SwitchAction::Boot,
+            &OutputSink::Log,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            HenixError::find(&e),
+            Some(HenixError::ActivationTimeout {
+                after_secs: 300,
+                ..
+            })
+        ));
     }
 }
@@ -0,0 +1,148 @@
+/// Errors that can be told apart, e.g. to decide whether a failure is worth retrying.
+/// They are attached to `anyhow::Error`s, so they still render as usual, and can be found with
+/// `downcast_ref::<HenixError>()` on the whole error.
+use serde::{Deserialize, Serialize};
+
+/// How many lines of stderr are kept in errors.
+pub const STDERR_TAIL_LINES: usize = 20;
+
+#[derive(thiserror::Error, Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum HenixError {
+    /// Evaluating the configuration of a node failed (`--eval-locally`).
+    #[serde(rename_all = "camelCase")]
+    #[error("Could not evaluate `{attr}`")]
+    Eval {
+        attr: String,
+        stderr_tail: Vec<String>,
+    },
+    #[error("Could not connect to node with name `{node}`")]
+    Connect { node: String },
+    /// The node's host key is unknown or changed, so SSH refused to connect.
+    #[error("Could not verify the host key of `{node}`")]
+    HostKey { node: String },
+    /// The node didn't accept any of its `authMethods`.
+    #[error("`{node}` did not accept any of its `authMethods`")]
+    Auth { node: String },
+    /// The node's `rsyncArgs` aren't allowed.
+    #[error("Invalid `rsyncArgs` of `{node}`")]
+    RsyncArgs { node: String },
+    #[serde(rename_all = "camelCase")]
+    #[error("Could not rsync files to `{node}` (rsync exited with {})", exit_code_text(.exit_code))]
+    Copy {
+        node: String,
+        exit_code: Option<i32>,
+        stderr_tail: Vec<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    #[error("`{program}` failed on `{node}` (exited with {})", exit_code_text(.exit_code))]
+    Build {
+        node: String,
+        program: String,
+        exit_code: Option<i32>,
+        stderr_tail: Vec<String>,
+    },
+    /// `nixos-rebuild`, which builds and activates, took longer than `activationTimeoutSecs`.
+    #[serde(rename_all = "camelCase")]
+    #[error("`{program}` on `{node}` timed out after {after_secs}s")]
+    BuildTimeout {
+        node: String,
+        program: String,
+        after_secs: u64,
+    },
+    #[error("Could not activate the new system on `{node}`")]
+    Activation { node: String },
+    #[serde(rename_all = "camelCase")]
+    #[error("Activating the new system on `{node}` timed out after {after_secs}s")]
+    ActivationTimeout { node: String, after_secs: u64 },
+    /// The canary isn't running the deployed system, or could not be checked.
+    #[error("Canary check of `{node}` failed")]
+    HealthCheck { node: String },
+}
+
+impl HenixError {
+    /// The name of the variant, as it is serialized.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HenixError::Eval { .. } => "eval",
+            HenixError::Connect { .. } => "connect",
+            HenixError::HostKey { .. } => "hostKey",
+            HenixError::Auth { .. } => "auth",
+            HenixError::RsyncArgs { .. } => "rsyncArgs",
+            HenixError::Copy { .. } => "copy",
+            HenixError::Build { .. } => "build",
+            HenixError::BuildTimeout { .. } => "buildTimeout",
+            HenixError::Activation { .. } => "activation",
+            HenixError::ActivationTimeout { .. } => "activationTimeout",
+            HenixError::HealthCheck { .. } => "healthCheck",
+        }
+    }
+
+    /// Finds the `HenixError` that `e` was created from or with, if any.
+    pub fn find(e: &anyhow::Error) -> Option<&HenixError> {
+        e.downcast_ref()
+    }
+
+    /// Whether the operation that failed with `e` may succeed if it is attempted again, for
+    /// `util::retry`. Errors that henix can't tell apart are assumed to be transient.
+    pub fn is_retryable(e: &anyhow::Error) -> bool {
+        match HenixError::find(e) {
+            Some(HenixError::HostKey { .. })
+            | Some(HenixError::Auth { .. })
+            | Some(HenixError::RsyncArgs { .. }) => false,
+            // Syntax or usage error, protocol incompatibility, errors selecting the files, or an
+            // unsupported action; doing it again gives the same result.
+            Some(HenixError::Copy {
+                exit_code: Some(1..=4),
+                ..
+            }) => false,
+            _ => true,
+        }
+    }
+}
+
+fn exit_code_text(exit_code: &Option<i32>) -> String {
+    exit_code.map_or_else(|| "<unknown>".to_owned(), |code| code.to_string())
+}
+
+/// Returns the last `STDERR_TAIL_LINES` lines of `stderr`.
+pub fn stderr_tail(stderr: &[u8]) -> Vec<String> {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines = stderr.lines().collect::<Vec<_>>();
+    let skip = lines.len().saturating_sub(STDERR_TAIL_LINES);
+    lines[skip..]
+        .iter()
+        .map(|line| (*line).to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HenixError;
+
+    fn copy_failed(exit_code: i32) -> anyhow::Error {
+        HenixError::Copy {
+            node: "web".to_owned(),
+            exit_code: Some(exit_code),
+            stderr_tail: Vec::new(),
+        }
+        .into()
+    }
+
+    #[test]
+    fn is_retryable() {
+        let node = || "web".to_owned();
+        assert!(HenixError::is_retryable(&anyhow::anyhow!(
+            "Connection reset"
+        )));
+        assert!(HenixError::is_retryable(&copy_failed(12)));
+        assert!(!HenixError::is_retryable(&copy_failed(1)));
+        let host_key = anyhow::anyhow!("Host key verification failed.")
+            .context(HenixError::HostKey { node: node() });
+        assert!(!HenixError::is_retryable(&host_key));
+        let auth = anyhow::anyhow!("Permission denied").context(HenixError::Auth { node: node() });
+        assert!(!HenixError::is_retryable(
+            &auth.context("Could not connect")
+        ));
+    }
+}
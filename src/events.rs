@@ -0,0 +1,395 @@
+/// Machine-readable progress of a deployment, written with `henix deploy --events` and shown by
+/// `henix top`. Every event is one line of JSON.
+use crate::history::NodeResult;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Phase {
+    Connecting,
+    Copying,
+    Building,
+    Activating,
+    Done,
+    Failed,
+    /// The node couldn't be reached (`--skip-unreachable`) or wasn't confirmed
+    /// (`--confirm-per-node`), and was skipped.
+    Skipped,
+    /// The node was cancelled because the deployment was aborted (`--fail-fast`).
+    Aborted,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Connecting => "connecting",
+            Phase::Copying => "copying",
+            Phase::Building => "building",
+            Phase::Activating => "activating",
+            Phase::Done => "done",
+            Phase::Failed => "failed",
+            Phase::Skipped => "skipped",
+            Phase::Aborted => "aborted",
+        }
+    }
+
+    /// Whether the node is finished.
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            Phase::Done | Phase::Failed | Phase::Skipped | Phase::Aborted
+        )
+    }
+}
+
+/// How the whole deployment ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum RunResult {
+    Succeeded,
+    /// Some nodes failed.
+    Failed,
+    /// The deployment was aborted, e.g. by `--fail-fast`.
+    Aborted,
+}
+
+/// What happened, in the `kind` field of an event. The other fields depend on it.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum EventKind {
+    /// The deployment started, to `nodes`. Comes first, without a `node`.
+    RunStarted { nodes: Vec<String> },
+    /// The node entered a new phase.
+    Phase { phase: Phase },
+    /// The node left `phase`, which took `durationMs`.
+    #[serde(rename_all = "camelCase")]
+    PhaseFinished { phase: Phase, duration_ms: u64 },
+    /// A line of output of a command run for the node, with `--events-include-output`.
+    Output { line: String },
+    /// The node finished, after `durationMs`, with `error` if it failed.
+    #[serde(rename_all = "camelCase")]
+    NodeResult {
+        result: NodeResult,
+        duration_ms: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// The deployment finished, with how many nodes ended up how. Comes last, without a `node`.
+    #[serde(rename_all = "camelCase")]
+    RunFinished {
+        result: RunResult,
+        succeeded: usize,
+        failed: usize,
+        skipped: usize,
+        aborted: usize,
+        duration_ms: u64,
+        /// Why the deployment was aborted, if it was.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub timestamp: DateTime<Utc>,
+    /// The node the event is about, unless it is about the whole deployment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node: Option<String>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// The file (or pipe, or stdout) that events are written to, shared between nodes.
+pub struct EventStream {
+    out: Mutex<Box<dyn Write + Send>>,
+    /// Whether the output of commands is written too (`--events-include-output`).
+    include_output: bool,
+}
+
+impl EventStream {
+    /// Opens `path` for appending, creating it if needed, or stdout if it is `-`.
+    /// If it is a named pipe, this waits until it is opened for reading, e.g. by `henix top`.
+    pub fn open(path: &Path, include_output: bool) -> Result<Self> {
+        let out: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .context(format!("Could not open event stream `{}`", path.display()))?,
+            )
+        };
+        Ok(EventStream {
+            out: Mutex::new(out),
+            include_output,
+        })
+    }
+
+    /// Writes an event about `node`, or about the whole deployment.
+    pub fn emit(&self, node: Option<&str>, kind: EventKind) {
+        let event = Event {
+            timestamp: Utc::now(),
+            node: node.map(str::to_owned),
+            kind,
+        };
+        let res = serde_json::to_string(&event)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut line| {
+                line.push('\n');
+                let mut out = self.out.lock().unwrap();
+                // Write the line in one go, so events of different nodes don't interleave,
+                // and flush it, so that whoever reads the stream sees it right away.
+                out.write_all(line.as_bytes())?;
+                Ok(out.flush()?)
+            });
+        if let Err(e) = res {
+            warn!("Could not write to the event stream: {:?}", e);
+        }
+    }
+}
+
+/// The duration of a phase or a node, as it is written in events.
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+/// Emits the events of a single node.
+#[derive(Clone)]
+pub struct NodeEvents {
+    stream: Arc<EventStream>,
+    node: String,
+    /// The phase the node is in, and when it entered it.
+    current: Arc<Mutex<Option<(Phase, Instant)>>>,
+}
+
+impl NodeEvents {
+    pub fn new(stream: Arc<EventStream>, node: &str) -> Self {
+        NodeEvents {
+            stream,
+            node: node.to_owned(),
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn emit(&self, kind: EventKind) {
+        self.stream.emit(Some(&self.node), kind);
+    }
+
+    pub fn phase(&self, phase: Phase) {
+        let now = Instant::now();
+        let previous = std::mem::replace(
+            &mut *self.current.lock().unwrap(),
+            Some((phase, now)).filter(|_| !phase.is_final()),
+        );
+        if let Some((previous, entered)) = previous {
+            self.emit(EventKind::PhaseFinished {
+                phase: previous,
+                duration_ms: millis(now - entered),
+            });
+        }
+        self.emit(EventKind::Phase { phase });
+    }
+
+    pub fn output(&self, line: &str) {
+        if !self.stream.include_output {
+            return;
+        }
+        self.emit(EventKind::Output {
+            line: line.to_owned(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventKind, Phase, RunResult};
+    use crate::history::NodeResult;
+    use chrono::{DateTime, Utc};
+    use serde_json::json;
+
+    fn event(node: Option<&str>, kind: EventKind) -> Event {
+        Event {
+            timestamp: DateTime::parse_from_rfc3339("2024-03-01T12:00:03.107Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            node: node.map(str::to_owned),
+            kind,
+        }
+    }
+
+    /// Checks that `kind` is written as `expected`, and read back the same way.
+    fn check(node: Option<&str>, kind: EventKind, expected: serde_json::Value) {
+        let line = serde_json::to_string(&event(node, kind)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value, expected);
+        let read: Event = serde_json::from_str(&line).unwrap();
+        assert_eq!(serde_json::to_string(&read).unwrap(), line);
+    }
+
+    #[test]
+    fn readme_example() {
+        let event = event(
+            Some("web-01"),
+            EventKind::PhaseFinished {
+                phase: Phase::Copying,
+                duration_ms: 3006,
+            },
+        );
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"timestamp":"2024-03-01T12:00:03.107Z","node":"web-01","kind":"phaseFinished","phase":"copying","durationMs":3006}"#
+        );
+    }
+
+    #[test]
+    fn every_kind() {
+        check(
+            None,
+            EventKind::RunStarted {
+                nodes: vec!["db-01".to_owned(), "web-01".to_owned()],
+            },
+            json!({
+                "timestamp": "2024-03-01T12:00:03.107Z",
+                "kind": "runStarted",
+                "nodes": ["db-01", "web-01"],
+            }),
+        );
+        check(
+            Some("web-01"),
+            EventKind::Phase {
+                phase: Phase::Building,
+            },
+            json!({
+                "timestamp": "2024-03-01T12:00:03.107Z",
+                "node": "web-01",
+                "kind": "phase",
+                "phase": "building",
+            }),
+        );
+        check(
+            Some("web-01"),
+            EventKind::PhaseFinished {
+                phase: Phase::Activating,
+                duration_ms: 1500,
+            },
+            json!({
+                "timestamp": "2024-03-01T12:00:03.107Z",
+                "node": "web-01",
+                "kind": "phaseFinished",
+                "phase": "activating",
+                "durationMs": 1500,
+            }),
+        );
+        check(
+            Some("web-01"),
+            EventKind::Output {
+                line: "building '/nix/store/abc-foo.drv'...".to_owned(),
+            },
+            json!({
+                "timestamp": "2024-03-01T12:00:03.107Z",
+                "node": "web-01",
+                "kind": "output",
+                "line": "building '/nix/store/abc-foo.drv'...",
+            }),
+        );
+        check(
+            Some("web-01"),
+            EventKind::NodeResult {
+                result: NodeResult::Failed,
+                duration_ms: 42000,
+                error: Some("Could not build config".to_owned()),
+            },
+            json!({
+                "timestamp": "2024-03-01T12:00:03.107Z",
+                "node": "web-01",
+                "kind": "nodeResult",
+                "result": "failed",
+                "durationMs": 42000,
+                "error": "Could not build config",
+            }),
+        );
+        check(
+            Some("db-01"),
+            EventKind::NodeResult {
+                result: NodeResult::Succeeded,
+                duration_ms: 30000,
+                error: None,
+            },
+            json!({
+                "timestamp": "2024-03-01T12:00:03.107Z",
+                "node": "db-01",
+                "kind": "nodeResult",
+                "result": "succeeded",
+                "durationMs": 30000,
+            }),
+        );
+        check(
+            None,
+            EventKind::RunFinished {
+                result: RunResult::Aborted,
+                succeeded: 1,
+                failed: 1,
+                skipped: 0,
+                aborted: 2,
+                duration_ms: 45000,
+                reason: Some("--fail-fast".to_owned()),
+            },
+            json!({
+                "timestamp": "2024-03-01T12:00:03.107Z",
+                "kind": "runFinished",
+                "result": "aborted",
+                "succeeded": 1,
+                "failed": 1,
+                "skipped": 0,
+                "aborted": 2,
+                "durationMs": 45000,
+                "reason": "--fail-fast",
+            }),
+        );
+    }
+
+    #[test]
+    fn names() {
+        let phases = [
+            Phase::Connecting,
+            Phase::Copying,
+            Phase::Building,
+            Phase::Activating,
+            Phase::Done,
+            Phase::Failed,
+            Phase::Skipped,
+            Phase::Aborted,
+        ];
+        for phase in phases {
+            assert_eq!(serde_json::to_value(phase).unwrap(), json!(phase.name()));
+        }
+        let results = [
+            (NodeResult::Succeeded, "succeeded"),
+            (NodeResult::Failed, "failed"),
+            (NodeResult::Aborted, "aborted"),
+            (NodeResult::Skipped, "skipped"),
+        ];
+        for (result, name) in results {
+            assert_eq!(serde_json::to_value(result).unwrap(), json!(name));
+        }
+        let results = [
+            (RunResult::Succeeded, "succeeded"),
+            (RunResult::Failed, "failed"),
+            (RunResult::Aborted, "aborted"),
+        ];
+        for (result, name) in results {
+            assert_eq!(serde_json::to_value(result).unwrap(), json!(name));
+        }
+    }
+}
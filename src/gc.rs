@@ -0,0 +1,166 @@
+/// Cleaning up the local caches and logs of henix, for `henix gc`.
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Which files `collect` removes.
+pub struct GcFilter {
+    /// Keeps at least the most recent `keep` files of each group (e.g. the logs of a node).
+    pub keep: usize,
+    /// Removes files older than this, even if they are among the most recent `keep`.
+    pub older_than: Option<Duration>,
+    /// Only lists the files that would be removed.
+    pub dry_run: bool,
+}
+
+/// The outcome of `collect`.
+#[derive(Default)]
+pub struct GcSummary {
+    pub removed: usize,
+    pub remaining: usize,
+    /// The size of the removed files, in bytes.
+    pub freed: u64,
+}
+
+struct Entry {
+    path: PathBuf,
+    modified: SystemTime,
+    size: u64,
+}
+
+/// Returns the node that the log file `name` is of, if it is named like the logs that henix
+/// writes (see `output::node_log_path`).
+fn log_node(name: &str) -> Option<&str> {
+    // `{node}-{date}-{time}.log`, where the node name may contain `-` itself.
+    let mut parts = name.strip_suffix(".log")?.rsplitn(3, '-');
+    let (time, date, node) = (parts.next()?, parts.next()?, parts.next()?);
+    let digits = |s: &str, len| s.len() == len && s.bytes().all(|b| b.is_ascii_digit());
+    let is_timestamp = digits(date, 8)
+        && digits(time, 6)
+        && NaiveDateTime::parse_from_str(&format!("{}-{}", date, time), "%Y%m%d-%H%M%S").is_ok();
+    Some(node).filter(|node| is_timestamp && !node.is_empty())
+}
+
+/// Lists the files in `dir`, and in its subdirectories if `recursive`, grouped by `group`, which
+/// gets the path of each file relative to `dir`. Files that `group` returns `None` for are left
+/// out.
+fn list_files(
+    dir: &Path,
+    recursive: bool,
+    group: &dyn Fn(&Path) -> Option<String>,
+) -> Result<BTreeMap<String, Vec<Entry>>> {
+    let mut groups = BTreeMap::<_, Vec<_>>::new();
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(current) = dirs.pop() {
+        let entries = std::fs::read_dir(&current)
+            .context(format!("Could not list `{}`", current.display()))?;
+        for entry in entries {
+            let entry = entry.context(format!("Could not list `{}`", current.display()))?;
+            let metadata = entry
+                .metadata()
+                .context(format!("Could not stat `{}`", entry.path().display()))?;
+            if metadata.is_dir() {
+                if recursive {
+                    dirs.push(entry.path());
+                }
+                continue;
+            }
+            let path = entry.path();
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            let group = match group(relative) {
+                Some(group) => group,
+                None => continue,
+            };
+            groups.entry(group).or_default().push(Entry {
+                modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                size: metadata.len(),
+                path,
+            });
+        }
+    }
+    Ok(groups)
+}
+
+/// Removes the files in `dir` that match `filter`, keeping the most recent ones of each group
+/// (see `list_files`). Nothing is done if `dir` doesn't exist.
+fn collect_dir(
+    dir: &Path,
+    recursive: bool,
+    group: &dyn Fn(&Path) -> Option<String>,
+    filter: &GcFilter,
+    summary: &mut GcSummary,
+) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    let cutoff = filter
+        .older_than
+        .and_then(|age| SystemTime::now().checked_sub(age));
+    for (_, mut entries) in list_files(dir, recursive, group)? {
+        // Newest first.
+        entries.sort_by_key(|entry| std::cmp::Reverse(entry.modified));
+        for (i, entry) in entries.into_iter().enumerate() {
+            let expired =
+                i >= filter.keep || matches!(cutoff, Some(cutoff) if entry.modified < cutoff);
+            if !expired {
+                summary.remaining += 1;
+                continue;
+            }
+            if filter.dry_run {
+                println!("Would remove {}", entry.path.display());
+            } else {
+                std::fs::remove_file(&entry.path)
+                    .context(format!("Could not remove `{}`", entry.path.display()))?;
+            }
+            summary.removed += 1;
+            summary.freed += entry.size;
+        }
+    }
+    Ok(())
+}
+
+/// Removes old files from `log_dir` (the `--log-dir`, whose logs are grouped by node) and from
+/// `cache_dir` (grouped by directory). Only the logs that henix wrote directly in `log_dir` are
+/// removed, since it may be shared with other files.
+pub fn collect(log_dir: &Path, cache_dir: &Path, filter: &GcFilter) -> Result<GcSummary> {
+    let mut summary = GcSummary::default();
+    collect_dir(
+        log_dir,
+        false,
+        &|path| path.to_str().and_then(log_node).map(str::to_owned),
+        filter,
+        &mut summary,
+    )?;
+    collect_dir(
+        cache_dir,
+        true,
+        &|path| {
+            Some(
+                path.parent()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_default(),
+            )
+        },
+        filter,
+        &mut summary,
+    )?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::log_node;
+
+    #[test]
+    fn log_node_matches_only_node_logs() {
+        assert_eq!(log_node("web-20240102-030405.log"), Some("web"));
+        assert_eq!(log_node("db-eu-1-20240102-030405.log"), Some("db-eu-1"));
+        assert_eq!(log_node("notes.log"), None);
+        assert_eq!(log_node("web-20240102-030405.txt"), None);
+        assert_eq!(log_node("web-2024012-030405.log"), None);
+        assert_eq!(log_node("web-20241302-030405.log"), None);
+        assert_eq!(log_node("-20240102-030405.log"), None);
+    }
+}
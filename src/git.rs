@@ -0,0 +1,115 @@
+/// Git utilities, e.g. for committing the configuration after a deployment.
+use crate::{output::OutputSink, util};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use std::path::Path;
+use tokio::process;
+
+/// Runs `git args...` in `cfg_dir`, logging its output.
+async fn git(cfg_dir: &Path, args: &[&str]) -> Result<std::process::ExitStatus> {
+    let mut cmd = process::Command::new("git");
+    cmd.current_dir(cfg_dir).args(args);
+    util::proxy_output_to_logging("git", cmd, &OutputSink::Log)
+        .await
+        .context(format!("Could not execute `git {}`", args.join(" ")))
+}
+
+/// Runs `git args...` in `cfg_dir`, returning its trimmed stdout.
+async fn git_output(cfg_dir: &Path, args: &[&str]) -> Result<String> {
+    let out = process::Command::new("git")
+        .current_dir(cfg_dir)
+        .args(args)
+        .output()
+        .await
+        .context(format!("Could not execute `git {}`", args.join(" ")))?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "`git {}` failed, with stderr:\n{}",
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+}
+
+/// Returns the files under `cfg_dir` that Git neither tracks nor ignores, relative to `cfg_dir`.
+/// Flakes in Git repositories don't include them when they are evaluated locally.
+pub async fn untracked_files(cfg_dir: &Path) -> Result<Vec<String>> {
+    let out = git_output(cfg_dir, &["ls-files", "--others", "--exclude-standard"]).await?;
+    Ok(out.lines().map(str::to_owned).collect())
+}
+
+/// Marks `files` in `cfg_dir` as intended to be added (`git add -N`), so that flakes include
+/// them, without staging their contents.
+pub async fn add_intent_to_add(cfg_dir: &Path, files: &[String]) -> Result<()> {
+    let mut args = vec!["add", "--intent-to-add", "--"];
+    args.extend(files.iter().map(String::as_str));
+    if !git(cfg_dir, &args).await?.success() {
+        return Err(anyhow!("`git add --intent-to-add` failed"));
+    }
+    Ok(())
+}
+
+/// Returns the URL of the flake in `cfg_dir` as of the Git revision `rev` (e.g. `origin/main`),
+/// regardless of what is checked out.
+pub async fn flake_url_at(cfg_dir: &Path, rev: &str) -> Result<String> {
+    let commit = git_output(
+        cfg_dir,
+        &["rev-parse", "--verify", &format!("{}^{{commit}}", rev)],
+    )
+    .await?;
+    let repo = git_output(cfg_dir, &["rev-parse", "--show-toplevel"]).await?;
+    let mut url = format!("git+file://{}?rev={}", repo, commit);
+    // The flake may be in a subdirectory of the repository.
+    let prefix = git_output(cfg_dir, &["rev-parse", "--show-prefix"]).await?;
+    let prefix = prefix.trim_end_matches('/');
+    if !prefix.is_empty() {
+        url.push_str(&format!("&dir={}", prefix));
+    }
+    Ok(url)
+}
+
+/// Returns the abbreviated Git revision checked out in `cfg_dir`.
+pub async fn head_rev(cfg_dir: &Path) -> Result<String> {
+    git_output(cfg_dir, &["rev-parse", "--short", "HEAD"]).await
+}
+
+/// Commits `flake.lock` in `cfg_dir` after the config with hash `cfg_hash` was deployed to
+/// `nodes` at `timestamp`. Other staged changes are not committed.
+/// Does nothing if `flake.lock` has no changes.
+pub async fn commit_flake_lock(
+    cfg_dir: &Path,
+    cfg_hash: &str,
+    timestamp: DateTime<Utc>,
+    nodes: &[&str],
+) -> Result<()> {
+    let unchanged = process::Command::new("git")
+        .current_dir(cfg_dir)
+        .args(["diff", "--quiet", "HEAD", "--", "flake.lock"])
+        .status()
+        .await
+        .context("Could not execute `git diff`")?;
+    if unchanged.success() {
+        return Ok(());
+    }
+    if !git(cfg_dir, &["add", "flake.lock"]).await?.success() {
+        return Err(anyhow!("`git add flake.lock` failed"));
+    }
+    let message = format!(
+        "deploy: {} - {} nodes\n\nDeployed at {}.\n\nNodes:\n{}",
+        cfg_hash,
+        nodes.len(),
+        timestamp.to_rfc3339(),
+        nodes
+            .iter()
+            .map(|node| format!("- {}\n", node))
+            .collect::<String>()
+    );
+    if !git(cfg_dir, &["commit", "-m", &message, "--", "flake.lock"])
+        .await?
+        .success()
+    {
+        return Err(anyhow!("`git commit` failed"));
+    }
+    Ok(())
+}
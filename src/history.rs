@@ -0,0 +1,258 @@
+/// The local, append-only history of deployments.
+use crate::{
+    error::HenixError,
+    util::{self, FileLock},
+};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Local, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// The default history file name, relative to the configuration directory.
+pub const DEFAULT_FILE_NAME: &str = ".henix-history";
+
+/// Returns the history file path, `history_file` if given, otherwise the default in `cfg_dir`.
+pub fn path(cfg_dir: &Path, history_file: Option<&Path>) -> PathBuf {
+    history_file.map_or_else(|| cfg_dir.join(DEFAULT_FILE_NAME), Path::to_owned)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum NodeResult {
+    Succeeded,
+    Failed,
+    /// The deployment was aborted before this node finished.
+    Aborted,
+    /// The node couldn't be reached (`--skip-unreachable`) or wasn't confirmed
+    /// (`--confirm-per-node`), and was skipped.
+    Skipped,
+}
+
+impl NodeResult {
+    pub fn from_success(success: bool) -> Self {
+        if success {
+            NodeResult::Succeeded
+        } else {
+            NodeResult::Failed
+        }
+    }
+}
+
+/// A single deployment, stored as one line of JSON in the history file.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeployRecord {
+    pub timestamp: DateTime<Utc>,
+    /// The user that ran henix.
+    pub user: String,
+    pub hash: String,
+    /// Given using `--change-ref`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_ref: Option<String>,
+    /// Given using `--change-notes`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_notes: Option<String>,
+    /// How long the whole deployment took, in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<u64>,
+    /// Whether the deployment was terminated (by SIGTERM) before every node finished.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub terminated: bool,
+    /// The result of every node that was targeted.
+    pub nodes: BTreeMap<String, NodeResult>,
+    /// Why nodes failed, for the failures that henix can tell apart.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub errors: BTreeMap<String, HenixError>,
+}
+
+impl DeployRecord {
+    /// Whether any targeted node did not succeed. Skipped nodes don't count.
+    pub fn failed(&self) -> bool {
+        self.nodes
+            .values()
+            .any(|result| !matches!(result, NodeResult::Succeeded | NodeResult::Skipped))
+    }
+}
+
+/// Returns the name of the user running henix.
+pub fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_else(|_| "<unknown>".to_owned())
+}
+
+/// Locks the history file at `path`, so that it isn't appended to while it is being pruned.
+/// The lock is held on a separate file, since pruning replaces the history file.
+fn lock(path: &Path) -> Result<FileLock> {
+    FileLock::acquire(&lock_path(path), true)
+}
+
+fn lock_path(path: &Path) -> PathBuf {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    PathBuf::from(lock_path)
+}
+
+/// Appends `record` to the history file at `path`, creating it if needed.
+pub fn append(path: &Path, record: &DeployRecord) -> Result<()> {
+    let _lock = lock(path)?;
+    let mut line = serde_json::to_string(record).context("Could not serialize deploy record")?;
+    line.push('\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .context(format!("Could not open history file `{}`", path.display()))?;
+    // Write the line in one go, so concurrent henix runs don't interleave records.
+    file.write_all(line.as_bytes()).context(format!(
+        "Could not write to history file `{}`",
+        path.display()
+    ))
+}
+
+/// Reads all records from the history file at `path`, oldest first.
+/// A missing history file is treated as empty.
+pub fn read(path: &Path) -> Result<Vec<DeployRecord>> {
+    // Reading only needs to wait for a running prune, so it doesn't create the lock file (e.g. in
+    // the configuration directory): without one, the history hasn't been written to yet.
+    let _lock = FileLock::acquire_existing(&lock_path(path), false)?;
+    read_unlocked(path)
+}
+
+/// Reads the history file. The caller must hold the lock.
+fn read_unlocked(path: &Path) -> Result<Vec<DeployRecord>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).context(format!("Could not open history file `{}`", path.display()))
+        }
+    };
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.context(format!("Could not read history file `{}`", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line).context(format!(
+            "Line {} of history file `{}` is not a valid deploy record",
+            i + 1,
+            path.display()
+        ))?);
+    }
+    Ok(records)
+}
+
+/// Which records `prune` removes.
+pub struct PruneFilter {
+    /// Keeps at least the most recent `keep` records.
+    pub keep: usize,
+    /// Removes records older than this, even if they are among the most recent `keep`.
+    pub older_than: Option<Duration>,
+    /// Only removes records of deployments that failed on some node.
+    pub failed_only: bool,
+}
+
+/// The outcome of `prune`.
+pub struct PruneSummary {
+    pub removed: usize,
+    pub remaining: usize,
+    /// The size of the history file afterwards, in bytes.
+    pub size: u64,
+}
+
+/// Removes the records of the history file at `path` that match `filter`.
+/// The history file is replaced atomically, and deployments that finish in the meantime wait
+/// for the pruning to be done before recording themselves.
+pub fn prune(path: &Path, filter: &PruneFilter) -> Result<PruneSummary> {
+    let _lock = lock(path)?;
+    let records = read_unlocked(path)?;
+    let total = records.len();
+    let cutoff = filter.older_than.map(|age| Utc::now() - age);
+    let kept = records
+        .into_iter()
+        .enumerate()
+        .filter(|(i, record)| {
+            let expired =
+                total - i > filter.keep || cutoff.is_some_and(|cutoff| record.timestamp < cutoff);
+            !expired || (filter.failed_only && !record.failed())
+        })
+        .map(|(_, record)| record)
+        .collect::<Vec<_>>();
+    let removed = total - kept.len();
+    if removed > 0 {
+        let mut contents = String::new();
+        for record in &kept {
+            contents.push_str(
+                &serde_json::to_string(record).context("Could not serialize deploy record")?,
+            );
+            contents.push('\n');
+        }
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        std::fs::write(&tmp_path, contents)
+            .context(format!("Could not write `{}`", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path).context(format!(
+            "Could not replace history file `{}`",
+            path.display()
+        ))?;
+    }
+    let size = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => {
+            return Err(e).context(format!("Could not read history file `{}`", path.display()))
+        }
+    };
+    Ok(PruneSummary {
+        removed,
+        remaining: kept.len(),
+        size,
+    })
+}
+
+/// Pretty-prints `records` to stdout.
+pub fn print(records: &[DeployRecord]) {
+    for record in records {
+        println!(
+            "{}  {}  {}",
+            record
+                .timestamp
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S %z"),
+            record.user,
+            record.hash
+        );
+        if let Some(change_ref) = &record.change_ref {
+            println!("    change: {}", change_ref);
+        }
+        if let Some(change_notes) = &record.change_notes {
+            println!("    notes: {}", change_notes);
+        }
+        if let Some(secs) = record.duration_secs {
+            println!(
+                "    took: {}",
+                util::format_duration(std::time::Duration::from_secs(secs))
+            );
+        }
+        if record.terminated {
+            println!("    terminated");
+        }
+        for (name, result) in &record.nodes {
+            let result = match result {
+                NodeResult::Succeeded => "succeeded",
+                NodeResult::Failed => "failed",
+                NodeResult::Aborted => "aborted",
+                NodeResult::Skipped => "skipped",
+            };
+            match record.errors.get(name) {
+                Some(error) => println!("    {}: {} ({})", name, result, error.kind()),
+                None => println!("    {}: {}", name, result),
+            }
+        }
+    }
+}
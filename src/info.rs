@@ -0,0 +1,82 @@
+/// Version and system information, for `henix info`.
+use serde::Serialize;
+use tokio::process;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Info {
+    pub henix_version: &'static str,
+    /// Short SHA of the commit henix was built from.
+    pub git_revision: &'static str,
+    /// `git describe` of the commit henix was built from.
+    pub git_describe: &'static str,
+    pub build_date: &'static str,
+    pub target: &'static str,
+    /// `None` if `nix` could not be run.
+    pub nix_version: Option<String>,
+    /// `None` if `rsync` could not be run.
+    pub rsync_version: Option<String>,
+    pub os: String,
+}
+
+/// Runs `program --version`, returning the first line of its output.
+async fn tool_version(program: &str) -> Option<String> {
+    let out = process::Command::new(program)
+        .arg("--version")
+        .output()
+        .await
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .next()
+        .map(|line| line.trim().to_owned())
+}
+
+/// Returns the `PRETTY_NAME` of the OS from `/etc/os-release`, falling back to the OS family.
+fn os_name() -> String {
+    let os = std::fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|os_release| {
+            os_release.lines().find_map(|line| {
+                line.strip_prefix("PRETTY_NAME=")
+                    .map(|name| name.trim_matches('"').to_owned())
+            })
+        })
+        .unwrap_or_else(|| std::env::consts::OS.to_owned());
+    format!("{} ({})", os, std::env::consts::ARCH)
+}
+
+pub async fn gather() -> Info {
+    Info {
+        henix_version: env!("CARGO_PKG_VERSION"),
+        git_revision: env!("VERGEN_SHA_SHORT"),
+        git_describe: env!("VERGEN_SEMVER_LIGHTWEIGHT"),
+        build_date: env!("VERGEN_BUILD_DATE"),
+        target: env!("VERGEN_TARGET_TRIPLE"),
+        nix_version: tool_version("nix").await,
+        rsync_version: tool_version("rsync").await,
+        os: os_name(),
+    }
+}
+
+impl Info {
+    /// Prints the information in a human-readable format.
+    pub fn print(&self) {
+        let not_found = || "<not found>".to_owned();
+        println!("henix:    {}", self.henix_version);
+        println!("git:      {} ({})", self.git_revision, self.git_describe);
+        println!("built:    {} for {}", self.build_date, self.target);
+        println!(
+            "nix:      {}",
+            self.nix_version.clone().unwrap_or_else(not_found)
+        );
+        println!(
+            "rsync:    {}",
+            self.rsync_version.clone().unwrap_or_else(not_found)
+        );
+        println!("os:       {}", self.os);
+    }
+}
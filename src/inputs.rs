@@ -0,0 +1,132 @@
+/// Checking that the inputs of flakes can be reached, for `--check-flake-inputs`.
+use crate::nix::{self, NixOpts};
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::{net::TcpStream, time};
+use tracing::{error, info};
+
+/// How long connecting to an input may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the host and port that the locked input `locked` (from `flake.lock`) is fetched
+/// from, if it is fetched over the network.
+fn endpoint(locked: &Value) -> Option<(String, u16)> {
+    let host = |default: &str| {
+        locked
+            .get("host")
+            .and_then(Value::as_str)
+            .unwrap_or(default)
+            .to_owned()
+    };
+    match locked.get("type")?.as_str()? {
+        "github" => Some((host("github.com"), 443)),
+        "gitlab" => Some((host("gitlab.com"), 443)),
+        "sourcehut" => Some((host("git.sr.ht"), 443)),
+        "git" | "hg" | "mercurial" | "tarball" | "file" => {
+            url_endpoint(locked.get("url")?.as_str()?)
+        }
+        // E.g. `path`, which is local.
+        _ => None,
+    }
+}
+
+/// Returns the host and port of `url`, if it is fetched over the network.
+fn url_endpoint(url: &str) -> Option<(String, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    // E.g. `git+https`.
+    let scheme = scheme.rsplit('+').next().unwrap_or(scheme);
+    let default_port = match scheme {
+        "http" => 80,
+        "https" => 443,
+        "ssh" => 22,
+        "git" => 9418,
+        _ => return None,
+    };
+    let authority = rest.split(&['/', '?', '#'][..]).next()?;
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+    let (host, port) = match authority.strip_prefix('[') {
+        // An IPv6 address.
+        Some(ipv6) => {
+            let (host, port) = ipv6.split_once(']')?;
+            (host, port.strip_prefix(':'))
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_owned(), port))
+}
+
+/// Checks that every input of the flake in `dir` that is fetched over the network can be
+/// connected to, and lists the ones that can't.
+pub async fn check(dir: &Path, override_input: &[String]) -> Result<()> {
+    let opts = NixOpts::in_dir(dir).override_input(override_input);
+    let metadata = nix::flake_metadata(&opts, ".")
+        .await
+        .context("Could not get the flake metadata")?;
+    let root = metadata.locks.get("root").and_then(Value::as_str);
+    let nodes = metadata
+        .locks
+        .get("nodes")
+        .and_then(Value::as_object)
+        .ok_or_else(|| anyhow!("The flake metadata has no locked inputs"))?;
+    // Inputs are often fetched from the same place, which only has to be checked once.
+    let mut endpoints = BTreeMap::<(String, u16), Vec<&str>>::new();
+    for (name, node) in nodes {
+        if Some(name.as_str()) == root {
+            continue;
+        }
+        if let Some(endpoint) = node.get("locked").and_then(endpoint) {
+            endpoints.entry(endpoint).or_default().push(name);
+        }
+    }
+    info!(
+        "Checking that the inputs of {} can be reached ({} hosts)",
+        dir.display(),
+        endpoints.len()
+    );
+    let results = futures::future::join_all(endpoints.keys().map(|(host, port)| async move {
+        match time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host.as_str(), *port))).await {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(_) => Err(format!("timed out after {}s", CONNECT_TIMEOUT.as_secs())),
+        }
+    }))
+    .await;
+    let mut unreachable = 0;
+    for (((host, port), inputs), result) in endpoints.iter().zip(results) {
+        if let Err(e) = result {
+            unreachable += inputs.len();
+            let inputs = inputs
+                .iter()
+                .map(|input| format!("`{}`", input))
+                .collect::<Vec<_>>();
+            error!(
+                "Could not connect to {}:{} (for {}): {}",
+                host,
+                port,
+                inputs.join(", "),
+                e
+            );
+        }
+    }
+    if unreachable > 0 {
+        return Err(anyhow!(
+            "{} inputs of the flake in {} can't be reached",
+            unreachable,
+            dir.display()
+        ));
+    }
+    Ok(())
+}
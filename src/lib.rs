@@ -0,0 +1,2803 @@
+//! Handles command line options, getting the deployment configuration,
+//! and calling `deploy::deploy_node` for each node.
+mod bench;
+mod build;
+mod closure;
+mod completion;
+pub mod deploy;
+mod error;
+mod events;
+mod gc;
+mod git;
+mod history;
+mod info;
+mod inputs;
+mod logging;
+mod meta;
+pub mod nix;
+mod nixos_option;
+#[cfg(feature = "notify-desktop")]
+mod notify;
+mod output;
+mod plan;
+mod remote;
+mod ssh;
+mod state;
+mod top;
+mod update;
+mod util;
+mod verify;
+
+use anyhow::{anyhow, Context, Result};
+use error::HenixError;
+use futures::StreamExt;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use structopt::{clap::Shell, StructOpt};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// The `deploy` output of the configuration flake.
+#[derive(Deserialize, JsonSchema)]
+pub struct DeployCfg {
+    /// The nodes to deploy to, by name.
+    // (name, config)
+    pub nodes: BTreeMap<String, NodeCfg>,
+    #[serde(default)]
+    /// Defaults to no requirements.
+    pub policy: PolicyCfg,
+}
+
+/// Requirements that every deployment has to meet.
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyCfg {
+    /// Requires `--change-ref` to be given when deploying.
+    #[serde(default)]
+    pub require_change_ref: bool,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeCfg {
+    /// The host to connect to, or `local` for the machine henix runs on.
+    pub location: String,
+    /// The SSH port of the node, if it isn't 22.
+    pub ssh_port: Option<u16>,
+    /// Connects as the user that the SSH config (e.g. `~/.ssh/config`) has for the location,
+    /// instead of `root`, e.g. for a `Host` alias with its own `User`.
+    #[serde(default)]
+    pub use_ssh_config: bool,
+    /// How to authenticate to the node, tried in order until one is accepted. Defaults to the
+    /// SSH agent.
+    pub auth_methods: Option<Vec<ssh::SshAuthMethod>>,
+    /// A command prefix that remote commands are run through, e.g. `bash -lc`.
+    /// The actual command is passed to it as a single, quoted argument.
+    pub remote_shell: Option<String>,
+    /// If set, overrides `--no-update-known-hosts`/`--add-known-hosts` for this node.
+    /// `true` requires the host key to already be known, `false` adds unknown host keys.
+    pub strict_host_checking: Option<bool>,
+    /// A SOCKS5 proxy (`host:port`) to connect to the node through.
+    pub socks_proxy: Option<String>,
+    /// How often (in seconds) SSH checks that the node is still there when nothing is sent,
+    /// which keeps the connection from being dropped as idle during long builds. Defaults to
+    /// 30, and 0 disables it.
+    pub ssh_keepalive_interval: Option<u64>,
+    /// How many of those checks may go unanswered before SSH gives up on the connection.
+    /// Defaults to 3.
+    pub ssh_keepalive_count_max: Option<u32>,
+    /// If set, overrides `--total-timeout` for this node.
+    pub total_timeout_secs: Option<u64>,
+    /// Gives up on activating the new system after this many seconds, e.g. for slow services.
+    /// With `nixos-rebuild`, which builds and activates in one go, this limits the whole rebuild.
+    /// The program is stopped on the node too, using `timeout`.
+    pub activation_timeout_secs: Option<u64>,
+    /// How commands on the node get root. Defaults to `sudo` for the local node if henix isn't
+    /// run as root, and to `none` otherwise.
+    pub escalation: Option<Escalation>,
+    /// If set, overrides `--compress` for this node.
+    pub compress: Option<Compress>,
+    /// If set, overrides `--compress-level` for this node.
+    pub compress_level: Option<u32>,
+    /// Keeps partially copied files when rsync is interrupted, so that the next copy resumes
+    /// them instead of starting over, e.g. for nodes behind unreliable links.
+    #[serde(default)]
+    pub rsync_partial: bool,
+    /// Whether copying removes files from the node's copy of the configuration that aren't in
+    /// the local one (rsync's `--delete`), `true` by default. `--no-delete` overrides it.
+    pub delete_extraneous: Option<bool>,
+    /// Extra arguments for rsync when copying to the node, e.g. `--rsync-path=/opt/bin/rsync`.
+    /// Each must be a single option, and they come after henix's own, so that they can override
+    /// them.
+    #[serde(default)]
+    pub rsync_args: Vec<String>,
+    /// A shell command (e.g. `hostname`) that is run on the node right after connecting, to
+    /// check that it is the intended machine before anything is copied to it.
+    pub identity_check_cmd: Option<String>,
+    /// What `identityCheckCmd` must print (ignoring surrounding whitespace).
+    pub identity_check_expected: Option<String>,
+    /// The specialisation of the node's system to switch to, unless `--specialisation` is given.
+    pub specialisation: Option<String>,
+    /// Passes `--no-build-nix` to `nixos-rebuild`, which then uses the node's installed Nix
+    /// instead of building the configuration's first. Only safe if they are the same version.
+    #[serde(default)]
+    pub no_build_nix: bool,
+    /// The `nixos-rebuild` to run on the node, e.g. `/run/current-system/sw/bin/nixos-rebuild`
+    /// if it isn't on the PATH of non-login SSH sessions, or to use a specific version.
+    pub nixos_rebuild_path: Option<String>,
+    /// How connecting to the node and copying the configuration to it are retried, e.g. for
+    /// nodes behind unreliable links. Fields that aren't given take their defaults. Nothing is
+    /// retried without it.
+    pub retry_policy: Option<util::RetryPolicy>,
+    /// The flake the node was read from, with `--sources`.
+    #[serde(skip)]
+    pub source: Option<NodeSource>,
+    /// The log level of everything about the node (`error`, `warn`, `info`, `debug` or
+    /// `trace`), instead of the global one, e.g. to debug a single problematic node.
+    pub log_level: Option<String>,
+}
+
+/// A flake attribute that nodes are read from, as listed in the `--sources` file.
+#[derive(Debug, Clone)]
+pub struct NodeSource {
+    pub dir: PathBuf,
+    pub attribute: String,
+}
+
+impl std::fmt::Display for NodeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "`{}#{}`", self.dir.display(), self.attribute)
+    }
+}
+
+/// The `--sources` file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourcesFile {
+    sources: Vec<SourceEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceEntry {
+    /// The flake directory, relative to the sources file.
+    flake: PathBuf,
+    #[serde(default = "default_source_attribute")]
+    attribute: String,
+}
+
+fn default_source_attribute() -> String {
+    "deploy".to_owned()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Escalation {
+    Sudo,
+    Doas,
+    /// Commands are run as the user henix connects as.
+    None,
+}
+
+/// Whether rsync compresses the configuration while copying it to a node.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compress {
+    Off,
+    On,
+    /// Compress if the configuration is large, and the node isn't on a local subnet.
+    Auto,
+}
+
+impl Compress {
+    pub const VARIANTS: &'static [&'static str] = &["off", "on", "auto"];
+}
+
+impl std::str::FromStr for Compress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "off" => Ok(Compress::Off),
+            "on" => Ok(Compress::On),
+            "auto" => Ok(Compress::Auto),
+            _ => Err(anyhow!("Unknown compression setting `{}`", s)),
+        }
+    }
+}
+
+/// What `--preflight` does if nodes can't be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preflight {
+    /// Leaves them out of the deployment, as skipped.
+    Skip,
+    /// Aborts the deployment.
+    Strict,
+}
+
+impl Preflight {
+    pub const VARIANTS: &'static [&'static str] = &["skip", "strict"];
+}
+
+impl std::str::FromStr for Preflight {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Preflight::Skip),
+            "strict" => Ok(Preflight::Strict),
+            _ => Err(anyhow!("Unknown preflight mode `{}`", s)),
+        }
+    }
+}
+
+impl NodeCfg {
+    /// Whether the node is the machine henix runs on, which is managed without SSH.
+    pub fn is_local(&self) -> bool {
+        self.location == "local"
+    }
+
+    /// The configuration directory that is deployed to the node: the flake it was read from
+    /// with `--sources`, otherwise `cfg_dir`.
+    pub fn cfg_dir<'a>(&'a self, cfg_dir: &'a Path) -> &'a Path {
+        match &self.source {
+            Some(source) => &source.dir,
+            None => cfg_dir,
+        }
+    }
+
+    /// The `nixos-rebuild` to run on the node.
+    pub fn nixos_rebuild(&self) -> &str {
+        self.nixos_rebuild_path
+            .as_deref()
+            .unwrap_or("nixos-rebuild")
+    }
+
+    /// How failed connections and copies to the node are retried.
+    pub fn retry_policy(&self) -> &util::RetryPolicy {
+        self.retry_policy
+            .as_ref()
+            .unwrap_or(&util::RetryPolicy::NONE)
+    }
+}
+
+impl Opts {
+    /// Where the deployment writes its `--events`, if it does.
+    fn events(&self) -> Option<&Path> {
+        match &self.cmd {
+            OptCmd::Deploy(dep_opts)
+            | OptCmd::Update(UpdateOpts {
+                deploy: dep_opts, ..
+            }) => dep_opts.events.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "henix")]
+struct Opts {
+    #[structopt(parse(from_os_str), long, env = "HENIX_CFG_DIR")]
+    /// Specifies the path to the directory containing the configuration.
+    cfg_dir: Option<PathBuf>,
+    #[structopt(parse(from_os_str), long, env = "HENIX_HISTORY_FILE")]
+    /// Specifies the path to the deploy history file. Defaults to `.henix-history` in the
+    /// configuration directory.
+    history_file: Option<PathBuf>,
+    #[structopt(flatten)]
+    cfg_source: CfgSourceOpts,
+    #[structopt(
+        long,
+        global = true,
+        possible_values = logging::LEVELS,
+        conflicts_with = "verbose"
+    )]
+    /// Sets the log level. Takes precedence over `$RUST_LOG`, which is used otherwise.
+    log_level: Option<String>,
+    #[structopt(
+        long,
+        global = true,
+        number_of_values = 1,
+        value_name = "node=level",
+        parse(try_from_str = logging::parse_node_level)
+    )]
+    /// Sets the log level of everything about one node, e.g. `--node-log-level web1=debug` to
+    /// debug just that node. Overrides the node's `logLevel`. Can be given multiple times.
+    node_log_level: Vec<(String, tracing::level_filters::LevelFilter)>,
+    #[structopt(short, long, global = true, parse(from_occurrences))]
+    /// Increases the log level; `-v` is `--log-level debug`, `-vv` is `--log-level trace`.
+    verbose: u8,
+    #[structopt(long, global = true, default_value = logging::DEFAULT_PREFIX)]
+    /// Prepended to every log line about a node, with `{node}` replaced by the node's name,
+    /// e.g. to tell apart the logs of nodes deployed to in parallel. An empty prefix disables it.
+    log_prefix: String,
+    #[structopt(long, global = true, default_value = "60")]
+    /// Logs that a command on a node is still running after this many seconds without output.
+    /// 0 disables this.
+    heartbeat: u64,
+    #[structopt(long, global = true)]
+    /// Keeps an SSH master connection to each node at this `ControlPath` (e.g.
+    /// `~/.ssh/henix-%r@%h:%p`) while it is deployed to, which rsync reuses.
+    ssh_control_path: Option<String>,
+    #[structopt(parse(from_os_str), long, global = true, env = "HENIX_KNOWN_HOSTS")]
+    /// Uses this known hosts file instead of `~/.ssh/known_hosts`. `/dev/null` disables host
+    /// key checking entirely.
+    known_hosts: Option<PathBuf>,
+    #[structopt(long, global = true, default_value = "10")]
+    /// Limits how many SSH sessions are open at the same time, e.g. to stay below the
+    /// `MaxStartups` of the SSH servers. Unlike `--max-parallel`, this limits connections rather
+    /// than deployments.
+    max_ssh_connections: usize,
+    #[structopt(long, global = true)]
+    /// Limits how many local `nix eval` and `nix build` commands run at the same time, e.g. if
+    /// concurrent ones contend for Nix's locks. Unlimited by default, other than by
+    /// `--max-eval-jobs` and `henix build --parallel`, which this also bounds.
+    max_eval_parallel: Option<usize>,
+    #[structopt(subcommand)]
+    cmd: OptCmd,
+}
+
+#[derive(StructOpt, Debug)]
+// Only one is ever created, so boxing the options of `deploy` wouldn't save anything.
+#[allow(clippy::large_enum_variant)]
+enum OptCmd {
+    /// Deploy nodes.
+    Deploy(DeployOpts),
+    /// Evaluate and hash everything a deployment needs, and save what it would do to a plan file.
+    Plan(PlanOpts),
+    /// Deploy exactly what a plan file (from `plan`) says.
+    Apply(ApplyOpts),
+    /// Deploy repeatedly and show how long each phase takes, e.g. for capacity planning.
+    Benchmark(BenchmarkOpts),
+    /// Copy the configuration to nodes, without building it.
+    CopyConfig(CopyConfigOpts),
+    /// Build a configuration that was already copied to nodes (using `copy-config`).
+    BuildConfig(BuildConfigOpts),
+    /// Rebuild the configuration last deployed to nodes, without copying anything.
+    RemoteBuild(RemoteBuildOpts),
+    /// Build the systems of nodes locally, without deploying them, e.g. in CI.
+    Build(BuildOpts),
+    /// Update the inputs of the configuration flake, showing what changed, and optionally deploy.
+    Update(UpdateOpts),
+    /// Show recent deployments.
+    History(HistoryOpts),
+    /// Remove old deployments from the history.
+    Prune(PruneOpts),
+    /// Remove old logs (from `--log-dir`) and cached files.
+    Gc(GcOpts),
+    /// Show version and system information, e.g. for bug reports.
+    Info(InfoOpts),
+    /// Check whether the running systems of nodes are the ones henix last deployed.
+    Verify(VerifyOpts),
+    /// Show the live progress of a deployment, from its `--events`.
+    Top(TopOpts),
+    /// Show the value of a NixOS option on nodes, using `nixos-option`.
+    NixosOption(NixosOptionOpts),
+    /// Print a JSON Schema of the `deploy` output of the configuration flake, e.g. for editors.
+    Schema,
+    /// Print a shell completion script.
+    Completion(CompletionOpts),
+    /// Check which nodes can be connected to.
+    Ping(PingOpts),
+}
+
+/// Options controlling where the deploy configuration comes from.
+#[derive(StructOpt, Debug)]
+pub struct CfgSourceOpts {
+    #[structopt(parse(from_os_str), long, alias = "manifest")]
+    /// Reads the deploy configuration from this JSON file (or stdin, with `-`) instead of
+    /// evaluating `.#deploy`. It has the same structure as `.#deploy` (see `henix schema`). The
+    /// configuration directory is still copied and built as usual.
+    cfg_file: Option<PathBuf>,
+
+    #[structopt(long, conflicts_with = "cfg-file")]
+    /// A Nix function that `.#deploy` is passed through before it is used, e.g.
+    /// `d: d // { nodes = builtins.removeAttrs d.nodes [ "test" ]; }`.
+    apply: Option<String>,
+
+    #[structopt(long, number_of_values = 2, value_names = &["input", "flake-url"])]
+    /// Overrides a flake input, e.g. `--override-input nixpkgs github:me/nixpkgs/fix`, both when
+    /// evaluating and when building on the nodes. Can be given multiple times. The flake URL is
+    /// resolved on the node, so a local path has to exist there too.
+    override_input: Vec<String>,
+
+    #[structopt(parse(from_os_str), long, conflicts_with = "cfg-file")]
+    /// Reads the nodes from several flakes, listed in this TOML file as `[[sources]]` with a
+    /// `flake` directory (relative to the file) and an `attribute` (`deploy` by default). Each
+    /// node is copied from and built in the flake it is defined in.
+    sources: Option<PathBuf>,
+}
+
+impl CfgSourceOpts {
+    /// Whether the deploy configuration is read from stdin (`--cfg-file -`).
+    fn cfg_from_stdin(&self) -> bool {
+        self.cfg_file.as_deref() == Some(Path::new("-"))
+    }
+}
+
+/// Options controlling how host keys of nodes are checked.
+#[derive(StructOpt, Debug)]
+pub struct HostKeyOpts {
+    #[structopt(long, conflicts_with = "add-known-hosts")]
+    /// Refuses to connect to nodes whose host key is not already in `known_hosts`.
+    pub no_update_known_hosts: bool,
+
+    #[structopt(long)]
+    /// Adds the host keys of unknown nodes to `known_hosts`. This is the current default, but
+    /// will stop being so in the future.
+    pub add_known_hosts: bool,
+}
+
+impl HostKeyOpts {
+    /// Warns if the user relies on the implicit default.
+    fn warn_if_implicit(&self) {
+        if !self.no_update_known_hosts && !self.add_known_hosts {
+            warn!("Unknown host keys are added to `known_hosts` without confirmation. This default is deprecated; pass --add-known-hosts to keep this behaviour, or --no-update-known-hosts to only connect to known hosts.");
+        }
+    }
+}
+
+/// Options controlling how `nixos-rebuild` is run on the remote.
+#[derive(StructOpt, Debug)]
+pub struct RebuildOpts {
+    #[structopt(long)]
+    /// Makes the rebuild only restart at boot, equivalent to `nixos-rebuild boot`.
+    pub boot: bool,
+
+    #[structopt(long)]
+    /// Passes `--show-trace` to `nixos-rebuild`.
+    pub show_trace: bool,
+
+    #[structopt(long)]
+    /// Doesn't check that `nixos-rebuild` is on the `PATH` of the node before building, for nodes
+    /// with a non-standard `PATH` where `nixos-rebuild` still works.
+    pub skip_nix_check: bool,
+
+    #[structopt(long, conflicts_with = "boot", possible_values = deploy::SwitchAction::VARIANTS)]
+    /// Builds the system with `nix build` instead of `nixos-rebuild`, then activates it by
+    /// running its `switch-to-configuration` with this action.
+    pub switch_action: Option<deploy::SwitchAction>,
+
+    #[structopt(long, conflicts_with_all = &["boot", "switch-action"])]
+    /// Builds the system with `nix build`, stages it as the boot default
+    /// (`switch-to-configuration boot`), and only then switches to it as a final quick step.
+    pub staged: bool,
+
+    #[structopt(long, conflicts_with_all = &["boot", "staged"])]
+    /// Switches to this specialisation of the system (from `specialisation.<name>`) instead of
+    /// the system itself, on every node. Nodes can also set a default with `specialisation`.
+    pub specialisation: Option<String>,
+}
+
+impl RebuildOpts {
+    /// The specialisation to switch `node_cfg` to, if any. Specialisations can only be switched
+    /// to, so this fails if the system would only be activated at boot.
+    fn specialisation<'a>(&'a self, node_cfg: &'a NodeCfg) -> Result<Option<&'a str>> {
+        let specialisation = match self
+            .specialisation
+            .as_deref()
+            .or(node_cfg.specialisation.as_deref())
+        {
+            Some(specialisation) => specialisation,
+            None => return Ok(None),
+        };
+        if self.boot || self.staged || self.switch_action == Some(deploy::SwitchAction::Boot) {
+            return Err(anyhow!(
+                "Specialisation `{}` can't be activated at boot, only switched to",
+                specialisation
+            ));
+        }
+        Ok(Some(specialisation))
+    }
+
+    /// Whether the node runs the new system once it is deployed, rather than after a reboot.
+    fn activates_now(&self) -> bool {
+        !self.boot
+            && !matches!(
+                self.switch_action,
+                Some(deploy::SwitchAction::Boot) | Some(deploy::SwitchAction::DryActivate)
+            )
+    }
+
+    /// How the system is activated, as recorded in the metadata on the nodes.
+    fn activation(&self) -> &'static str {
+        match self.switch_action {
+            Some(action) => action.name(),
+            None if self.staged => "staged",
+            None if self.boot => "boot",
+            None => "switch",
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub struct DeployOpts {
+    #[structopt(flatten)]
+    pub rebuild: RebuildOpts,
+
+    #[structopt(flatten)]
+    pub host_keys: HostKeyOpts,
+
+    #[structopt(flatten)]
+    pub copy: CopyOpts,
+
+    #[structopt(short, long = "target")]
+    /// Specifies which targets to deploy to. If a non-present target is specified, an error will
+    /// be thrown. Defaults to the comma-separated node names in `HENIX_TARGETS`, if it is set.
+    pub targets: Option<Vec<String>>,
+
+    #[structopt(long)]
+    /// Deploys to all nodes, regardless of --target and `HENIX_TARGETS`.
+    pub all_targets: bool,
+
+    #[structopt(long, conflicts_with_all = &["targets", "all-targets"])]
+    /// Only deploys to the nodes that failed (or were aborted) in the last deployment of this
+    /// configuration, as recorded in the local state.
+    pub retry_failed: bool,
+
+    #[structopt(long)]
+    /// Limits how many node deployments are started per second. Fractional values are allowed,
+    /// e.g. `0.5` starts a deployment every two seconds.
+    pub rate_limit: Option<f64>,
+
+    #[structopt(long)]
+    /// Limits how many nodes are deployed to at the same time. Defaults to all of them.
+    pub max_parallel: Option<usize>,
+
+    #[structopt(long)]
+    /// Evaluates the system of every node locally before deploying, so that nodes whose
+    /// configuration doesn't evaluate fail before anything is copied, and checks that each node
+    /// ends up running the system that was evaluated.
+    pub eval_locally: bool,
+
+    #[structopt(long, default_value = "2")]
+    /// Limits how many systems are evaluated at the same time with `--eval-locally`, since
+    /// evaluation takes a lot of memory.
+    pub max_eval_jobs: usize,
+
+    #[structopt(long)]
+    /// Aborts the deployment once more than this many nodes have failed. Deployments that are
+    /// still running are cancelled, and no new ones are started.
+    pub max_failures: Option<usize>,
+
+    #[structopt(long)]
+    /// Aborts the deployment as soon as a node fails. Nodes that haven't started activating the
+    /// new system yet are cancelled and reported as aborted, while those that have are allowed
+    /// to finish, so that no node is left half-activated.
+    pub fail_fast: bool,
+
+    #[structopt(long)]
+    /// Skips the nodes that can't be connected to (e.g. because they are powered off) instead of
+    /// failing them. Skipped nodes don't count as failed, and are retried with --retry-failed.
+    /// Canaries are never skipped.
+    pub skip_unreachable: bool,
+
+    #[structopt(long, possible_values = Preflight::VARIANTS)]
+    /// Connects to every node before hashing, copying or building anything, and prints which
+    /// can be reached. Those that can't are left out of the deployment as skipped, or abort it
+    /// with `--preflight strict`. An unreachable canary always aborts it.
+    pub preflight: Option<Option<Preflight>>,
+
+    #[structopt(long, conflicts_with = "boot")]
+    /// Before activating the new system, schedules a rollback on each node that runs after this
+    /// many seconds, and cancels it once henix could connect to the node again. If the node
+    /// can't be reached after activating, or henix dies, the node rolls back by itself. This
+    /// needs the system to be built before activating it, so it implies `--switch-action switch`
+    /// unless `--switch-action` or `--staged` is given.
+    pub confirm_timeout: Option<u64>,
+
+    #[structopt(long, conflicts_with_all = &["max-parallel", "activate-all-at-once", "total-timeout"])]
+    /// Asks before building and activating each node whether to deploy to it: `y` deploys to it,
+    /// `a` to it and all remaining nodes, `q` quits the deployment, and anything else skips it.
+    /// Nodes are deployed to one at a time.
+    pub confirm_per_node: bool,
+
+    #[structopt(long)]
+    /// Gives up on a node if deploying to it takes longer than this many seconds in total.
+    /// Commands that are still running on the node are not stopped.
+    pub total_timeout: Option<u64>,
+
+    #[structopt(long)]
+    /// Deploys to this node first, and only deploys to the others if it succeeds and is then
+    /// running the deployed system. Can be given multiple times.
+    pub canary: Vec<String>,
+
+    #[structopt(long, requires = "staged", conflicts_with_all = &["canary", "max-parallel"])]
+    /// With `--staged`, waits until every node has staged the new system before switching any of
+    /// them to it, so that they switch at nearly the same time. Every node is deployed to at once.
+    pub activate_all_at_once: bool,
+
+    #[structopt(long)]
+    /// Deploys to the local node (`location = "local"`) at the same time as the other nodes.
+    /// By default, it is deployed to after all other nodes are done.
+    pub local_in_parallel: bool,
+
+    #[structopt(long, alias = "event-stream", parse(from_os_str))]
+    /// Writes the progress of the deployment to this file (or stdout, with `-`, which moves the
+    /// logs to stderr) as JSON lines, e.g. for `henix top`. If it is a named pipe, the
+    /// deployment only starts once the pipe is opened for reading.
+    pub events: Option<PathBuf>,
+
+    #[structopt(long, requires = "events")]
+    /// Also writes every line of output of the commands run for the nodes to `--events`.
+    pub events_include_output: bool,
+
+    #[structopt(long)]
+    /// Saves the full output of every node to `{node}-{time}.log` in this directory, or in
+    /// `.henix-logs` in the configuration directory if no directory is given.
+    pub log_dir: Option<Option<PathBuf>>,
+
+    #[structopt(long)]
+    /// Copies the configuration even if a complete copy of it is already on the node.
+    pub force_copy: bool,
+
+    #[structopt(long)]
+    /// Runs every step on every node even if the configuration is unchanged, e.g. to re-apply it
+    /// after the system's state got corrupted. For now, this implies --force-copy.
+    pub force: bool,
+
+    #[structopt(long)]
+    /// Copies every file, instead of hardlinking the files that didn't change from the
+    /// configuration `/etc/henix/latest` points to.
+    pub no_link_dest: bool,
+
+    #[structopt(long, default_value = "copy", possible_values = deploy::DeployPhase::VARIANTS)]
+    /// Skips the phases before this one, e.g. `build` to only rebuild a configuration that was
+    /// already copied, or `link` to only record and link it after it was built. `--switch-action`
+    /// and `--staged` need `build` or an earlier phase.
+    pub from_phase: deploy::DeployPhase,
+
+    #[structopt(long, default_value = "interleaved", possible_values = output::OutputMode::VARIANTS)]
+    /// How the output of commands run on each node is shown. `grouped` holds back each node's
+    /// output and prints it as one block once that node finishes.
+    pub output: output::OutputMode,
+
+    #[structopt(long)]
+    /// A change reference (e.g. a ticket number) to record with this deployment in the history.
+    pub change_ref: Option<String>,
+
+    #[structopt(long)]
+    /// A human-readable name for the deployed configuration (e.g. `release-2024-06`), which is
+    /// recorded on the nodes and linked from `/etc/henix/labels/{label}`. Only letters, digits,
+    /// `.`, `_` and `-` are allowed.
+    pub label: Option<String>,
+
+    #[structopt(long)]
+    /// Free-form notes to record with this deployment in the history.
+    pub change_notes: Option<String>,
+
+    #[structopt(long)]
+    /// Doesn't save the deployed systems to the local state (in `$XDG_STATE_HOME/henix`).
+    pub no_state: bool,
+
+    #[structopt(long)]
+    /// Prints the settings every selected node would be deployed with as JSON (after applying
+    /// the command line flags and defaults), and exits without deploying.
+    pub dump_config: bool,
+
+    #[structopt(long)]
+    /// Commits `flake.lock` (if it changed) once every node was deployed successfully.
+    pub commit_on_success: bool,
+
+    #[cfg(feature = "notify-desktop")]
+    #[structopt(long)]
+    /// Shows a desktop notification with how many nodes succeeded and which failed once the
+    /// deployment finished, e.g. to not have to watch a long one.
+    pub notify_desktop: bool,
+
+    #[structopt(long)]
+    /// Marks the files in the configuration directory that Git doesn't track as intended to be
+    /// added (`git add -N`) before evaluating, so that the flake includes them.
+    pub add_untracked: bool,
+
+    #[structopt(long)]
+    /// Checks that every input of the flake that is fetched over the network can be connected
+    /// to before deploying, and lists the ones that can't, e.g. for air-gapped networks.
+    pub check_flake_inputs: bool,
+
+    #[structopt(long)]
+    /// After copying, checks that the `nix-hash` of the copy on each node matches the local
+    /// files that were copied, and fails the node before building if it doesn't. Skipped for
+    /// copies that don't delete extraneous files.
+    pub verify_copy: bool,
+
+    #[structopt(long)]
+    /// Measures the closure of each node's system (with `nix path-info --closure-size`) before
+    /// and after deploying, and reports how much it grew.
+    pub report_closure_size: bool,
+
+    #[structopt(long)]
+    /// Fails a node whose system's closure grows by more than this many MiB, before activating it
+    /// if the system is built separately (`--switch-action` or `--staged`). Implies
+    /// `--report-closure-size`.
+    pub max_closure_growth_mb: Option<u64>,
+}
+
+/// Options controlling how the configuration is copied to nodes.
+#[derive(StructOpt, Debug)]
+pub struct CopyOpts {
+    #[structopt(long, default_value = "off", possible_values = Compress::VARIANTS)]
+    /// Whether rsync compresses the configuration while copying it. `auto` compresses if it is
+    /// larger than 1 MiB and the node isn't on a local subnet. Can be overridden per node.
+    pub compress: Compress,
+
+    #[structopt(long)]
+    /// The compression level rsync uses (`--compress-level`), if compressing.
+    pub compress_level: Option<u32>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Also excludes the files matching the patterns in this file (see rsync's `--exclude-from`)
+    /// from the copy, e.g. a list shared across configurations that lives outside of them.
+    pub exclude_from: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Doesn't remove files from the node's copy of the configuration that aren't in the local
+    /// one (rsync's `--delete`), e.g. state that an activation script writes there. Files that
+    /// were removed locally then stay on the node, and are still part of the configuration that
+    /// is built if the copy is reused.
+    pub no_delete: bool,
+
+    #[structopt(long)]
+    /// Only removes files from the node's copy of the configuration that aren't in the local one
+    /// (rsync's `--delete`) if henix completely copied that configuration before, so that files
+    /// in a `/etc/henix/{hash}` that henix didn't create are never removed.
+    pub no_delete_on_first_deploy: bool,
+
+    #[structopt(long)]
+    /// Copies the configuration even if its directory is empty or has no `flake.nix`, which
+    /// otherwise aborts before anything is copied.
+    pub allow_empty: bool,
+}
+
+impl CopyOpts {
+    /// Whether copying to `node_cfg` removes files that aren't in the local configuration.
+    fn delete_extraneous(&self, node_cfg: &NodeCfg) -> bool {
+        !self.no_delete && node_cfg.delete_extraneous.unwrap_or(true)
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub struct CopyConfigOpts {
+    #[structopt(flatten)]
+    copy: CopyOpts,
+
+    #[structopt(flatten)]
+    host_keys: HostKeyOpts,
+
+    #[structopt(short, long = "target")]
+    /// Specifies which targets to copy to. If a non-present target is specified, an error will
+    /// be thrown.
+    targets: Option<Vec<String>>,
+
+    #[structopt(long)]
+    /// Copies the configuration to `/etc/henix/{hash}` using this hash instead of the computed
+    /// one, e.g. to stage a previously computed version.
+    hash: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct BuildConfigOpts {
+    #[structopt(flatten)]
+    rebuild: RebuildOpts,
+
+    #[structopt(flatten)]
+    host_keys: HostKeyOpts,
+
+    #[structopt(short, long = "target")]
+    /// Specifies which targets to build on. If a non-present target is specified, an error will
+    /// be thrown.
+    targets: Option<Vec<String>>,
+
+    #[structopt(long)]
+    /// Builds the configuration at `/etc/henix/{hash}` instead of the one matching the hash of
+    /// the local configuration.
+    hash: Option<String>,
+
+    #[structopt(long)]
+    /// Doesn't save the deployed systems to the local state (in `$XDG_STATE_HOME/henix`).
+    no_state: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct RemoteBuildOpts {
+    #[structopt(flatten)]
+    rebuild: RebuildOpts,
+
+    #[structopt(flatten)]
+    host_keys: HostKeyOpts,
+
+    #[structopt(short, long = "target")]
+    /// Specifies which targets to build on. If a non-present target is specified, an error will
+    /// be thrown.
+    targets: Option<Vec<String>>,
+
+    #[structopt(long)]
+    /// Builds the configuration at `/etc/henix/{hash}` instead of the one `/etc/henix/latest`
+    /// links to.
+    hash: Option<String>,
+
+    #[structopt(long)]
+    /// Doesn't save the deployed systems to the local state (in `$XDG_STATE_HOME/henix`).
+    no_state: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct HistoryOpts {
+    #[structopt(short = "n", long, default_value = "10")]
+    /// How many of the most recent deployments to show.
+    count: usize,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct PruneOpts {
+    #[structopt(long, default_value = "100")]
+    /// How many of the most recent deployments to keep.
+    keep: usize,
+
+    #[structopt(long)]
+    /// Also removes deployments older than this many days, even if they are among the most
+    /// recent ones.
+    older_than: Option<u32>,
+
+    #[structopt(long)]
+    /// Only removes deployments that failed on some node.
+    failed_only: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct GcOpts {
+    #[structopt(long, default_value = "10")]
+    /// How many of the most recent logs of each node (and cached files of each kind) to keep.
+    keep: usize,
+
+    #[structopt(long)]
+    /// Also removes files older than this many days, even if they are among the most recent
+    /// ones.
+    older_than: Option<u32>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// The directory that logs were saved in with `--log-dir`, `.henix-logs` in the
+    /// configuration directory by default.
+    log_dir: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Only lists the files that would be removed.
+    dry_run: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct TopOpts {
+    #[structopt(parse(from_os_str))]
+    /// The `--events` of the deployment to show.
+    events: PathBuf,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct PlanOpts {
+    #[structopt(long, short, parse(from_os_str))]
+    /// The file to write the plan to.
+    out: PathBuf,
+
+    #[structopt(flatten)]
+    deploy: DeployOpts,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct ApplyOpts {
+    #[structopt(parse(from_os_str))]
+    /// The plan file written by `henix plan`.
+    plan: PathBuf,
+
+    #[structopt(long)]
+    /// Deploys the plan even if the configuration changed since it was made. The current
+    /// configuration is deployed then.
+    force: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct BenchmarkOpts {
+    #[structopt(long, default_value = "1")]
+    /// How many times to deploy to each node. The table shows the minimum, maximum and mean
+    /// duration of each phase across them.
+    iterations: usize,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Also writes the duration of every phase of every deployment to this file, as CSV.
+    csv: Option<PathBuf>,
+
+    // Nodes are deployed to one at a time, unless `--max-parallel` is given. Unless
+    // `--switch-action` or `--staged` is given, the new system is only dry-activated.
+    #[structopt(flatten)]
+    deploy: DeployOpts,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct PingOpts {
+    #[structopt(flatten)]
+    host_keys: HostKeyOpts,
+
+    #[structopt(short, long = "target")]
+    /// Specifies which targets to connect to. If a non-present target is specified, an error
+    /// will be thrown.
+    targets: Option<Vec<String>>,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct CompletionOpts {
+    #[structopt(long, possible_values = &Shell::variants(), required_unless = "cache-nodes")]
+    /// The shell to print the completion script for.
+    shell: Option<Shell>,
+
+    #[structopt(long, conflicts_with = "shell")]
+    /// Caches the names of the nodes, which the zsh completion of `--target` uses. It runs this
+    /// itself if they aren't cached yet, so this is only needed after nodes changed.
+    cache_nodes: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct InfoOpts {
+    #[structopt(long)]
+    /// Prints the information as JSON.
+    json: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct BuildOpts {
+    #[structopt(short, long = "target")]
+    /// Specifies which nodes to build. If a non-present target is specified, an error will be
+    /// thrown.
+    targets: Option<Vec<String>>,
+
+    #[structopt(long)]
+    /// Only builds the nodes whose system changed since this Git revision (e.g. `origin/main`),
+    /// which keeps CI of pull requests fast.
+    since: Option<String>,
+
+    #[structopt(long, default_value = "2")]
+    /// Limits how many nodes are built at the same time.
+    parallel: usize,
+
+    #[structopt(long)]
+    /// Prints a JSON object of the store path of each node that was built, instead of one line
+    /// per node.
+    json: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct UpdateOpts {
+    #[structopt(long = "input")]
+    /// Only updates this input of the flake (with `nix flake lock --update-input`). Can be given
+    /// multiple times. By default, all inputs are updated (with `nix flake update`).
+    inputs: Vec<String>,
+
+    #[structopt(long)]
+    /// Deploys the updated configuration afterwards, with the options of `henix deploy`, after
+    /// asking for confirmation. Nothing is deployed if no input changed.
+    and_deploy: bool,
+
+    #[structopt(long, short, requires = "and-deploy")]
+    /// Deploys without asking for confirmation.
+    yes: bool,
+
+    #[structopt(flatten)]
+    deploy: DeployOpts,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct VerifyOpts {
+    #[structopt(flatten)]
+    host_keys: HostKeyOpts,
+
+    #[structopt(short, long = "target")]
+    /// Specifies which targets to check. If a non-present target is specified, an error will
+    /// be thrown.
+    targets: Option<Vec<String>>,
+
+    #[structopt(long)]
+    /// Doesn't show the last known deployment from the local state for nodes that could not
+    /// be checked.
+    no_state: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct NixosOptionOpts {
+    #[structopt(flatten)]
+    host_keys: HostKeyOpts,
+
+    #[structopt(short, long = "target")]
+    /// Specifies which targets to query. If a non-present target is specified, an error will
+    /// be thrown.
+    targets: Option<Vec<String>>,
+
+    #[structopt(long)]
+    /// Prints a JSON object of the value on each node, using `nixos-option --json`.
+    json: bool,
+
+    /// The option to show, e.g. `services.nginx.enable`.
+    option: String,
+}
+
+/// Evaluates the deploy configuration and returns the nodes specified by `targets`,
+/// or all of them if there are no `targets`.
+async fn get_nodes(
+    cfg_dir: &Path,
+    cfg_source: &CfgSourceOpts,
+    targets: Option<&Vec<String>>,
+) -> Result<Vec<(String, NodeCfg)>> {
+    let nodes = select_nodes(get_deploy_cfg(cfg_dir, cfg_source).await?.nodes, targets)?;
+    apply_log_levels(&nodes)?;
+    check_auth_methods(&nodes)?;
+    Ok(nodes)
+}
+
+/// Evaluates the deploy configuration, or reads it from `--cfg-file` if given.
+async fn get_deploy_cfg(cfg_dir: &Path, cfg_source: &CfgSourceOpts) -> Result<DeployCfg> {
+    if let Some(cfg_file) = &cfg_source.cfg_file {
+        let (name, contents) = if cfg_source.cfg_from_stdin() {
+            info!("Reading deploy information from stdin");
+            let mut contents = Vec::new();
+            std::io::Read::read_to_end(&mut std::io::stdin(), &mut contents)
+                .context("Could not read the config from stdin")?;
+            ("The config from stdin".to_owned(), contents)
+        } else {
+            info!("Reading deploy information from `{}`", cfg_file.display());
+            let contents = std::fs::read(cfg_file).context(format!(
+                "Could not read config file `{}`",
+                cfg_file.display()
+            ))?;
+            (format!("Config file `{}`", cfg_file.display()), contents)
+        };
+        // The error contains the line and column.
+        return serde_json::from_slice(&contents).context(format!(
+            "{} does not match the deploy configuration schema",
+            name
+        ));
+    }
+    if let Some(sources_file) = &cfg_source.sources {
+        return get_merged_deploy_cfg(sources_file, cfg_source).await;
+    }
+    info!("Gathering deploy information");
+    let nix_opts = nix::NixOpts::in_dir(cfg_dir).override_input(&cfg_source.override_input);
+    nix::eval(&nix_opts, ".#deploy", cfg_source.apply.as_deref())
+        .await
+        .context("Could not get deploy configuration")
+}
+
+/// Evaluates the deploy configuration of every flake in the `--sources` file, and merges
+/// their nodes. A node name may only be used once across all flakes.
+async fn get_merged_deploy_cfg(
+    sources_file: &Path,
+    cfg_source: &CfgSourceOpts,
+) -> Result<DeployCfg> {
+    let contents = std::fs::read_to_string(sources_file).context(format!(
+        "Could not read sources file `{}`",
+        sources_file.display()
+    ))?;
+    let sources: SourcesFile = toml::from_str(&contents).context(format!(
+        "Sources file `{}` is not valid",
+        sources_file.display()
+    ))?;
+    if sources.sources.is_empty() {
+        return Err(anyhow!(
+            "Sources file `{}` does not list any sources",
+            sources_file.display()
+        ));
+    }
+    let base = sources_file.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = DeployCfg {
+        nodes: BTreeMap::new(),
+        policy: PolicyCfg::default(),
+    };
+    for entry in sources.sources {
+        let source = NodeSource {
+            dir: base.join(&entry.flake),
+            attribute: entry.attribute,
+        };
+        info!("Gathering deploy information from {}", source);
+        let nix_opts = nix::NixOpts::in_dir(&source.dir).override_input(&cfg_source.override_input);
+        let deploy_cfg: DeployCfg = nix::eval(
+            &nix_opts,
+            &format!(".#{}", source.attribute),
+            cfg_source.apply.as_deref(),
+        )
+        .await
+        .context(format!(
+            "Could not get deploy configuration from {}",
+            source
+        ))?;
+        // The policy applies to the whole deployment, so the strictest one wins.
+        merged.policy.require_change_ref |= deploy_cfg.policy.require_change_ref;
+        for (name, mut node_cfg) in deploy_cfg.nodes {
+            if let Some(existing) = merged.nodes.get(&name) {
+                return Err(anyhow!(
+                    "Node `{}` is defined in both {} and {}",
+                    name,
+                    existing.source.as_ref().unwrap(),
+                    source
+                ));
+            }
+            node_cfg.source = Some(source.clone());
+            merged.nodes.insert(name, node_cfg);
+        }
+    }
+    Ok(merged)
+}
+
+/// Returns the targets in `HENIX_TARGETS` (comma-separated node names), if it is set and not
+/// empty, for when `--target` isn't given.
+fn env_targets() -> Option<Vec<String>> {
+    let var = std::env::var("HENIX_TARGETS").ok()?;
+    let targets = var
+        .split(',')
+        .map(str::trim)
+        .filter(|target| !target.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    if targets.is_empty() {
+        return None;
+    }
+    info!(
+        "Deploying to the targets in HENIX_TARGETS: {}",
+        targets.join(", ")
+    );
+    Some(targets)
+}
+
+/// Returns the nodes specified by `targets`, or all of them if there are no `targets`.
+fn select_nodes(
+    nodes: BTreeMap<String, NodeCfg>,
+    targets: Option<&Vec<String>>,
+) -> Result<Vec<(String, NodeCfg)>> {
+    // Check if all targets exist
+    if let Some(targets) = targets {
+        for target in targets {
+            if !nodes.contains_key(target) {
+                return Err(anyhow!("Node name `{}` (specified using --target or HENIX_TARGETS) does not exist. Did you remember to `git add` its configuration?", target));
+            }
+        }
+    }
+    // If the user-specified `targets` exists, check if the node is specified in it.
+    // Otherwise, just allow it through.
+    Ok(nodes
+        .into_iter()
+        .filter(|(name, _)| targets.is_none_or(|targets| targets.iter().any(|t| t == name)))
+        .collect())
+}
+
+/// Returns the nodes that did not succeed in the last deployment of the configuration in
+/// `cfg_dir`, for `--retry-failed`. Nodes that were removed from `nodes` since are skipped.
+async fn failed_nodes(cfg_dir: &Path, nodes: &BTreeMap<String, NodeCfg>) -> Result<Vec<String>> {
+    let state = state::load()
+        .await
+        .context("Could not load the local state")?;
+    let last_run = state.last_run(cfg_dir).ok_or_else(|| {
+        anyhow!("No previous deployment of this configuration is recorded in the local state, so there is nothing to retry")
+    })?;
+    let mut failed = Vec::new();
+    for (name, result) in last_run {
+        if *result == history::NodeResult::Succeeded {
+            continue;
+        }
+        if nodes.contains_key(name) {
+            failed.push(name.clone());
+        } else {
+            warn!("Node `{}` failed last time, but no longer exists", name);
+        }
+    }
+    if failed.is_empty() {
+        return Err(anyhow!(
+            "All nodes succeeded in the last deployment, there is nothing to retry"
+        ));
+    }
+    info!(
+        "Retrying the nodes that failed last time: {}",
+        failed.join(", ")
+    );
+    Ok(failed)
+}
+
+/// Prints the effective settings of the deployment and of each node, for `--dump-config`.
+fn dump_config(
+    dep_opts: &DeployOpts,
+    policy: &PolicyCfg,
+    nodes: &[(String, NodeCfg)],
+) -> Result<()> {
+    let nodes = nodes
+        .iter()
+        .map(|(name, node_cfg)| {
+            let local = node_cfg.is_local();
+            let resolved = serde_json::json!({
+                "location": node_cfg.location,
+                "sshDestination": if local {
+                    None
+                } else {
+                    Some(ssh::RemoteTarget::of(node_cfg)?.ssh_destination())
+                },
+                "useSshConfig": node_cfg.use_ssh_config,
+                "authMethods": ssh::auth_methods(node_cfg).iter().map(ssh::SshAuthMethod::redacted).collect::<Vec<_>>(),
+                "socksProxy": node_cfg.socks_proxy,
+                "sshKeepaliveInterval": ssh::keepalive(node_cfg).0,
+                "sshKeepaliveCountMax": ssh::keepalive(node_cfg).1,
+                "remoteShell": node_cfg.remote_shell,
+                "source": node_cfg.source.as_ref().map(ToString::to_string),
+                "escalation": remote::escalation(node_cfg),
+                "strictHostChecking": !local && ssh::strict_host_checking(node_cfg, &dep_opts.host_keys),
+                "totalTimeoutSecs": node_cfg.total_timeout_secs.or(dep_opts.total_timeout),
+                "activationTimeoutSecs": node_cfg.activation_timeout_secs,
+                "compress": node_cfg.compress.unwrap_or(dep_opts.copy.compress),
+                "compressLevel": node_cfg.compress_level.or(dep_opts.copy.compress_level),
+                "rsyncPartial": node_cfg.rsync_partial,
+                "deleteExtraneous": dep_opts.copy.delete_extraneous(node_cfg),
+                "rsyncArgs": node_cfg.rsync_args,
+                "identityCheckCmd": node_cfg.identity_check_cmd,
+                "specialisation": dep_opts.rebuild.specialisation.as_ref().or(node_cfg.specialisation.as_ref()),
+                "noBuildNix": node_cfg.no_build_nix,
+                "nixosRebuild": node_cfg.nixos_rebuild(),
+                "retryPolicy": node_cfg.retry_policy(),
+                "canary": dep_opts.canary.contains(name),
+                "deployedLast": local && !dep_opts.local_in_parallel,
+            });
+            Ok((name.clone(), resolved))
+        })
+        .collect::<Result<BTreeMap<_, _>>>()?;
+    let dump = serde_json::json!({
+        "settings": {
+            "requireChangeRef": policy.require_change_ref,
+            "fromPhase": dep_opts.from_phase.name(),
+            "activation": match dep_opts.rebuild.switch_action {
+                Some(action) => format!("switch-to-configuration {}", action.name()),
+                None if dep_opts.rebuild.staged => "switch-to-configuration boot, then test".to_owned(),
+                None if dep_opts.rebuild.boot => "nixos-rebuild boot".to_owned(),
+                None => "nixos-rebuild switch".to_owned(),
+            },
+            "activateAllAtOnce": dep_opts.activate_all_at_once,
+            "maxParallel": dep_opts.max_parallel,
+            "maxFailures": dep_opts.max_failures,
+            "failFast": dep_opts.fail_fast,
+            "reportClosureSize": dep_opts.report_closure_size || dep_opts.max_closure_growth_mb.is_some(),
+            "maxClosureGrowthMb": dep_opts.max_closure_growth_mb,
+            "skipUnreachable": dep_opts.skip_unreachable,
+            "confirmTimeout": dep_opts.confirm_timeout,
+            "preflight": match dep_opts.preflight {
+                Some(Some(Preflight::Strict)) => Some("strict"),
+                Some(_) => Some("skip"),
+                None => None,
+            },
+            "confirmPerNode": dep_opts.confirm_per_node,
+            "rateLimit": dep_opts.rate_limit,
+        },
+        "nodes": nodes,
+    });
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&dump).context("Could not serialize the settings")?
+    );
+    Ok(())
+}
+
+/// Looks for files in `cfg_dir` that Git doesn't track, which the flake doesn't include when it
+/// is evaluated locally. With `add`, they are `git add -N`ed. Otherwise, untracked Nix files are
+/// warned about, and all untracked files are returned, for `warn_untracked`.
+async fn check_untracked(cfg_dir: &Path, add: bool) -> Result<Vec<String>> {
+    let untracked = match git::untracked_files(cfg_dir).await {
+        Ok(untracked) => untracked,
+        Err(e) => {
+            // E.g. the configuration isn't in a Git repository, in which case this doesn't matter.
+            debug!("Could not list untracked files: {:#}", e);
+            return Ok(Vec::new());
+        }
+    };
+    if untracked.is_empty() {
+        return Ok(untracked);
+    }
+    if add {
+        git::add_intent_to_add(cfg_dir, &untracked)
+            .await
+            .context("Could not add the untracked files")?;
+        info!(
+            "Marked {} untracked files as intended to be added: {}",
+            untracked.len(),
+            untracked.join(", ")
+        );
+        return Ok(Vec::new());
+    }
+    let nix_files = untracked
+        .iter()
+        .filter(|file| file.ends_with(".nix"))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !nix_files.is_empty() {
+        warn!(
+            "These Nix files aren't tracked by Git, so the flake doesn't include them when it is evaluated locally: {}. `git add` them, or pass --add-untracked",
+            nix_files.join(", ")
+        );
+    }
+    Ok(untracked)
+}
+
+/// How many untracked files `warn_untracked` lists at most.
+const MAX_UNTRACKED_LISTED: usize = 20;
+
+/// Warns about the `untracked` files after evaluating the flake locally failed with `e`, since
+/// importing one of them is a common cause. The files the error mentions are listed if there
+/// are any, and otherwise all of them.
+fn warn_untracked(e: &anyhow::Error, untracked: &[String]) {
+    let message = format!("{:#}", e);
+    let mentioned = untracked
+        .iter()
+        .filter(|file| message.contains(file.as_str()))
+        .collect::<Vec<_>>();
+    let listed = if mentioned.is_empty() {
+        untracked.iter().collect()
+    } else {
+        mentioned
+    };
+    if listed.is_empty() {
+        return;
+    }
+    let mut list = listed
+        .iter()
+        .take(MAX_UNTRACKED_LISTED)
+        .map(|file| file.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if listed.len() > MAX_UNTRACKED_LISTED {
+        list.push_str(&format!(
+            " and {} more",
+            listed.len() - MAX_UNTRACKED_LISTED
+        ));
+    }
+    warn!(
+        "Evaluating the flake failed, and these files aren't tracked by Git, so the flake doesn't include them: {}. Did you remember to `git add` them (or pass --add-untracked)?",
+        list
+    );
+}
+
+/// Checks that the `logLevel` of every node is a valid log level, and has the logs use them.
+fn apply_log_levels(nodes: &[(String, NodeCfg)]) -> Result<()> {
+    for (name, node_cfg) in nodes {
+        if let Some(level) = &node_cfg.log_level {
+            logging::parse_level(level)
+                .map_err(|e| anyhow!("Invalid `logLevel` of node `{}`: {}", name, e))?;
+            logging::enable_node_levels();
+        }
+    }
+    Ok(())
+}
+
+/// Checks that no node has a `password` in its `authMethods`, which can't be used.
+fn check_auth_methods(nodes: &[(String, NodeCfg)]) -> Result<()> {
+    for (name, node_cfg) in nodes {
+        if ssh::auth_methods(node_cfg)
+            .iter()
+            .any(|method| matches!(method, ssh::SshAuthMethod::Password(_)))
+        {
+            return Err(anyhow!(
+                "Node `{}` has a `password` in its `authMethods`, which isn't supported, since henix runs `ssh` in batch mode; use `identityFile` or `agent` instead",
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `label` can be used as a file name on the nodes, for `--label`.
+fn check_label(label: &str) -> Result<()> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-';
+    if label.is_empty() || label.starts_with('.') || !label.chars().all(allowed) {
+        return Err(anyhow!(
+            "`{}` is not a valid label, only letters, digits, `.`, `_` and `-` are allowed, and it can't start with `.`",
+            label
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that no two nodes are the same machine, since their deployments would run at the same
+/// time and fight over the same `/etc/henix/{hash}`.
+fn check_distinct_locations(nodes: &[(String, NodeCfg)]) -> Result<()> {
+    let mut seen = BTreeMap::new();
+    for (name, node_cfg) in nodes {
+        let target = if node_cfg.is_local() {
+            node_cfg.location.clone()
+        } else {
+            // Not the user, since `/etc/henix` is the same for all of them.
+            let target = ssh::RemoteTarget::of(node_cfg)
+                .context(format!("Invalid location of node `{}`", name))?;
+            format!("{}:{}", target.host, target.port.unwrap_or(22))
+        };
+        if let Some(other) = seen.insert(target, name) {
+            return Err(anyhow!(
+                "Nodes `{}` and `{}` are both at location `{}`, deploying to both at once would clobber the configuration",
+                other,
+                name,
+                node_cfg.location
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Connects to every node at the same time, and prints whether it could be reached. Returns the
+/// names of the nodes that couldn't be.
+async fn ping_nodes(nodes: &[(String, NodeCfg)], host_key_opts: &HostKeyOpts) -> BTreeSet<String> {
+    let results = futures::future::join_all(
+        nodes
+            .iter()
+            .map(|(name, node_cfg)| remote::ping(name, node_cfg, host_key_opts)),
+    )
+    .await;
+    let name_width = nodes.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let location_width = nodes
+        .iter()
+        .map(|(_, node_cfg)| node_cfg.location.len())
+        .max()
+        .unwrap_or(0);
+    let mut unreachable = BTreeSet::new();
+    for ((name, node_cfg), result) in nodes.iter().zip(results) {
+        let status = match result {
+            Ok(took) => format!("reachable ({}ms)", took.as_millis()),
+            Err(e) => {
+                unreachable.insert(name.clone());
+                format!("unreachable: {:#}", e)
+            }
+        };
+        println!(
+            "{:name_width$}  {:location_width$}  {}",
+            name,
+            node_cfg.location,
+            status,
+            name_width = name_width,
+            location_width = location_width
+        );
+    }
+    unreachable
+}
+
+/// Checks the configuration directory of every node with `deploy::check_cfg_dir`.
+fn check_cfg_dirs(cfg_dir: &Path, nodes: &[(String, NodeCfg)]) -> Result<()> {
+    let dirs = nodes
+        .iter()
+        .map(|(_, node_cfg)| node_cfg.cfg_dir(cfg_dir))
+        .collect::<BTreeSet<_>>();
+    for dir in dirs {
+        deploy::check_cfg_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Gets the hash to use, either the one given by the user or the hash of `cfg_dir`.
+/// Flake input overrides are part of the hash, since they change what is built.
+async fn get_hash(
+    cfg_dir: &Path,
+    hash: Option<String>,
+    cfg_source: &CfgSourceOpts,
+) -> Result<String> {
+    match hash {
+        Some(hash) => {
+            // This ends up in a remote path, so don't allow anything funny.
+            if hash.is_empty() || !hash.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(anyhow!(
+                    "`{}` (specified using --hash) is not a valid hash",
+                    hash
+                ));
+            }
+            Ok(hash)
+        }
+        None => {
+            let mut hash = deploy::cfg_hash(cfg_dir)
+                .await
+                .context("Could not get hash")?;
+            if !cfg_source.override_input.is_empty() {
+                // Hash what the overrides lock to, since e.g. a branch moves.
+                let nix_opts =
+                    nix::NixOpts::in_dir(cfg_dir).override_input(&cfg_source.override_input);
+                let metadata = nix::flake_metadata(&nix_opts, ".")
+                    .await
+                    .context("Could not lock the overridden inputs")?;
+                let mut overridden = hash.clone();
+                for input in cfg_source.override_input.chunks(2).map(|pair| &pair[0]) {
+                    let nar_hash = metadata.input_nar_hash(input).ok_or_else(|| {
+                        anyhow!("Could not find the locked input `{}` of the flake", input)
+                    })?;
+                    overridden.push_str(&format!(" {}={}", input, nar_hash));
+                }
+                hash = nix::hash_string(&nix::NixOpts::default(), &overridden)
+                    .await
+                    .context("Could not get hash")?;
+            }
+            info!("Configuration hash is {}", hash);
+            Ok(hash)
+        }
+    }
+}
+
+/// Gets the hash of every configuration directory the `nodes` are deployed from (see
+/// `NodeCfg::cfg_dir`), by directory. A hash given by the user only works for a single one.
+async fn get_hashes(
+    cfg_dir: &Path,
+    nodes: &[(String, NodeCfg)],
+    hash: Option<String>,
+    cfg_source: &CfgSourceOpts,
+) -> Result<BTreeMap<PathBuf, String>> {
+    let mut dirs = nodes
+        .iter()
+        .map(|(_, node_cfg)| node_cfg.cfg_dir(cfg_dir).to_owned())
+        .collect::<BTreeSet<_>>();
+    if dirs.is_empty() {
+        dirs.insert(cfg_dir.to_owned());
+    }
+    if hash.is_some() && dirs.len() > 1 {
+        return Err(anyhow!(
+            "--hash can't be used for nodes from several flakes, since each has its own hash"
+        ));
+    }
+    let mut hashes = BTreeMap::new();
+    for dir in dirs {
+        let hash = get_hash(&dir, hash.clone(), cfg_source).await?;
+        hashes.insert(dir, hash);
+    }
+    Ok(hashes)
+}
+
+/// Evaluates the systems of the `nodes` and works out what deploying them does, for
+/// `henix plan`.
+async fn make_plan(
+    cfg_dir: &Path,
+    dep_opts: &DeployOpts,
+    policy: &PolicyCfg,
+    nodes: &[(String, NodeCfg)],
+    cfg_source: &CfgSourceOpts,
+) -> Result<plan::Plan> {
+    let hashes = get_hashes(cfg_dir, nodes, None, cfg_source).await?;
+    let to_eval = nodes
+        .iter()
+        .map(|(name, node_cfg)| (name.as_str(), node_cfg.cfg_dir(cfg_dir)))
+        .collect::<Vec<_>>();
+    info!("Evaluating {} systems", to_eval.len());
+    let evaluated =
+        nix::eval_toplevels(&to_eval, &cfg_source.override_input, dep_opts.max_eval_jobs).await;
+    let mut toplevels = BTreeMap::new();
+    for (name, toplevel) in evaluated {
+        let toplevel = toplevel.map_err(|e| {
+            let stderr_tail = error::stderr_tail(e.stderr().unwrap_or("").as_bytes());
+            anyhow::Error::new(e).context(HenixError::Eval {
+                attr: nix::toplevel_attr(&name),
+                stderr_tail,
+            })
+        });
+        let toplevel = toplevel.context(format!("Could not evaluate the system of `{}`", name))?;
+        toplevels.insert(name, toplevel);
+    }
+    let mut planned = BTreeMap::new();
+    for (name, node_cfg) in nodes {
+        let hash = hashes[node_cfg.cfg_dir(cfg_dir)].clone();
+        let toplevel = toplevels.remove(name).unwrap();
+        let commands = deploy::planned_commands(
+            &dep_opts.rebuild,
+            name,
+            node_cfg,
+            &hash,
+            &toplevel,
+            &cfg_source.override_input,
+        )?;
+        planned.insert(
+            name.clone(),
+            plan::PlannedNode {
+                location: node_cfg.location.clone(),
+                hash,
+                toplevel,
+                commands,
+            },
+        );
+    }
+    let mut planned_nodes = BTreeMap::new();
+    for (name, node_cfg) in nodes {
+        let mut node = serde_json::to_value(node_cfg)?;
+        // Nodes with passwords are rejected, but make sure that none end up in the plan.
+        if let Some(methods) = &node_cfg.auth_methods {
+            node["authMethods"] = methods.iter().map(ssh::SshAuthMethod::redacted).collect();
+        }
+        planned_nodes.insert(name, node);
+    }
+    let deploy = serde_json::json!({
+        "nodes": planned_nodes,
+        "policy": policy,
+    });
+    Ok(plan::Plan::new(cfg_dir, hashes, deploy, planned))
+}
+
+/// Updates the flake inputs in `cfg_dir` and prints what changed. Returns the options to deploy
+/// with if the update should be deployed.
+async fn update(cfg_dir: &Path, update_opts: UpdateOpts) -> Result<Option<DeployOpts>> {
+    let changes = update::update_inputs(cfg_dir, &update_opts.inputs).await?;
+    if changes.is_empty() {
+        println!("No inputs changed.");
+        return Ok(None);
+    }
+    for change in &changes {
+        println!("{}", change);
+    }
+    if !update_opts.and_deploy {
+        return Ok(None);
+    }
+    if !update_opts.yes && !util::confirm("Deploy the updated configuration?")? {
+        info!("Not deploying; `flake.lock` keeps the updated inputs");
+        return Ok(None);
+    }
+    Ok(Some(update_opts.deploy))
+}
+
+async fn run(mut opts: Opts) -> Result<()> {
+    let run_started = std::time::Instant::now();
+    if let Some(known_hosts) = &opts.known_hosts {
+        ssh::set_known_hosts(known_hosts)?;
+    }
+    if opts.max_ssh_connections == 0 {
+        return Err(anyhow!("--max-ssh-connections must be at least 1"));
+    }
+    ssh::set_max_connections(opts.max_ssh_connections);
+    if let Some(max_eval_parallel) = opts.max_eval_parallel {
+        if max_eval_parallel == 0 {
+            return Err(anyhow!("--max-eval-parallel must be at least 1"));
+        }
+        nix::set_max_parallel(max_eval_parallel);
+    }
+    let mut cfg_dir = opts
+        .cfg_dir
+        .unwrap_or_else(|| std::env::current_dir().unwrap());
+
+    // `henix update --and-deploy`, `henix plan` and `henix apply` continue as `henix deploy`.
+    let mut plan_mode = plan::PlanMode::None;
+    let cmd = match opts.cmd {
+        OptCmd::Update(update_opts) => {
+            if update_opts.and_deploy && !update_opts.yes && opts.cfg_source.cfg_from_stdin() {
+                return Err(anyhow!(
+                    "Confirming the deployment reads the answer from stdin, so the config can't be read from it too; pass --yes"
+                ));
+            }
+            match update(&cfg_dir, update_opts).await? {
+                Some(dep_opts) => OptCmd::Deploy(dep_opts),
+                None => return Ok(()),
+            }
+        }
+        OptCmd::Plan(plan_opts) => {
+            if opts.cfg_source.sources.is_some() {
+                return Err(anyhow!("--sources can't be used with `henix plan`"));
+            }
+            plan_mode = plan::PlanMode::Write(plan_opts.out);
+            OptCmd::Deploy(plan_opts.deploy)
+        }
+        OptCmd::Apply(apply_opts) => {
+            let plan = plan::Plan::read(&apply_opts.plan)?;
+            // The deployment is done with the options it was planned with.
+            let planned = Opts::from_iter_safe(&plan.args).context(format!(
+                "Could not parse the command line of the plan `{}`",
+                apply_opts.plan.display()
+            ))?;
+            let mut dep_opts = match planned.cmd {
+                OptCmd::Plan(plan_opts) => plan_opts.deploy,
+                _ => {
+                    return Err(anyhow!(
+                        "`{}` was not written by `henix plan`",
+                        apply_opts.plan.display()
+                    ))
+                }
+            };
+            // The plan has only the planned nodes.
+            dep_opts.all_targets = true;
+            dep_opts.targets = None;
+            dep_opts.retry_failed = false;
+            opts.cfg_source.override_input = planned.cfg_source.override_input;
+            cfg_dir = plan.cfg_dir.clone();
+            plan_mode = plan::PlanMode::Apply {
+                plan: Box::new(plan),
+                force: apply_opts.force,
+            };
+            OptCmd::Deploy(dep_opts)
+        }
+        cmd => cmd,
+    };
+
+    match cmd {
+        OptCmd::Deploy(mut dep_opts) => {
+            let builds_separately =
+                dep_opts.rebuild.switch_action.is_some() || dep_opts.rebuild.staged;
+            if builds_separately && dep_opts.from_phase > deploy::DeployPhase::Build {
+                return Err(anyhow!(
+                    "--switch-action and --staged can't be used with --from-phase {}, since they activate the system they build",
+                    dep_opts.from_phase.name()
+                ));
+            }
+            if dep_opts.confirm_timeout.is_some() {
+                if dep_opts.rebuild.switch_action.is_none() && !dep_opts.rebuild.staged {
+                    dep_opts.rebuild.switch_action = Some(deploy::SwitchAction::Switch);
+                }
+                if !dep_opts.rebuild.activates_now() {
+                    return Err(anyhow!(
+                        "--confirm-timeout needs the new system to be activated right away"
+                    ));
+                }
+                if dep_opts.from_phase > deploy::DeployPhase::Build {
+                    return Err(anyhow!(
+                        "--confirm-timeout can't be used with --from-phase {}, since the new system isn't activated then",
+                        dep_opts.from_phase.name()
+                    ));
+                }
+            }
+            if let Some(rate_limit) = dep_opts.rate_limit {
+                if !(rate_limit.is_finite() && rate_limit > 0.0) {
+                    return Err(anyhow!(
+                        "--rate-limit must be a positive number, got `{}`",
+                        rate_limit
+                    ));
+                }
+            }
+            if dep_opts.max_parallel == Some(0) {
+                return Err(anyhow!("--max-parallel must be at least 1"));
+            }
+            if dep_opts.max_eval_jobs == 0 {
+                return Err(anyhow!("--max-eval-jobs must be at least 1"));
+            }
+            if let Some(label) = &dep_opts.label {
+                check_label(label)?;
+            }
+            dep_opts.host_keys.warn_if_implicit();
+            if dep_opts.confirm_per_node && opts.cfg_source.cfg_from_stdin() {
+                return Err(anyhow!(
+                    "--confirm-per-node reads the answers from stdin, so the config can't be read from it too"
+                ));
+            }
+            let untracked = check_untracked(&cfg_dir, dep_opts.add_untracked).await?;
+            let deploy_cfg = match &plan_mode {
+                plan::PlanMode::Apply { plan, .. } => serde_json::from_value(plan.deploy.clone())
+                    .context("The plan has an invalid deploy configuration"),
+                _ => get_deploy_cfg(&cfg_dir, &opts.cfg_source).await,
+            };
+            let deploy_cfg = match deploy_cfg {
+                Ok(deploy_cfg) => deploy_cfg,
+                Err(e) => {
+                    warn_untracked(&e, &untracked);
+                    return Err(e);
+                }
+            };
+            if deploy_cfg.policy.require_change_ref && dep_opts.change_ref.is_none() {
+                return Err(anyhow!(
+                    "The deploy policy requires a change reference, specify one using --change-ref"
+                ));
+            }
+            let targets = if dep_opts.all_targets {
+                None
+            } else if dep_opts.retry_failed {
+                Some(failed_nodes(&cfg_dir, &deploy_cfg.nodes).await?)
+            } else {
+                dep_opts.targets.clone().or_else(env_targets)
+            };
+            let nodes = select_nodes(deploy_cfg.nodes, targets.as_ref())?;
+            check_distinct_locations(&nodes)?;
+            apply_log_levels(&nodes)?;
+            check_auth_methods(&nodes)?;
+            for (name, node_cfg) in &nodes {
+                dep_opts
+                    .rebuild
+                    .specialisation(node_cfg)
+                    .context(format!("Can't deploy to `{}`", name))?;
+            }
+            for canary in &dep_opts.canary {
+                if !nodes.iter().any(|(name, _)| name == canary) {
+                    return Err(anyhow!(
+                        "Canary `{}` is not one of the nodes being deployed to",
+                        canary
+                    ));
+                }
+            }
+            if dep_opts.dump_config {
+                return dump_config(&dep_opts, &deploy_cfg.policy, &nodes);
+            }
+            if !dep_opts.copy.allow_empty {
+                check_cfg_dirs(&cfg_dir, &nodes)?;
+            }
+            if dep_opts.check_flake_inputs {
+                let dirs = nodes
+                    .iter()
+                    .map(|(_, node_cfg)| node_cfg.cfg_dir(&cfg_dir))
+                    .collect::<BTreeSet<_>>();
+                for dir in dirs {
+                    inputs::check(dir, &opts.cfg_source.override_input).await?;
+                }
+            }
+            if let plan::PlanMode::Write(out) = &plan_mode {
+                let plan = make_plan(
+                    &cfg_dir,
+                    &dep_opts,
+                    &deploy_cfg.policy,
+                    &nodes,
+                    &opts.cfg_source,
+                )
+                .await?;
+                plan.write(out)?;
+                for (name, node) in &plan.nodes {
+                    println!("{} ({}): {}", name, node.location, node.toplevel);
+                    println!("  configuration hash {}", node.hash);
+                    for command in &node.commands {
+                        println!("  $ {}", command);
+                    }
+                }
+                println!(
+                    "Wrote the plan for {} nodes to {}; deploy it with `henix apply {}`",
+                    plan.nodes.len(),
+                    out.display(),
+                    out.display()
+                );
+                return Ok(());
+            }
+            let mut unreachable = BTreeSet::new();
+            if let Some(preflight) = dep_opts.preflight {
+                info!("Checking that the {} nodes can be reached", nodes.len());
+                unreachable = ping_nodes(&nodes, &dep_opts.host_keys).await;
+                if !unreachable.is_empty() {
+                    let list = unreachable.iter().cloned().collect::<Vec<_>>().join(", ");
+                    if preflight == Some(Preflight::Strict) {
+                        return Err(anyhow!(
+                            "{} nodes can't be reached: {}",
+                            unreachable.len(),
+                            list
+                        ));
+                    }
+                    if let Some(canary) = dep_opts.canary.iter().find(|c| unreachable.contains(*c))
+                    {
+                        return Err(anyhow!("Canary `{}` can't be reached", canary));
+                    }
+                    warn!(
+                        "Leaving out {} nodes that can't be reached: {}",
+                        unreachable.len(),
+                        list
+                    );
+                }
+            }
+            let hashes = get_hashes(&cfg_dir, &nodes, None, &opts.cfg_source).await?;
+            // The store paths of the systems evaluated with `--eval-locally` (or planned), by
+            // node. If the configuration changed since it was planned, they are evaluated again.
+            let mut toplevels = BTreeMap::new();
+            if let plan::PlanMode::Apply { plan, force } = &plan_mode {
+                if plan.check_hashes(&hashes, *force)? {
+                    toplevels = plan.toplevels();
+                }
+            }
+            // Recorded on the nodes; configurations outside Git repositories have none.
+            let mut git_revs = BTreeMap::new();
+            for dir in hashes.keys() {
+                if let Ok(rev) = git::head_rev(dir).await {
+                    git_revs.insert(dir, rev);
+                }
+            }
+            let mut nodes = nodes;
+            let mut deploy_result = deploy::DeployResult::default();
+            for (name, node_cfg) in nodes.iter().filter(|(name, _)| unreachable.contains(name)) {
+                deploy_result.nodes.push(deploy::NodeOutcome {
+                    name: name.clone(),
+                    cfg_hash: hashes[node_cfg.cfg_dir(&cfg_dir)].clone(),
+                    duration: std::time::Duration::default(),
+                    phases_completed: Vec::new(),
+                    phase_timings: Vec::new(),
+                    closure: None,
+                    error: None,
+                    skipped: true,
+                    aborted: false,
+                });
+            }
+            nodes.retain(|(name, _)| !unreachable.contains(name));
+            if dep_opts.eval_locally && toplevels.is_empty() {
+                let to_eval = nodes
+                    .iter()
+                    .map(|(name, node_cfg)| (name.as_str(), node_cfg.cfg_dir(&cfg_dir)))
+                    .collect::<Vec<_>>();
+                info!(
+                    "Evaluating {} systems locally, {} at a time",
+                    to_eval.len(),
+                    dep_opts.max_eval_jobs
+                );
+                let evaluated = nix::eval_toplevels(
+                    &to_eval,
+                    &opts.cfg_source.override_input,
+                    dep_opts.max_eval_jobs,
+                )
+                .await;
+                let mut warned_untracked = false;
+                for (name, toplevel) in evaluated {
+                    let e = match toplevel {
+                        Ok(toplevel) => {
+                            toplevels.insert(name, toplevel);
+                            continue;
+                        }
+                        Err(e) => {
+                            let stderr_tail =
+                                error::stderr_tail(e.stderr().unwrap_or("").as_bytes());
+                            anyhow::Error::new(e).context(HenixError::Eval {
+                                attr: nix::toplevel_attr(&name),
+                                stderr_tail,
+                            })
+                        }
+                    };
+                    if !warned_untracked {
+                        warn_untracked(&e, &untracked);
+                        warned_untracked = true;
+                    }
+                    if dep_opts.canary.contains(&name) {
+                        return Err(e.context(format!(
+                            "Could not evaluate the system of canary `{}`",
+                            name
+                        )));
+                    }
+                    error!("Could not evaluate the system of `{}`: {:?}", name, e);
+                    let node_cfg = &nodes.iter().find(|(n, _)| *n == name).unwrap().1;
+                    deploy_result.nodes.push(deploy::NodeOutcome {
+                        cfg_hash: hashes[node_cfg.cfg_dir(&cfg_dir)].clone(),
+                        name,
+                        duration: std::time::Duration::default(),
+                        phases_completed: Vec::new(),
+                        phase_timings: Vec::new(),
+                        closure: None,
+                        error: Some(e),
+                        skipped: false,
+                        aborted: false,
+                    });
+                }
+                nodes.retain(|(name, _)| toplevels.contains_key(name));
+            }
+            let history_path = history::path(&cfg_dir, opts.history_file.as_deref());
+            let dep_opts = Arc::new(dep_opts);
+            let events = dep_opts
+                .events
+                .as_deref()
+                .map(|path| events::EventStream::open(path, dep_opts.events_include_output))
+                .transpose()?
+                .map(Arc::new);
+            if let Some(events) = &events {
+                let names = deploy_result.nodes.iter().map(|node| &node.name);
+                events.emit(
+                    None,
+                    events::EventKind::RunStarted {
+                        nodes: names
+                            .chain(nodes.iter().map(|(name, _)| name))
+                            .cloned()
+                            .collect(),
+                    },
+                );
+                // The nodes that were already left out.
+                for node in &deploy_result.nodes {
+                    events.emit(Some(&node.name), node.event());
+                }
+            }
+            let log_dir = dep_opts.log_dir.as_ref().map(|log_dir| {
+                log_dir
+                    .clone()
+                    .unwrap_or_else(|| cfg_dir.join(output::DEFAULT_LOG_DIR))
+            });
+            if let Some(log_dir) = &log_dir {
+                std::fs::create_dir_all(log_dir).context(format!(
+                    "Could not create log directory `{}`",
+                    log_dir.display()
+                ))?;
+            }
+            let started = chrono::Local::now();
+            let log_file = |name: &str| {
+                log_dir
+                    .as_ref()
+                    .map(|log_dir| output::node_log_path(log_dir, name, started))
+            };
+            let log_file = &log_file;
+            let rate_limiter = dep_opts.rate_limit.map(util::RateLimiter::new);
+            let rate_limiter = rate_limiter.as_ref();
+            let confirmation = if dep_opts.confirm_per_node {
+                Some(deploy::Confirmation::default())
+            } else {
+                None
+            };
+            let confirmation = confirmation.as_ref();
+            // Also used to stop the deployment on SIGTERM.
+            let abort = &deploy::Abort::default();
+            // Only one node can ask for confirmation at a time.
+            let max_parallel = if dep_opts.confirm_per_node {
+                1
+            } else {
+                dep_opts.max_parallel.unwrap_or_else(|| nodes.len().max(1))
+            };
+            let names = nodes
+                .iter()
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+            let (canary_nodes, nodes) = nodes
+                .into_iter()
+                .partition::<Vec<_>, _>(|(name, _)| dep_opts.canary.contains(name));
+            // The local node is deployed to last, so that switching it doesn't disrupt the
+            // deployment of the others.
+            let (local_nodes, nodes) = nodes.into_iter().partition::<Vec<_>, _>(|(_, node_cfg)| {
+                node_cfg.is_local() && !dep_opts.local_in_parallel
+            });
+            // With `--activate-all-at-once`, the nodes of each group switch together.
+            let max_ssh_connections = opts.max_ssh_connections;
+            let barrier = |group: &[(String, NodeCfg)]| {
+                if !dep_opts.activate_all_at_once {
+                    return None;
+                }
+                let remote_nodes = group
+                    .iter()
+                    .filter(|(_, node_cfg)| !node_cfg.is_local())
+                    .count();
+                if remote_nodes > max_ssh_connections {
+                    // The nodes waiting for the others would keep them from connecting.
+                    return Some(Err(anyhow!(
+                        "--activate-all-at-once needs an SSH session to every node at once, pass a --max-ssh-connections of at least {}",
+                        remote_nodes
+                    )));
+                }
+                Some(Ok(Arc::new(tokio::sync::Barrier::new(group.len()))))
+            };
+            let nodes_barrier = barrier(&nodes).transpose()?;
+            let local_barrier = barrier(&local_nodes).transpose()?;
+            // Run all node deployments, at most `max_parallel` at a time.
+            let (hashes, git_revs) = (&hashes, &git_revs);
+            let override_input = &opts.cfg_source.override_input;
+            let cfg_dir = &cfg_dir;
+            let deploy = |(name, node_cfg): (String, NodeCfg),
+                          barrier: Option<Arc<tokio::sync::Barrier>>| {
+                let (dir, hash) = hashes.get_key_value(node_cfg.cfg_dir(cfg_dir)).unwrap();
+                let cfg = deploy::LocalCfg {
+                    dir,
+                    hash,
+                    toplevel: toplevels.get(&name).map(String::as_str),
+                    git_rev: git_revs.get(dir).map(String::as_str),
+                    override_input,
+                    state_dir: cfg_dir,
+                    confirmation,
+                    abort: Some(abort),
+                };
+                let dep_opts = dep_opts.clone();
+                let events = events.clone();
+                async move {
+                    if let Some(rate_limiter) = rate_limiter {
+                        rate_limiter.acquire().await;
+                    }
+                    deploy::deploy_node(
+                        &dep_opts,
+                        &name,
+                        &node_cfg,
+                        cfg,
+                        events,
+                        log_file(&name).as_deref(),
+                        barrier.as_deref(),
+                    )
+                    .await
+                }
+            };
+            let mut deployments = futures::stream::iter(canary_nodes)
+                .map(|node| deploy(node, None))
+                .buffer_unordered(max_parallel)
+                .chain(
+                    futures::stream::iter(nodes)
+                        .map(|node| deploy(node, nodes_barrier.clone()))
+                        .buffer_unordered(max_parallel),
+                )
+                .chain(
+                    futures::stream::iter(local_nodes)
+                        .map(|node| deploy(node, local_barrier.clone()))
+                        .buffer_unordered(max_parallel),
+                );
+            let mut failures = deploy_result.failed().count();
+            let mut abort_reason = None;
+            let mut sigterm =
+                signal(SignalKind::terminate()).context("Could not install the SIGTERM handler")?;
+            let mut terminated = false;
+            loop {
+                let result = tokio::select! {
+                    result = deployments.next() => match result {
+                        Some(result) => result,
+                        None => break,
+                    },
+                    _ = sigterm.recv(), if !terminated => {
+                        warn!(
+                            "Received SIGTERM, letting the nodes finish their current phase for up to {}s",
+                            deploy::TERMINATE_GRACE_PERIOD.as_secs()
+                        );
+                        terminated = true;
+                        abort.terminate();
+                        continue;
+                    }
+                };
+                if let Some(events) = &events {
+                    events.emit(Some(&result.name), result.event());
+                }
+                if result.error.is_some() {
+                    failures += 1;
+                    if dep_opts.canary.contains(&result.name) {
+                        abort_reason = Some(format!("canary `{}` failed", result.name));
+                    } else if dep_opts.fail_fast && abort_reason.is_none() {
+                        abort_reason = Some(format!("`{}` failed (--fail-fast)", result.name));
+                    }
+                }
+                if dep_opts.max_failures.is_some_and(|max| failures > max) {
+                    abort_reason = Some(format!(
+                        "{} nodes failed (more than --max-failures)",
+                        failures
+                    ));
+                }
+                // Canaries are only skipped when they weren't confirmed.
+                if result.skipped && dep_opts.canary.contains(&result.name) {
+                    abort_reason = Some(format!("canary `{}` was not confirmed", result.name));
+                }
+                if let Some(confirmation) = confirmation {
+                    if confirmation.quit() {
+                        abort_reason = Some("it was quit when asked for confirmation".to_owned());
+                    }
+                }
+                deploy_result.nodes.push(result);
+                // Once terminated, the nodes that are still running are already stopping.
+                if abort_reason.is_some() && !terminated {
+                    if dep_opts.fail_fast {
+                        // The nodes that are still running cancel themselves unless they are
+                        // activating, and the rest as soon as they start.
+                        abort.abort();
+                    } else {
+                        // Dropping the stream cancels the deployments that are still running,
+                        // and doesn't start the rest.
+                        break;
+                    }
+                }
+            }
+            drop(deployments);
+            if terminated {
+                abort_reason = Some("henix received SIGTERM".to_owned());
+            }
+            deploy_result.log_summary();
+            let skipped = deploy_result
+                .skipped()
+                .map(|node| node.name.as_str())
+                .collect::<Vec<_>>();
+            if !skipped.is_empty() {
+                warn!("Skipped {} nodes: {}", skipped.len(), skipped.join(", "));
+            }
+            let aborted = deploy_result
+                .aborted()
+                .map(|node| node.name.as_str())
+                .collect::<Vec<_>>();
+            if !aborted.is_empty() {
+                warn!("Aborted {} nodes: {}", aborted.len(), aborted.join(", "));
+            }
+            let duration = run_started.elapsed();
+            info!("Deployment finished in {}", util::format_duration(duration));
+            let mut results = deploy_result
+                .nodes
+                .iter()
+                .map(|node| (node.name.clone(), node.history_result()))
+                .collect::<BTreeMap<_, _>>();
+            for name in names {
+                results.entry(name).or_insert(history::NodeResult::Aborted);
+            }
+            if let Some(log_dir) = &log_dir {
+                info!(
+                    "The output of every node was saved in {}",
+                    log_dir.display()
+                );
+                for node in deploy_result.failed() {
+                    if let Some(path) = log_file(&node.name) {
+                        error!(
+                            "`{}` failed, see {} for its output",
+                            node.name,
+                            path.display()
+                        );
+                    }
+                }
+            }
+            if !dep_opts.no_state {
+                if let Err(e) = state::record_run(cfg_dir, results.clone()).await {
+                    warn!(
+                        "Could not save the results to the local state, --retry-failed will not work: {:?}",
+                        e
+                    );
+                }
+            }
+            let record = history::DeployRecord {
+                timestamp: chrono::Utc::now(),
+                user: history::current_user(),
+                // With `--sources`, every flake has its own hash.
+                hash: hashes.values().cloned().collect::<Vec<_>>().join(","),
+                change_ref: dep_opts.change_ref.clone(),
+                change_notes: dep_opts.change_notes.clone(),
+                duration_secs: Some(duration.as_secs()),
+                terminated,
+                nodes: results,
+                errors: deploy_result
+                    .failed()
+                    .filter_map(|node| Some((node.name.clone(), node.henix_error()?.clone())))
+                    .collect(),
+            };
+            history::append(&history_path, &record)
+                .context("Could not record deployment in history")?;
+            if dep_opts.commit_on_success && !record.failed() {
+                let nodes = record.nodes.keys().map(String::as_str).collect::<Vec<_>>();
+                for (dir, hash) in hashes {
+                    // The deployment itself succeeded, so don't fail it because of this.
+                    if let Err(e) =
+                        git::commit_flake_lock(dir, hash, record.timestamp, &nodes).await
+                    {
+                        warn!(
+                            "Could not commit flake.lock in `{}`: {:?}",
+                            dir.display(),
+                            e
+                        );
+                    }
+                }
+            }
+            let count = |result| {
+                record
+                    .nodes
+                    .values()
+                    .filter(|node_result| **node_result == result)
+                    .count()
+            };
+            if let Some(events) = &events {
+                let result = if abort_reason.is_some() {
+                    events::RunResult::Aborted
+                } else if record.failed() {
+                    events::RunResult::Failed
+                } else {
+                    events::RunResult::Succeeded
+                };
+                events.emit(
+                    None,
+                    events::EventKind::RunFinished {
+                        result,
+                        succeeded: count(history::NodeResult::Succeeded),
+                        failed: count(history::NodeResult::Failed),
+                        skipped: count(history::NodeResult::Skipped),
+                        aborted: count(history::NodeResult::Aborted),
+                        duration_ms: events::millis(duration),
+                        reason: abort_reason.clone(),
+                    },
+                );
+            }
+            #[cfg(feature = "notify-desktop")]
+            if dep_opts.notify_desktop {
+                notify::deploy_finished(&deploy_result).await;
+            }
+            if let Some(reason) = abort_reason {
+                return Err(DeployAborted {
+                    reason,
+                    succeeded: deploy_result.succeeded().count(),
+                    failed: deploy_result.failed().count(),
+                    aborted: count(history::NodeResult::Aborted),
+                    terminated,
+                }
+                .into());
+            }
+            let failed = deploy_result
+                .nodes
+                .into_iter()
+                .filter_map(|node| Some((node.name, node.error?)))
+                .collect::<Vec<_>>();
+            if !failed.is_empty() {
+                return Err(DeployFailures { failed }.into());
+            }
+            Ok(())
+        }
+        OptCmd::Benchmark(bench_opts) => {
+            if bench_opts.iterations == 0 {
+                return Err(anyhow!("--iterations must be at least 1"));
+            }
+            let mut dep_opts = bench_opts.deploy;
+            if dep_opts.rebuild.switch_action.is_none() && !dep_opts.rebuild.staged {
+                dep_opts.rebuild.switch_action = Some(deploy::SwitchAction::DryActivate);
+            }
+            if dep_opts.max_parallel == Some(0) {
+                return Err(anyhow!("--max-parallel must be at least 1"));
+            }
+            dep_opts.host_keys.warn_if_implicit();
+            let targets = if dep_opts.all_targets {
+                None
+            } else {
+                dep_opts.targets.clone().or_else(env_targets)
+            };
+            let nodes = get_nodes(&cfg_dir, &opts.cfg_source, targets.as_ref()).await?;
+            check_distinct_locations(&nodes)?;
+            if !dep_opts.copy.allow_empty {
+                check_cfg_dirs(&cfg_dir, &nodes)?;
+            }
+            let hashes = get_hashes(&cfg_dir, &nodes, None, &opts.cfg_source).await?;
+            let (dep_opts, cfg_dir) = (&dep_opts, &cfg_dir);
+            let override_input = &opts.cfg_source.override_input;
+            let mut benchmark = bench::Benchmark::default();
+            let mut failures = 0;
+            for iteration in 1..=bench_opts.iterations {
+                info!("Iteration {}/{}", iteration, bench_opts.iterations);
+                let mut deployments = futures::stream::iter(&nodes)
+                    .map(|(name, node_cfg)| {
+                        let (dir, hash) = hashes.get_key_value(node_cfg.cfg_dir(cfg_dir)).unwrap();
+                        let cfg = deploy::LocalCfg {
+                            dir,
+                            hash,
+                            toplevel: None,
+                            git_rev: None,
+                            override_input,
+                            state_dir: cfg_dir,
+                            confirmation: None,
+                            abort: None,
+                        };
+                        deploy::deploy_node(dep_opts, name, node_cfg, cfg, None, None, None)
+                    })
+                    .buffer_unordered(dep_opts.max_parallel.unwrap_or(1));
+                while let Some(outcome) = deployments.next().await {
+                    // Failures were already logged, and their timings would skew the results.
+                    if outcome.error.is_some() || outcome.skipped {
+                        failures += 1;
+                        continue;
+                    }
+                    benchmark.record(iteration, &outcome);
+                }
+            }
+            benchmark.print_table();
+            if let Some(path) = &bench_opts.csv {
+                benchmark.write_csv(path)?;
+                info!("Wrote the timings to {}", path.display());
+            }
+            if failures > 0 {
+                return Err(anyhow!(
+                    "{} of the {} deployments failed, and were left out of the results",
+                    failures,
+                    nodes.len() * bench_opts.iterations
+                ));
+            }
+            Ok(())
+        }
+        OptCmd::CopyConfig(copy_opts) => {
+            let nodes = get_nodes(&cfg_dir, &opts.cfg_source, copy_opts.targets.as_ref()).await?;
+            check_distinct_locations(&nodes)?;
+            if !copy_opts.copy.allow_empty {
+                check_cfg_dirs(&cfg_dir, &nodes)?;
+            }
+            let hashes = get_hashes(&cfg_dir, &nodes, copy_opts.hash, &opts.cfg_source).await?;
+            let copy = &copy_opts.copy;
+            let host_key_opts = &copy_opts.host_keys;
+            host_key_opts.warn_if_implicit();
+            let results = futures::future::join_all(nodes.iter().map(|(name, node_cfg)| {
+                let (dir, hash) = hashes.get_key_value(node_cfg.cfg_dir(&cfg_dir)).unwrap();
+                deploy::copy_node(name, node_cfg, dir, hash, copy, host_key_opts)
+            }))
+            .await;
+            check_node_results(&nodes, results)
+        }
+        OptCmd::BuildConfig(build_opts) => {
+            let nodes = get_nodes(&cfg_dir, &opts.cfg_source, build_opts.targets.as_ref()).await?;
+            check_distinct_locations(&nodes)?;
+            let hashes = get_hashes(&cfg_dir, &nodes, build_opts.hash, &opts.cfg_source).await?;
+            build_opts.host_keys.warn_if_implicit();
+            let rebuild_opts = &build_opts.rebuild;
+            let host_key_opts = &build_opts.host_keys;
+            let override_input = &opts.cfg_source.override_input;
+            let state_cfg_dir = if build_opts.no_state {
+                None
+            } else {
+                Some(cfg_dir.as_path())
+            };
+            let results = futures::future::join_all(nodes.iter().map(|(name, node_cfg)| {
+                deploy::build_node(
+                    rebuild_opts,
+                    host_key_opts,
+                    name,
+                    node_cfg,
+                    Some(&hashes[node_cfg.cfg_dir(&cfg_dir)]),
+                    override_input,
+                    state_cfg_dir,
+                )
+            }))
+            .await;
+            check_node_results(&nodes, results)
+        }
+        OptCmd::RemoteBuild(build_opts) => {
+            let nodes = get_nodes(&cfg_dir, &opts.cfg_source, build_opts.targets.as_ref()).await?;
+            check_distinct_locations(&nodes)?;
+            build_opts.host_keys.warn_if_implicit();
+            let rebuild_opts = &build_opts.rebuild;
+            let host_key_opts = &build_opts.host_keys;
+            let override_input = &opts.cfg_source.override_input;
+            let cfg_hash = build_opts.hash.as_deref();
+            let state_cfg_dir = if build_opts.no_state {
+                None
+            } else {
+                Some(cfg_dir.as_path())
+            };
+            let results = futures::future::join_all(nodes.iter().map(|(name, node_cfg)| {
+                deploy::build_node(
+                    rebuild_opts,
+                    host_key_opts,
+                    name,
+                    node_cfg,
+                    cfg_hash,
+                    override_input,
+                    state_cfg_dir,
+                )
+            }))
+            .await;
+            check_node_results(&nodes, results)
+        }
+        OptCmd::History(history_opts) => {
+            let history_path = history::path(&cfg_dir, opts.history_file.as_deref());
+            let records = history::read(&history_path)?;
+            let skip = records.len().saturating_sub(history_opts.count);
+            history::print(&records[skip..]);
+            Ok(())
+        }
+        OptCmd::Prune(prune_opts) => {
+            let history_path = history::path(&cfg_dir, opts.history_file.as_deref());
+            let summary = history::prune(
+                &history_path,
+                &history::PruneFilter {
+                    keep: prune_opts.keep,
+                    older_than: prune_opts
+                        .older_than
+                        .map(|days| chrono::Duration::days(days.into())),
+                    failed_only: prune_opts.failed_only,
+                },
+            )?;
+            println!(
+                "Removed {} deployments, {} remain; `{}` is now {} bytes",
+                summary.removed,
+                summary.remaining,
+                history_path.display(),
+                summary.size
+            );
+            Ok(())
+        }
+        OptCmd::Gc(gc_opts) => {
+            let log_dir = gc_opts
+                .log_dir
+                .unwrap_or_else(|| cfg_dir.join(output::DEFAULT_LOG_DIR));
+            let summary = gc::collect(
+                &log_dir,
+                &util::cache_dir()?,
+                &gc::GcFilter {
+                    keep: gc_opts.keep,
+                    older_than: gc_opts
+                        .older_than
+                        .map(|days| std::time::Duration::from_secs(u64::from(days) * 24 * 60 * 60)),
+                    dry_run: gc_opts.dry_run,
+                },
+            )?;
+            println!(
+                "{} {} files ({} bytes), {} remain",
+                if gc_opts.dry_run {
+                    "Would remove"
+                } else {
+                    "Removed"
+                },
+                summary.removed,
+                summary.freed,
+                summary.remaining
+            );
+            Ok(())
+        }
+        OptCmd::Info(info_opts) => {
+            let info = info::gather().await;
+            if info_opts.json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&info).context("Could not serialize info")?
+                );
+            } else {
+                info.print();
+            }
+            Ok(())
+        }
+        OptCmd::Verify(verify_opts) => {
+            verify_opts.host_keys.warn_if_implicit();
+            let nodes = get_nodes(&cfg_dir, &opts.cfg_source, verify_opts.targets.as_ref()).await?;
+            let host_key_opts = &verify_opts.host_keys;
+            let statuses = futures::future::join_all(
+                nodes
+                    .iter()
+                    .map(|(name, node_cfg)| verify::verify_node(name, node_cfg, host_key_opts)),
+            )
+            .await;
+            let state = if verify_opts.no_state {
+                state::State::default()
+            } else {
+                state::load().await.unwrap_or_else(|e| {
+                    warn!("Could not load the local state: {:?}", e);
+                    state::State::default()
+                })
+            };
+            let node_states = state.nodes(&cfg_dir);
+            let mut failures = 0;
+            for ((name, _), status) in nodes.iter().zip(statuses) {
+                println!("{}: {}", name, status);
+                if let verify::NodeStatus::Error(_) = status {
+                    // Still show what should be running there.
+                    if let Some(node_state) = node_states.and_then(|nodes| nodes.get(name)) {
+                        println!(
+                            "    last known deployment: {} ({}) at {}",
+                            node_state.hash, node_state.toplevel, node_state.deployed_at
+                        );
+                    }
+                }
+                if status.is_failure() {
+                    failures += 1;
+                }
+            }
+            if failures > 0 {
+                return Err(anyhow!(
+                    "{} node(s) drifted or could not be checked",
+                    failures
+                ));
+            }
+            Ok(())
+        }
+        OptCmd::Top(top_opts) => top::run(&top_opts.events).await,
+        OptCmd::Ping(ping_opts) => {
+            ping_opts.host_keys.warn_if_implicit();
+            let nodes = get_nodes(&cfg_dir, &opts.cfg_source, ping_opts.targets.as_ref()).await?;
+            let unreachable = ping_nodes(&nodes, &ping_opts.host_keys).await;
+            if !unreachable.is_empty() {
+                return Err(anyhow!("{} node(s) can't be reached", unreachable.len()));
+            }
+            Ok(())
+        }
+        OptCmd::NixosOption(option_opts) => {
+            option_opts.host_keys.warn_if_implicit();
+            let nodes = get_nodes(&cfg_dir, &opts.cfg_source, option_opts.targets.as_ref()).await?;
+            let (host_key_opts, option) = (&option_opts.host_keys, &option_opts.option);
+            let outputs = futures::future::join_all(nodes.iter().map(|(name, node_cfg)| {
+                nixos_option::query_node(name, node_cfg, host_key_opts, option, option_opts.json)
+            }))
+            .await;
+            let mut failures = 0;
+            let mut values = BTreeMap::new();
+            for ((name, _), output) in nodes.iter().zip(outputs) {
+                let output = match output {
+                    Ok(output) => output,
+                    Err(e) => {
+                        error!("Could not query `{}`: {:?}", name, e);
+                        failures += 1;
+                        continue;
+                    }
+                };
+                if option_opts.json {
+                    let value = serde_json::from_str::<serde_json::Value>(&output).context(
+                        format!("`nixos-option --json` on `{}` did not print JSON", name),
+                    )?;
+                    values.insert(name, value);
+                } else {
+                    for line in output.lines() {
+                        println!("{}: {}", name, line);
+                    }
+                }
+            }
+            if option_opts.json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&values)
+                        .context("Could not serialize the values")?
+                );
+            }
+            if failures > 0 {
+                return Err(anyhow!("{} node(s) could not be queried", failures));
+            }
+            Ok(())
+        }
+        OptCmd::Build(build_opts) => {
+            if build_opts.parallel == 0 {
+                return Err(anyhow!("--parallel must be at least 1"));
+            }
+            let nodes = get_nodes(&cfg_dir, &opts.cfg_source, build_opts.targets.as_ref()).await?;
+            let override_input = &opts.cfg_source.override_input;
+            let nodes = match &build_opts.since {
+                Some(rev) => {
+                    let changed = build::changed_nodes(&cfg_dir, &nodes, rev, override_input)
+                        .await
+                        .context(format!("Could not find the nodes changed since `{}`", rev))?;
+                    info!(
+                        "{} of {} nodes changed since `{}`",
+                        changed.len(),
+                        nodes.len(),
+                        rev
+                    );
+                    nodes
+                        .into_iter()
+                        .filter(|(name, _)| changed.contains(name))
+                        .collect()
+                }
+                None => nodes,
+            };
+            let cfg_dir = &cfg_dir;
+            let mut builds = futures::stream::iter(&nodes)
+                .map(|(name, node_cfg)| async move {
+                    let toplevel =
+                        build::build_node(name, node_cfg.cfg_dir(cfg_dir), override_input).await;
+                    (name, toplevel)
+                })
+                .buffer_unordered(build_opts.parallel);
+            let mut failures = 0;
+            let mut toplevels = BTreeMap::new();
+            while let Some((name, toplevel)) = builds.next().await {
+                match toplevel {
+                    Ok(toplevel) => {
+                        info!("Built `{}`: {}", name, toplevel);
+                        toplevels.insert(name, toplevel);
+                    }
+                    Err(e) => {
+                        error!("Could not build `{}`: {:?}", name, e);
+                        failures += 1;
+                    }
+                }
+            }
+            if build_opts.json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&toplevels)
+                        .context("Could not serialize the store paths")?
+                );
+            } else {
+                for (name, toplevel) in &toplevels {
+                    println!("{}: {}", name, toplevel);
+                }
+            }
+            if failures > 0 {
+                return Err(anyhow!("{} node(s) failed to build", failures));
+            }
+            Ok(())
+        }
+        OptCmd::Update(_) | OptCmd::Plan(_) | OptCmd::Apply(_) => {
+            unreachable!("`update`, `plan` and `apply` are handled before")
+        }
+        OptCmd::Schema => {
+            let schema = schemars::schema_for!(DeployCfg);
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema).context("Could not serialize the schema")?
+            );
+            Ok(())
+        }
+        OptCmd::Completion(completion_opts) => {
+            if completion_opts.cache_nodes {
+                let deploy_cfg = get_deploy_cfg(&cfg_dir, &opts.cfg_source).await?;
+                let path = completion::cache_nodes(&cfg_dir, deploy_cfg.nodes.keys())?;
+                info!(
+                    "Cached the names of {} nodes in `{}`",
+                    deploy_cfg.nodes.len(),
+                    path.display()
+                );
+                return Ok(());
+            }
+            // `--shell` is required without `--cache-nodes`.
+            let shell = completion_opts.shell.unwrap();
+            print!("{}", completion::script(Opts::clap(), shell)?);
+            // Not logged, since logs go to stdout too.
+            eprintln!("Install it with e.g. `{}`", completion::install_hint(shell));
+            Ok(())
+        }
+    }
+}
+
+/// Initializes logging. The log level is taken from, in order of precedence,
+/// `--log-level`/`-v`, `$RUST_LOG`, and otherwise defaults to `info`.
+fn init_logging(opts: &Opts) {
+    let cli_level = opts.log_level.as_deref().or(match opts.verbose {
+        0 => None,
+        1 => Some("debug"),
+        _ => Some("trace"),
+    });
+    let env_var_exists = std::env::var("RUST_LOG").is_ok_and(|x| !x.is_empty());
+    let filter = match cli_level {
+        Some(level) => EnvFilter::new(level),
+        None if env_var_exists => EnvFilter::from_default_env(),
+        None => EnvFilter::new("info"),
+    };
+    // The events are written to stdout then, and mustn't be mixed up with the logs.
+    let events_to_stdout = opts.events() == Some(Path::new("-"));
+    // `NodeLevelFilter` does all the filtering, so that its level hint is the one that is used.
+    tracing_subscriber::registry()
+        .with(logging::NodeLevelFilter {
+            inner: filter,
+            overrides: opts.node_log_level.iter().cloned().collect(),
+        })
+        .with(logging::NodeNameLayer)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(move || -> Box<dyn std::io::Write> {
+                    if events_to_stdout {
+                        Box::new(std::io::stderr())
+                    } else {
+                        Box::new(std::io::stdout())
+                    }
+                })
+                .event_format(logging::NodePrefix {
+                    inner: tracing_subscriber::fmt::format::Format::default(),
+                    template: opts.log_prefix.clone(),
+                }),
+        )
+        .init();
+    if cli_level.is_none() && env_var_exists {
+        info!("Picked up $RUST_LOG");
+    }
+}
+
+/// What henix exits with when a deployment was aborted before every node was deployed to, to
+/// tell it apart from nodes only failing.
+const ABORTED_EXIT_CODE: i32 = 2;
+
+/// Nodes failed in a deployment that went through to the end. Each failure was already logged.
+#[derive(thiserror::Error, Debug)]
+#[error("{} nodes failed: {}", .failed.len(), DeployFailures::names(.failed))]
+struct DeployFailures {
+    /// The nodes that failed, with why.
+    failed: Vec<(String, anyhow::Error)>,
+}
+
+/// Fails with `DeployFailures` if any of the `nodes` failed, given the `results` of running
+/// something on each of them in order, which already logged the failures.
+fn check_node_results(nodes: &[(String, NodeCfg)], results: Vec<Result<()>>) -> Result<()> {
+    let failed = nodes
+        .iter()
+        .zip(results)
+        .filter_map(|((name, _), res)| Some((name.clone(), res.err()?)))
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        return Err(DeployFailures { failed }.into());
+    }
+    Ok(())
+}
+
+impl DeployFailures {
+    fn names(failed: &[(String, anyhow::Error)]) -> String {
+        failed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// What henix exits with when a deployment was terminated by SIGTERM.
+const TERMINATED_EXIT_CODE: i32 = 130;
+
+/// The deployment was aborted, e.g. by `--max-failures` or `--fail-fast`, or terminated.
+#[derive(thiserror::Error, Debug)]
+#[error("Aborted the deployment because {reason}; {succeeded} nodes were deployed successfully, {failed} failed and {aborted} were aborted")]
+struct DeployAborted {
+    reason: String,
+    succeeded: usize,
+    failed: usize,
+    aborted: usize,
+    /// Whether it was terminated by SIGTERM.
+    terminated: bool,
+}
+
+/// Runs the `henix` command line interface, exiting the process if it fails.
+pub async fn main() {
+    // Get the command line arguments.
+    let opts = Opts::from_args();
+    // Initialize logging, before anything else can log.
+    init_logging(&opts);
+    output::set_heartbeat_interval(match opts.heartbeat {
+        0 => None,
+        secs => Some(std::time::Duration::from_secs(secs)),
+    });
+    if let Some(template) = &opts.ssh_control_path {
+        ssh::set_control_path(template.clone());
+    }
+
+    // Run and process any errors.
+    if let Err(e) = run(opts).await {
+        error!("{:?}", e);
+        std::process::exit(match e.downcast_ref::<DeployAborted>() {
+            Some(e) if e.terminated => TERMINATED_EXIT_CODE,
+            Some(e) if e.aborted > 0 => ABORTED_EXIT_CODE,
+            _ => 1,
+        });
+    }
+}
@@ -0,0 +1,252 @@
+/// Prefixing the log lines of each node with its name, so that the logs of nodes that are
+/// deployed to in parallel can be told apart.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::Interest;
+use tracing::{Event, Metadata, Subscriber};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+/// The default `--log-prefix`.
+pub const DEFAULT_PREFIX: &str = "[{node}] ";
+
+/// The log levels that `--log-level`, `--node-log-level` and `logLevel` accept.
+pub const LEVELS: &[&str] = &["error", "warn", "info", "debug", "trace"];
+
+/// Parses one of `LEVELS`.
+pub fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    if !LEVELS.contains(&level) {
+        return Err(format!(
+            "`{}` is not a log level, expected one of {}",
+            level,
+            LEVELS.join(", ")
+        ));
+    }
+    level
+        .parse()
+        .map_err(|_| format!("`{}` is not a log level", level))
+}
+
+/// Parses a `--node-log-level` of the form `{node}={level}`.
+pub fn parse_node_level(arg: &str) -> Result<(String, LevelFilter), String> {
+    match arg.split_once('=') {
+        Some((node, level)) if !node.is_empty() => Ok((node.to_owned(), parse_level(level)?)),
+        _ => Err(format!("`{}` is not of the form `{{node}}={{level}}`", arg)),
+    }
+}
+
+/// The `node` field of a span, stored in the span's extensions by `NodeNameLayer`.
+struct NodeName(String);
+
+struct NodeNameVisitor(Option<String>);
+
+impl Visit for NodeNameVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "node" {
+            self.0 = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "node" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+/// Remembers the `node` field of every span that has one, for `NodePrefix`.
+pub struct NodeNameLayer;
+
+impl<S> Layer<S> for NodeNameLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = NodeNameVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(name), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().insert(NodeName(name));
+        }
+    }
+}
+
+/// The log level of the events inside a node's span, stored in the span's extensions by
+/// `NodeLevelFilter`.
+struct NodeLevel(LevelFilter);
+
+/// The `node` and `log_level` fields of a span.
+#[derive(Default)]
+struct NodeLevelVisitor {
+    node: Option<String>,
+    log_level: Option<String>,
+}
+
+impl Visit for NodeLevelVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "node" => self.node = Some(value.to_owned()),
+            "log_level" => self.log_level = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+}
+
+/// Whether some node of the configuration has a `logLevel`, see `enable_node_levels`.
+static CFG_NODE_LEVELS: AtomicBool = AtomicBool::new(false);
+
+/// Makes `NodeLevelFilter` look for the `logLevel` of nodes, once the configuration turned out to
+/// have some.
+pub fn enable_node_levels() {
+    if !CFG_NODE_LEVELS.swap(true, Ordering::Relaxed) {
+        // The interest in callsites that were already registered changes.
+        tracing_core::callsite::rebuild_interest_cache();
+    }
+}
+
+/// Whether `metadata` is of henix itself, whose events are the ones that node levels apply to.
+fn is_henix(metadata: &Metadata<'_>) -> bool {
+    let target = metadata.target();
+    target == "henix" || target.starts_with("henix::")
+}
+
+/// Filters events with `inner`, except for henix's own events inside the span of a node that has
+/// its own log level, either from `--node-log-level` (`overrides`) or from the `log_level` field
+/// of the span (the node's `logLevel`). Those are filtered by the node's level alone. Without
+/// any node levels, this is just `inner`.
+pub struct NodeLevelFilter {
+    pub inner: EnvFilter,
+    pub overrides: BTreeMap<String, LevelFilter>,
+}
+
+impl NodeLevelFilter {
+    fn node_levels(&self) -> bool {
+        !self.overrides.is_empty() || CFG_NODE_LEVELS.load(Ordering::Relaxed)
+    }
+}
+
+impl<S> Layer<S> for NodeLevelFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let interest = Layer::<S>::register_callsite(&self.inner, metadata);
+        if !self.node_levels() || !is_henix(metadata) {
+            return interest;
+        }
+        if metadata.is_span() {
+            // See `enabled`.
+            Interest::always()
+        } else {
+            // Whether the event is enabled depends on the node it is about.
+            Interest::sometimes()
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        if self.node_levels() {
+            Some(LevelFilter::TRACE)
+        } else {
+            Layer::<S>::max_level_hint(&self.inner)
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        if !self.node_levels() || !is_henix(metadata) {
+            return self.inner.enabled(metadata, ctx);
+        }
+        // henix's spans are always created, since whether they have a level is only known from
+        // their fields. They are only logged with events, which are filtered.
+        if metadata.is_span() {
+            return true;
+        }
+        // The innermost span with a level wins.
+        let level = ctx.lookup_current().and_then(|span| {
+            span.scope()
+                .find_map(|span| span.extensions().get::<NodeLevel>().map(|level| level.0))
+        });
+        match level {
+            Some(level) => metadata.level() <= &level,
+            None => self.inner.enabled(metadata, ctx),
+        }
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !self.node_levels() {
+            self.inner.new_span(attrs, id, ctx);
+            return;
+        }
+        let mut visitor = NodeLevelVisitor::default();
+        attrs.record(&mut visitor);
+        let level = match (&visitor.node, &visitor.log_level) {
+            (Some(node), _) if self.overrides.contains_key(node) => Some(self.overrides[node]),
+            // Invalid levels were rejected when the configuration was read.
+            (_, Some(level)) => parse_level(level).ok(),
+            _ => None,
+        };
+        if let (Some(level), Some(span)) = (level, ctx.span(id)) {
+            span.extensions_mut().insert(NodeLevel(level));
+        }
+        self.inner.new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        Layer::<S>::on_record(&self.inner, id, values, ctx);
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        Layer::<S>::on_enter(&self.inner, id, ctx);
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        Layer::<S>::on_exit(&self.inner, id, ctx);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        Layer::<S>::on_close(&self.inner, id, ctx);
+    }
+}
+
+/// Formats events with `inner`, prepending `template` (with `{node}` replaced by the node's
+/// name) to the events that happen inside a node's span.
+pub struct NodePrefix<F> {
+    pub inner: F,
+    pub template: String,
+}
+
+impl<S, N, F> FormatEvent<S, N> for NodePrefix<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        writer: &mut dyn fmt::Write,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if !self.template.is_empty() {
+            // The innermost span with a node wins.
+            let mut node = None;
+            ctx.visit_spans::<(), _>(|span| {
+                if let Some(name) = span.extensions().get::<NodeName>() {
+                    node = Some(name.0.clone());
+                }
+                Ok(())
+            })
+            .ok();
+            if let Some(node) = node {
+                writer.write_str(&self.template.replace("{node}", &node))?;
+            }
+        }
+        self.inner.format_event(ctx, writer, event)
+    }
+}
@@ -0,0 +1,165 @@
+/// Deployment metadata stored on the remote, next to the configuration.
+use crate::{history, remote::RemoteExecutor, util, NodeCfg};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteMeta {
+    /// The store path of the system built from the configuration.
+    pub toplevel: String,
+    #[serde(flatten)]
+    pub origin: Origin,
+}
+
+/// Who deployed the configuration, and how. Older versions of henix only recorded the label.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Origin {
+    /// The label the configuration was deployed with (`--label`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployed_at: Option<DateTime<Utc>>,
+    /// `user@host` of the machine henix ran on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deployed_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub henix_version: Option<String>,
+    /// The Git revision of the configuration, if it is in a Git repository.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git_rev: Option<String>,
+    /// How the system was activated, e.g. `switch` or `boot`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+}
+
+impl Origin {
+    /// The origin of a deployment that is activated now with `action`.
+    pub fn new(label: Option<&str>, git_rev: Option<&str>, action: &str) -> Self {
+        let user = history::current_user();
+        Origin {
+            label: label.map(str::to_owned),
+            deployed_at: Some(Utc::now()),
+            deployed_by: Some(match hostname() {
+                Some(host) => format!("{}@{}", user, host),
+                None => user,
+            }),
+            henix_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            git_rev: git_rev.map(str::to_owned),
+            action: Some(action.to_owned()),
+        }
+    }
+
+    /// E.g. "deployed 2024-05-01 by alice@work, rev abc123", or `None` if nothing is known.
+    pub fn summary(&self) -> Option<String> {
+        let mut summary = "deployed".to_owned();
+        if let Some(deployed_at) = self.deployed_at {
+            summary.push_str(&format!(" {}", deployed_at.format("%Y-%m-%d %H:%M")));
+        }
+        if let Some(deployed_by) = &self.deployed_by {
+            summary.push_str(&format!(" by {}", deployed_by));
+        }
+        if let Some(git_rev) = &self.git_rev {
+            summary.push_str(&format!(", rev {}", git_rev));
+        }
+        if let Some(action) = &self.action {
+            summary.push_str(&format!(" ({})", action));
+        }
+        if summary == "deployed" {
+            return None;
+        }
+        Some(summary)
+    }
+}
+
+/// Returns the host name of the machine henix runs on.
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its whole length.
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).into_owned())
+}
+
+/// The metadata of the config with hash `cfg_hash` is stored at `/etc/henix/{hash}.json`.
+/// It can't be stored inside `/etc/henix/{hash}`, since that would change the flake.
+pub fn path(cfg_hash: &str) -> String {
+    format!("/etc/henix/{}.json", cfg_hash)
+}
+
+/// Runs `program args...` on the node, returning its trimmed stdout,
+/// or `None` if it exited unsuccessfully.
+pub async fn remote_output(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    program: &str,
+    args: &[&str],
+) -> Result<Option<String>> {
+    let out = remote
+        .output(node_cfg, program, args)
+        .await
+        .context(format!("Could not execute `{}` on remote", program))?;
+    if !out.status.success() {
+        return Ok(None);
+    }
+    let stdout = String::from_utf8(out.stdout)
+        .context(format!("Could not decode output of `{}` as UTF-8", program))?;
+    Ok(Some(stdout.trim().to_owned()))
+}
+
+/// Returns the store path of the system the node will run, after `nixos-rebuild`.
+pub async fn system_toplevel(remote: &dyn RemoteExecutor, node_cfg: &NodeCfg) -> Result<String> {
+    // The system profile is updated by both `nixos-rebuild switch` and `nixos-rebuild boot`.
+    remote_output(
+        remote,
+        node_cfg,
+        "readlink",
+        &["-f", "/nix/var/nix/profiles/system"],
+    )
+    .await?
+    .ok_or_else(|| anyhow!("Could not resolve the system profile"))
+}
+
+/// Writes the metadata of the config with hash `cfg_hash`.
+pub async fn write(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+    meta: &RemoteMeta,
+) -> Result<()> {
+    let meta = serde_json::to_string(meta).context("Could not serialize metadata")?;
+    let script = format!(
+        "printf '%s\\n' {} > {}",
+        util::shell_quote(&meta),
+        path(cfg_hash)
+    );
+    remote_output(remote, node_cfg, "sh", &["-c", &script])
+        .await?
+        .ok_or_else(|| anyhow!("Could not write {}", path(cfg_hash)))?;
+    Ok(())
+}
+
+/// Reads the metadata of the config with hash `cfg_hash`,
+/// or returns `None` if there is none, or it is invalid (which is warned about).
+pub async fn read(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    cfg_hash: &str,
+) -> Result<Option<RemoteMeta>> {
+    let meta = match remote_output(remote, node_cfg, "cat", &[&path(cfg_hash)]).await? {
+        Some(meta) => meta,
+        None => return Ok(None),
+    };
+    match serde_json::from_str(&meta) {
+        Ok(meta) => Ok(Some(meta)),
+        Err(e) => {
+            warn!("{} is invalid, ignoring it: {}", path(cfg_hash), e);
+            Ok(None)
+        }
+    }
+}
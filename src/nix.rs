@@ -1,46 +1,344 @@
 /// Nix utilities.
-use std::path::Path;
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{anyhow, Context};
+use crate::util;
+pub use crate::util::{CommandRunner, TokioRunner};
+
+use futures::StreamExt;
 use serde::de::DeserializeOwned;
-use tokio::process;
-
-/// Equivalent to `nix eval --json "$arg"`.
-pub async fn eval<Schema: DeserializeOwned>(cfg_dir: &Path, arg: &str) -> anyhow::Result<Schema> {
-    let out = process::Command::new("nix")
-        .current_dir(cfg_dir)
-        .arg("eval")
-        .arg("--json")
-        .arg("--")
-        .arg(arg)
-        .output()
-        .await
-        .context("Could not execute nix eval command")?;
+use serde::Deserialize;
+use tokio::sync::{OnceCell, Semaphore, SemaphorePermit};
+use tokio::{process, time};
+
+/// Why running a Nix command failed.
+#[derive(thiserror::Error, Debug)]
+pub enum NixError {
+    #[error("`{program}` was not found, is Nix installed?")]
+    NotFound { program: String },
+    #[error("Could not execute `{command}`")]
+    Io {
+        command: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("`{command}` timed out after {}s", .after.as_secs())]
+    Timeout { command: String, after: Duration },
+    #[error("`{command}` failed (exit code {exit_code:?}), with stderr:\n{stderr}")]
+    Failed {
+        command: String,
+        exit_code: Option<i32>,
+        stderr: String,
+    },
+    #[error("The output of `{command}` does not match the expected JSON schema")]
+    Json {
+        command: String,
+        stderr: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+impl NixError {
+    /// The stderr of the command, if it ran.
+    pub fn stderr(&self) -> Option<&str> {
+        match self {
+            NixError::Failed { stderr, .. } | NixError::Json { stderr, .. } => Some(stderr),
+            NixError::NotFound { .. } | NixError::Io { .. } | NixError::Timeout { .. } => None,
+        }
+    }
+}
+
+/// How Nix commands are run.
+#[derive(Debug, Clone)]
+pub struct NixOpts {
+    /// The directory the command runs in, e.g. the flake for `.#attr` arguments.
+    pub dir: Option<PathBuf>,
+    /// Arguments passed after the subcommand, e.g. `--override-input`.
+    pub extra_args: Vec<String>,
+    /// Passes `--show-trace`, for more detailed evaluation errors.
+    pub show_trace: bool,
+    /// Kills the command if it takes longer than this.
+    pub timeout: Option<Duration>,
+    /// Runs the commands, with `tokio::process` by default.
+    pub runner: Arc<dyn CommandRunner>,
+}
+
+impl Default for NixOpts {
+    fn default() -> Self {
+        NixOpts {
+            dir: None,
+            extra_args: Vec::new(),
+            show_trace: false,
+            timeout: None,
+            runner: Arc::new(TokioRunner),
+        }
+    }
+}
+
+impl NixOpts {
+    /// Runs commands in `dir`.
+    pub fn in_dir(dir: &Path) -> Self {
+        NixOpts {
+            dir: Some(dir.to_owned()),
+            ..NixOpts::default()
+        }
+    }
+
+    /// Adds `--override-input {input} {flake-url}` for every pair in `override_input`.
+    pub fn override_input(mut self, override_input: &[String]) -> Self {
+        for input in override_input.chunks(2) {
+            self.extra_args.push("--override-input".to_owned());
+            self.extra_args.extend(input.iter().cloned());
+        }
+        self
+    }
+}
+
+/// Limits the `nix eval` and `nix build` commands that run at the same time
+/// (`--max-eval-parallel`), if they are limited.
+static PERMITS: OnceCell<Semaphore> = OnceCell::const_new();
+
+/// Allows at most `max` `nix eval` and `nix build` commands at the same time, which are
+/// unlimited otherwise. Only the first call has an effect.
+pub fn set_max_parallel(max: usize) {
+    let _ = PERMITS.set(Semaphore::new(max));
+}
+
+/// Waits until another `nix eval` or `nix build` may run.
+async fn permit() -> Option<SemaphorePermit<'static>> {
+    PERMITS.get()?.acquire().await.ok()
+}
+
+/// Runs `program subcommand... [options] args...`, returning its output if it succeeded.
+/// `program` can be overridden with `HENIX_{PROGRAM}_BIN`, see `util::bin`.
+async fn run(
+    program: &str,
+    subcommand: &[&str],
+    args: &[&str],
+    opts: &NixOpts,
+) -> Result<Output, NixError> {
+    let bin = util::bin(program);
+    let mut cmd = process::Command::new(&bin);
+    cmd.args(subcommand).kill_on_drop(true);
+    if let Some(dir) = &opts.dir {
+        cmd.current_dir(dir);
+    }
+    if opts.show_trace {
+        cmd.arg("--show-trace");
+    }
+    cmd.args(&opts.extra_args).args(args);
+    let bin = bin.to_string_lossy();
+    let command = std::iter::once(&*bin)
+        .chain(subcommand.iter().copied())
+        .chain(args.iter().copied())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let out = opts.runner.output(cmd);
+    let out = match opts.timeout {
+        Some(after) => match time::timeout(after, out).await {
+            Ok(out) => out,
+            Err(_) => return Err(NixError::Timeout { command, after }),
+        },
+        None => out.await,
+    };
+    let out = match out {
+        Ok(out) => out,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(NixError::NotFound {
+                program: bin.into_owned(),
+            })
+        }
+        Err(source) => return Err(NixError::Io { command, source }),
+    };
     if !out.status.success() {
-        return Err(anyhow!(format!(
-            "Could not execute `nix eval {}` command, with stderr:\n{}",
-            arg,
-            &String::from_utf8_lossy(&out.stderr)
-        )));
+        return Err(NixError::Failed {
+            command,
+            exit_code: out.status.code(),
+            stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+        });
     }
-    serde_json::from_slice(&out.stdout).context(format!("`{}` does not match JSON schema", arg))
+    Ok(out)
 }
 
-/// Equivalent to `nix-hash "$dir"`.
-pub async fn hash(dir: &Path) -> anyhow::Result<String> {
-    let out = process::Command::new("nix-hash")
-        .arg(dir)
-        .output()
+/// Parses the stdout of `command` as JSON.
+fn parse_json<T: DeserializeOwned>(command: &str, out: &Output) -> Result<T, NixError> {
+    serde_json::from_slice(&out.stdout).map_err(|source| NixError::Json {
+        command: command.to_owned(),
+        stderr: String::from_utf8_lossy(&out.stderr).into_owned(),
+        source,
+    })
+}
+
+/// Equivalent to `nix eval --json "$arg"`, or `nix eval --json --apply "$apply" "$arg"`.
+pub async fn eval<Schema: DeserializeOwned>(
+    opts: &NixOpts,
+    arg: &str,
+    apply: Option<&str>,
+) -> Result<Schema, NixError> {
+    let mut subcommand = vec!["eval", "--json"];
+    if let Some(apply) = apply {
+        subcommand.extend(&["--apply", apply]);
+    }
+    let permit = permit().await;
+    let out = run("nix", &subcommand, &["--", arg], opts).await?;
+    drop(permit);
+    let command = match apply {
+        Some(apply) => format!("nix eval --apply {} {}", apply, arg),
+        None => format!("nix eval {}", arg),
+    };
+    parse_json(&command, &out)
+}
+
+/// Quotes `s` as a Nix string literal.
+fn nix_string(s: &str) -> String {
+    format!(
+        "\"{}\"",
+        s.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace("${", "\\${")
+    )
+}
+
+/// Evaluates several attributes of the flake attribute `arg` (e.g. `.#nixosConfigurations`) with a
+/// single `nix eval`, which is a lot faster than evaluating them one by one.
+/// `attrs` is a list of `(key, attr)`, where `attr` is an attribute path relative to `arg`, e.g.
+/// `"web-01".config.networking.hostName`. The result is a record mapping each `key` to the value
+/// of its `attr`, deserialized into `Schema`.
+pub async fn eval_many<Schema: DeserializeOwned>(
+    opts: &NixOpts,
+    arg: &str,
+    attrs: &[(&str, &str)],
+) -> Result<Schema, NixError> {
+    let record = attrs
+        .iter()
+        .map(|(key, attr)| format!("{} = x.{};", nix_string(key), attr))
+        .collect::<Vec<_>>()
+        .join(" ");
+    eval(opts, arg, Some(&format!("x: {{ {} }}", record))).await
+}
+
+/// The flake attribute of the system of the node `name`, e.g. for `nix build`.
+pub fn toplevel_installable(name: &str) -> String {
+    format!(
+        ".#nixosConfigurations.{}.config.system.build.toplevel",
+        nix_string(name)
+    )
+}
+
+/// The flake attribute of the store path of the system of the node `name`.
+pub fn toplevel_attr(name: &str) -> String {
+    format!("{}.outPath", toplevel_installable(name))
+}
+
+/// Evaluates the derivation of the system of each of the nodes `names` in `flake` (a flake URL,
+/// e.g. `.`), which is cheaper than evaluating their store paths and changes just the same.
+/// Nodes that `flake` doesn't have a system for are `None`.
+pub async fn eval_drv_paths(
+    opts: &NixOpts,
+    flake: &str,
+    names: &[&str],
+) -> Result<BTreeMap<String, Option<String>>, NixError> {
+    let names = names
+        .iter()
+        .map(|name| nix_string(name))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let apply = format!(
+        "cfgs: builtins.listToAttrs (map (name: {{ inherit name; value = if cfgs ? ${{name}} then cfgs.${{name}}.config.system.build.toplevel.drvPath else null; }}) [ {} ])",
+        names
+    );
+    eval(
+        opts,
+        &format!("{}#nixosConfigurations", flake),
+        Some(&apply),
+    )
+    .await
+}
+
+/// Evaluates the store path of the system of every node in `nodes`, given as its name and the
+/// flake it is defined in, with at most `max_jobs` evaluations at a time, since each of them can
+/// take a lot of memory. Returns the store path or the error of each node, by name.
+pub async fn eval_toplevels(
+    nodes: &[(&str, &Path)],
+    override_input: &[String],
+    max_jobs: usize,
+) -> BTreeMap<String, Result<String, NixError>> {
+    futures::stream::iter(nodes)
+        .map(|(name, flake_dir)| async move {
+            let opts = NixOpts::in_dir(flake_dir).override_input(override_input);
+            let toplevel = eval(&opts, &toplevel_attr(name), None).await;
+            ((*name).to_owned(), toplevel)
+        })
+        .buffer_unordered(max_jobs)
+        .collect()
         .await
-        .context("Could not execute nix-hash command")?;
-    if !out.status.success() {
-        return Err(anyhow!(format!(
-            "Could not execute `nix-hash {}` command, with stderr:\n{}",
-            dir.display(),
-            &String::from_utf8_lossy(&out.stderr)
-        )));
-    }
-    let hash =
-        String::from_utf8(out.stdout).context("Could not decode nix-hash's output as UTF-8")?;
-    Ok(hash.trim().to_string())
+}
+
+/// A derivation built by `nix build`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildResult {
+    pub drv_path: String,
+    /// The store path of each output, by output name.
+    pub outputs: BTreeMap<String, String>,
+}
+
+/// Equivalent to `nix build --json --no-link "$installables"`.
+pub async fn build(opts: &NixOpts, installables: &[&str]) -> Result<Vec<BuildResult>, NixError> {
+    let permit = permit().await;
+    let out = run("nix", &["build", "--json", "--no-link"], installables, opts).await?;
+    drop(permit);
+    parse_json(&format!("nix build {}", installables.join(" ")), &out)
+}
+
+/// The parts of the output of `nix flake metadata --json` that are stable across Nix versions.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FlakeMetadata {
+    pub description: Option<String>,
+    /// The locked flake reference, as a URL.
+    pub url: String,
+    /// The store path of the flake's source.
+    pub path: String,
+    /// The commit of the flake, if it is a clean Git repository.
+    pub revision: Option<String>,
+    /// When the flake was last modified, as a Unix timestamp.
+    pub last_modified: Option<i64>,
+    /// The contents of `flake.lock`.
+    pub locks: serde_json::Value,
+}
+
+impl FlakeMetadata {
+    /// The `narHash` of the flake's input `input` as it is locked, if it is a direct input.
+    pub fn input_nar_hash(&self, input: &str) -> Option<&str> {
+        let nodes = self.locks.get("nodes")?;
+        let root = self.locks.get("root")?.as_str()?;
+        let node = nodes.get(root)?.get("inputs")?.get(input)?.as_str()?;
+        nodes.get(node)?.get("locked")?.get("narHash")?.as_str()
+    }
+}
+
+/// Equivalent to `nix flake metadata --json "$flake"`.
+pub async fn flake_metadata(opts: &NixOpts, flake: &str) -> Result<FlakeMetadata, NixError> {
+    let out = run("nix", &["flake", "metadata", "--json"], &[flake], opts).await?;
+    parse_json(&format!("nix flake metadata {}", flake), &out)
+}
+
+/// Equivalent to `nix-hash "$dir"`.
+pub async fn hash(opts: &NixOpts, dir: &Path) -> Result<String, NixError> {
+    let dir = dir.to_string_lossy();
+    let out = run("nix-hash", &[], &[&dir], opts).await?;
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+}
+
+/// Returns the MD5 hash of `s`, in the same format as `nix-hash`.
+pub async fn hash_string(opts: &NixOpts, s: &str) -> Result<String, NixError> {
+    let expr = format!("builtins.hashString \"md5\" {}", nix_string(s));
+    let out = run("nix", &["eval", "--raw"], &["--expr", &expr], opts).await?;
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
 }
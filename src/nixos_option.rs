@@ -0,0 +1,39 @@
+/// Querying NixOS options on nodes, for `henix nixos-option`.
+use crate::{
+    remote::{self, RemoteExecutor},
+    HostKeyOpts, NodeCfg,
+};
+use anyhow::{anyhow, Context, Result};
+
+/// Runs `nixos-option option` (with `--json` if `json`) on the node, and returns its output.
+#[tracing::instrument(
+    name = "nixos_option",
+    skip(name, node_cfg, host_key_opts, option, json),
+    fields(node = name)
+)]
+pub async fn query_node(
+    name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+    option: &str,
+    json: bool,
+) -> Result<String> {
+    let remote = remote::connect(name, node_cfg, host_key_opts).await?;
+    let mut args = Vec::new();
+    if json {
+        args.push("--json");
+    }
+    args.push(option);
+    let out = remote
+        .output(node_cfg, "nixos-option", &args)
+        .await
+        .context("Could not execute `nixos-option` on the node")?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "`nixos-option {}` failed, with stderr:\n{}",
+            option,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    String::from_utf8(out.stdout).context("Could not decode the output of `nixos-option` as UTF-8")
+}
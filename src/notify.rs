@@ -0,0 +1,56 @@
+/// Desktop notifications when a deployment finishes, for `--notify-desktop`.
+use crate::deploy::DeployResult;
+use tokio::process::Command;
+use tracing::warn;
+
+/// Shows a desktop notification with how many nodes were deployed to and which failed, with
+/// `notify-send`, or `osascript` on macOS. If it can't be shown (e.g. there is no notification
+/// daemon), the summary is only logged.
+pub async fn deploy_finished(result: &DeployResult) {
+    let failed = result
+        .failed()
+        .map(|node| node.name.as_str())
+        .collect::<Vec<_>>();
+    let mut summary = format!(
+        "deploy finished: {} ok, {} failed",
+        result.succeeded().count(),
+        failed.len()
+    );
+    let aborted = result.aborted().count();
+    if aborted > 0 {
+        summary.push_str(&format!(", {} aborted", aborted));
+    }
+    let body = if failed.is_empty() {
+        String::new()
+    } else {
+        format!("Failed: {}", failed.join(", "))
+    };
+    let mut command = if cfg!(target_os = "macos") {
+        let script = format!(
+            "display notification {} with title \"henix\" subtitle {}",
+            applescript_string(&body),
+            applescript_string(&summary)
+        );
+        let mut command = Command::new("osascript");
+        command.args(["-e", &script]);
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name=henix", &format!("henix: {}", summary), &body]);
+        command
+    };
+    match command.output().await {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => warn!(
+            "Could not show a desktop notification ({}): {}",
+            String::from_utf8_lossy(&output.stderr).trim(),
+            summary
+        ),
+        Err(e) => warn!("Could not show a desktop notification ({}): {}", e, summary),
+    }
+}
+
+/// Quotes `s` as an AppleScript string.
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
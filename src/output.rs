@@ -0,0 +1,338 @@
+/// Handling of the output of proxied commands (`rsync`, `nixos-rebuild`, etc.).
+use crate::error;
+use crate::events::{NodeEvents, Phase};
+use crate::util;
+use anyhow::anyhow;
+use chrono::{DateTime, Local, Utc};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval};
+use tracing::{info, warn};
+
+/// How the output of commands run for each node is shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Every line is logged as soon as it arrives, so output of different nodes is interleaved.
+    Interleaved,
+    /// Output is held back per node, and printed as one block once that node finishes.
+    Grouped,
+}
+
+impl OutputMode {
+    pub const VARIANTS: &'static [&'static str] = &["interleaved", "grouped"];
+}
+
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "interleaved" => Ok(OutputMode::Interleaved),
+            "grouped" => Ok(OutputMode::Grouped),
+            _ => Err(anyhow!("Unknown output mode `{}`", s)),
+        }
+    }
+}
+
+/// Where the output lines of a proxied command end up.
+#[derive(Clone)]
+pub enum OutputSink {
+    /// Log each line immediately.
+    Log,
+    /// Store each line in a buffer, to be printed later with `OutputBuffer::print_block`.
+    Buffer(Arc<Mutex<OutputBuffer>>),
+    /// Send each line to the inner sink, and also to the event stream.
+    Events(Box<OutputSink>, NodeEvents),
+    /// Send each line to the inner sink, and also to the node's log file.
+    File(Box<OutputSink>, Arc<NodeLog>),
+    /// Send each line to the inner sink, and also keep the lines on stdout, to be parsed
+    /// afterwards.
+    Capture(Box<OutputSink>, Arc<Mutex<Vec<String>>>),
+    /// Send each line to the inner sink, and also keep the last lines on stderr, for errors.
+    Tail(Box<OutputSink>, StderrTail),
+}
+
+impl OutputSink {
+    pub fn for_mode(mode: OutputMode) -> Self {
+        match mode {
+            OutputMode::Interleaved => OutputSink::Log,
+            OutputMode::Grouped => OutputSink::Buffer(Arc::new(Mutex::new(OutputBuffer::new()))),
+        }
+    }
+
+    /// Handles a single line of output of `program` on `stream` (either `stdout` or `stderr`).
+    pub fn line(&self, program: &str, stream: &str, line: &str) {
+        match self {
+            OutputSink::Log => info!(program, stream, "{}", line),
+            OutputSink::Buffer(buf) => buf
+                .lock()
+                .unwrap()
+                .push(&format!("[{}] {}: {}", program, stream, line)),
+            OutputSink::Events(inner, events) => {
+                inner.line(program, stream, line);
+                events.output(&format!("[{}] {}", program, line));
+            }
+            OutputSink::File(inner, log) => {
+                inner.line(program, stream, line);
+                log.write(&format!("[{}] {}: {}", program, stream, line));
+            }
+            OutputSink::Capture(inner, lines) => {
+                inner.line(program, stream, line);
+                if stream == "stdout" {
+                    lines.lock().unwrap().push(line.to_owned());
+                }
+            }
+            OutputSink::Tail(inner, tail) => {
+                inner.line(program, stream, line);
+                if stream == "stderr" {
+                    tail.push(line);
+                }
+            }
+        }
+    }
+
+    /// Records that the node entered `phase`, if events or a log file are being written.
+    pub fn phase(&self, phase: Phase) {
+        match self {
+            OutputSink::Events(inner, events) => {
+                inner.phase(phase);
+                events.phase(phase);
+            }
+            OutputSink::File(inner, log) => {
+                inner.phase(phase);
+                log.write(&format!("-- {}", phase.name()));
+            }
+            OutputSink::Capture(inner, _) | OutputSink::Tail(inner, _) => inner.phase(phase),
+            OutputSink::Log | OutputSink::Buffer(_) => {}
+        }
+    }
+
+    /// Writes `line` to the node's log file only, if there is one, e.g. for errors that are
+    /// already logged.
+    pub fn note(&self, line: &str) {
+        match self {
+            OutputSink::Events(inner, _)
+            | OutputSink::Capture(inner, _)
+            | OutputSink::Tail(inner, _) => inner.note(line),
+            OutputSink::File(inner, log) => {
+                inner.note(line);
+                log.write(line);
+            }
+            OutputSink::Log | OutputSink::Buffer(_) => {}
+        }
+    }
+}
+
+/// The last `error::STDERR_TAIL_LINES` lines on stderr of the commands run with a sink.
+#[derive(Clone, Default)]
+pub struct StderrTail(Arc<Mutex<VecDeque<String>>>);
+
+impl StderrTail {
+    /// Wraps `sink` so that its stderr also ends up in the returned tail.
+    pub fn wrap(sink: &OutputSink) -> (OutputSink, StderrTail) {
+        let tail = StderrTail::default();
+        (OutputSink::Tail(Box::new(sink.clone()), tail.clone()), tail)
+    }
+
+    fn push(&self, line: &str) {
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() == error::STDERR_TAIL_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_owned());
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// The default directory for `--log-dir`, relative to the configuration directory.
+pub const DEFAULT_LOG_DIR: &str = ".henix-logs";
+
+/// Returns the log file of `node` for the deployment started at `started`.
+pub fn node_log_path(log_dir: &Path, node: &str, started: DateTime<Local>) -> PathBuf {
+    log_dir.join(format!("{}-{}.log", node, started.format("%Y%m%d-%H%M%S")))
+}
+
+/// The full output of a single node, saved for `--log-dir`.
+pub struct NodeLog(Mutex<File>);
+
+impl NodeLog {
+    /// Creates the log file at `path`, appending to it if it already exists.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(NodeLog(Mutex::new(file)))
+    }
+
+    fn write(&self, line: &str) {
+        let line = format!("{} {}\n", Utc::now().to_rfc3339(), line);
+        // Not buffered, so that the file can be followed while the node is being deployed.
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Could not write to the log file: {:?}", e);
+        }
+    }
+}
+
+/// The heartbeat interval in seconds, or 0 if heartbeats are disabled.
+static HEARTBEAT_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets how long a command has to be silent before `Heartbeat` logs that it is still running.
+/// `None` disables heartbeats.
+pub fn set_heartbeat_interval(interval: Option<Duration>) {
+    let secs = interval.map_or(0, |interval| interval.as_secs().max(1));
+    HEARTBEAT_SECS.store(secs, Ordering::Relaxed);
+}
+
+/// Logs that a command is still running while it produces no output, so that long, silent
+/// steps (e.g. fetching a large source during a build) don't look like a hang.
+pub struct Heartbeat {
+    start: Instant,
+    last_output: Instant,
+    /// `None` if heartbeats are disabled.
+    interval: Option<(Duration, Interval)>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        let interval = match HEARTBEAT_SECS.load(Ordering::Relaxed) {
+            0 => None,
+            secs => {
+                let period = Duration::from_secs(secs);
+                Some((period, time::interval_at(now + period, period)))
+            }
+        };
+        Heartbeat {
+            start: now,
+            last_output: now,
+            interval,
+        }
+    }
+
+    /// Records that the command produced output.
+    pub fn output(&mut self) {
+        self.last_output = Instant::now();
+    }
+
+    /// Waits until the next heartbeat is due, and logs it. Never finishes if heartbeats are
+    /// disabled.
+    pub async fn beat(&mut self, program: &str) {
+        let (period, interval) = match &mut self.interval {
+            Some(interval) => interval,
+            None => return futures::future::pending().await,
+        };
+        loop {
+            interval.tick().await;
+            if self.last_output.elapsed() >= *period {
+                break;
+            }
+        }
+        info!(
+            program,
+            "Still running, no output for {} ({} elapsed)",
+            util::format_duration(self.last_output.elapsed()),
+            util::format_duration(self.start.elapsed())
+        );
+    }
+}
+
+/// Buffered output is kept in memory up to this many bytes, after which it is spooled to a
+/// temporary file.
+const SPOOL_THRESHOLD: usize = 1 << 20;
+
+/// The held-back output of a single node.
+pub struct OutputBuffer {
+    lines: Vec<String>,
+    size: usize,
+    spool: Option<(PathBuf, BufWriter<File>)>,
+}
+
+impl OutputBuffer {
+    fn new() -> Self {
+        OutputBuffer {
+            lines: Vec::new(),
+            size: 0,
+            spool: None,
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        if self.spool.is_none() && self.size + line.len() > SPOOL_THRESHOLD {
+            if let Err(e) = self.start_spooling() {
+                // Just keep everything in memory instead.
+                warn!("Could not spool output to a temporary file: {:?}", e);
+            }
+        }
+        if let Some((_, file)) = self.spool.as_mut() {
+            if writeln!(file, "{}", line).is_ok() {
+                return;
+            }
+            warn!("Could not write output to the spool file, keeping it in memory");
+        }
+        self.size += line.len();
+        self.lines.push(line.to_owned());
+    }
+
+    /// Moves everything buffered so far into a fresh temporary file.
+    fn start_spooling(&mut self) -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "henix-output-{}-{:p}.log",
+            std::process::id(),
+            self as *const Self
+        ));
+        let mut file = BufWriter::new(
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?,
+        );
+        for line in &self.lines {
+            writeln!(file, "{}", line)?;
+        }
+        self.lines.clear();
+        self.size = 0;
+        self.spool = Some((path, file));
+        Ok(())
+    }
+
+    /// Prints all buffered output to stdout as one contiguous block, surrounded by a header and a
+    /// footer containing the node name and `result`.
+    pub fn print_block(&mut self, node_name: &str, result: &str) -> io::Result<()> {
+        let stdout = io::stdout();
+        // Hold the lock for the entire block, so that blocks of different nodes don't mix.
+        let mut out = stdout.lock();
+        writeln!(out, "===== {} =====", node_name)?;
+        if let Some((_, file)) = self.spool.as_mut() {
+            file.flush()?;
+            let file = file.get_mut();
+            file.seek(SeekFrom::Start(0))?;
+            for line in BufReader::new(&*file).lines() {
+                writeln!(out, "{}", line?)?;
+            }
+        }
+        for line in &self.lines {
+            writeln!(out, "{}", line)?;
+        }
+        writeln!(out, "===== {}: {} =====", node_name, result)?;
+        out.flush()
+    }
+}
+
+impl Drop for OutputBuffer {
+    fn drop(&mut self) {
+        if let Some((path, _)) = self.spool.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
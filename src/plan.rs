@@ -0,0 +1,145 @@
+/// Saved deployment plans, for `henix plan` and `henix apply`.
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{OpenOptions, Permissions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// The version of the plan file format, which `henix apply` must know.
+const VERSION: u32 = 1;
+
+/// What a planned deployment does to a node.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedNode {
+    pub location: String,
+    /// The hash of the configuration that is copied to the node.
+    pub hash: String,
+    /// The store path of the system that is deployed, as it was evaluated.
+    pub toplevel: String,
+    /// The commands that are run on the node after copying the configuration to it.
+    pub commands: Vec<String>,
+}
+
+/// A deployment that was planned with `henix plan`, to be done with `henix apply`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Plan {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    /// The command line of `henix plan`, whose options `henix apply` deploys with.
+    pub args: Vec<String>,
+    pub cfg_dir: PathBuf,
+    /// The hash of each configuration directory.
+    pub hashes: BTreeMap<PathBuf, String>,
+    /// The deploy configuration, with only the planned nodes, so that it isn't evaluated again.
+    pub deploy: serde_json::Value,
+    /// By node name.
+    pub nodes: BTreeMap<String, PlannedNode>,
+}
+
+/// Whether the deployment is planned, or is the one of a plan.
+pub enum PlanMode {
+    /// Deploys as usual.
+    None,
+    /// Writes the plan to this file instead of deploying, for `henix plan`.
+    Write(PathBuf),
+    /// Deploys the plan, for `henix apply`. With `force`, even if the configuration changed.
+    Apply { plan: Box<Plan>, force: bool },
+}
+
+impl Plan {
+    pub fn new(
+        cfg_dir: &Path,
+        hashes: BTreeMap<PathBuf, String>,
+        deploy: serde_json::Value,
+        nodes: BTreeMap<String, PlannedNode>,
+    ) -> Self {
+        Plan {
+            version: VERSION,
+            created_at: Utc::now(),
+            args: std::env::args_os()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            cfg_dir: cfg_dir.to_owned(),
+            hashes,
+            deploy,
+            nodes,
+        }
+    }
+
+    /// Writes the plan to `path`, which only the user can read, since it has the deploy
+    /// configuration.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        let write = || {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(path)?;
+            // `mode` only applies to new files.
+            file.set_permissions(Permissions::from_mode(0o600))?;
+            file.write_all((json + "\n").as_bytes())
+        };
+        write().context(format!("Could not write the plan to `{}`", path.display()))
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read(path).context(format!("Could not read the plan `{}`", path.display()))?;
+        let plan: serde_json::Value = serde_json::from_slice(&contents)
+            .context(format!("`{}` is not a valid plan", path.display()))?;
+        let version = plan.get("version").and_then(serde_json::Value::as_u64);
+        if version != Some(VERSION.into()) {
+            return Err(anyhow!(
+                "The plan `{}` has version {}, but this henix only knows version {}",
+                path.display(),
+                version.map_or_else(|| "none".to_owned(), |version| version.to_string()),
+                VERSION
+            ));
+        }
+        serde_json::from_value(plan).context(format!("`{}` is not a valid plan", path.display()))
+    }
+
+    /// Checks that the configuration directories still have the `hashes` they had when the
+    /// deployment was planned. With `force`, it is only warned about if they changed. Returns
+    /// whether they are unchanged.
+    pub fn check_hashes(&self, hashes: &BTreeMap<PathBuf, String>, force: bool) -> Result<bool> {
+        let mut unchanged = true;
+        for (dir, hash) in hashes {
+            let planned = self.hashes.get(dir).map(String::as_str);
+            if planned == Some(hash.as_str()) {
+                continue;
+            }
+            unchanged = false;
+            let message = format!(
+                "The configuration in `{}` changed since the deployment was planned (hash {}, planned {})",
+                dir.display(),
+                hash,
+                planned.unwrap_or("none")
+            );
+            if !force {
+                return Err(anyhow!(
+                    "{}; plan it again, or pass --force to deploy it anyway",
+                    message
+                ));
+            }
+            warn!("{}, deploying it anyway (--force)", message);
+        }
+        Ok(unchanged)
+    }
+
+    /// The store paths of the planned systems, by node.
+    pub fn toplevels(&self) -> BTreeMap<String, String> {
+        self.nodes
+            .iter()
+            .map(|(name, node)| (name.clone(), node.toplevel.clone()))
+            .collect()
+    }
+}
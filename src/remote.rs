@@ -0,0 +1,353 @@
+/// Running commands on nodes, over SSH, or directly for the node henix runs on.
+use crate::{error::HenixError, output::OutputSink, ssh, util, Escalation, HostKeyOpts, NodeCfg};
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use std::process::{ExitStatus, Output};
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::{process, time};
+use tracing::{info, warn};
+
+/// Runs commands on a node as root. The deployment steps are written against this rather than
+/// `Remote`, so that they don't depend on how the node is reached.
+pub trait RemoteExecutor: Sync {
+    /// Runs `program args...` on the node, returning its output.
+    fn output<'a>(
+        &'a self,
+        node_cfg: &'a NodeCfg,
+        program: &'a str,
+        args: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Output>>;
+
+    /// Runs `program args...` on the node, sending its output to `sink` line-by-line, with
+    /// `name` as the program's name in the logs.
+    fn run_logged<'a>(
+        &'a self,
+        node_cfg: &'a NodeCfg,
+        name: &'a str,
+        program: &'a str,
+        args: &'a [&'a str],
+        sink: &'a OutputSink,
+    ) -> BoxFuture<'a, Result<ExitStatus>>;
+}
+
+pub enum Remote {
+    Ssh {
+        session: openssh::Session,
+        /// The master connection for rsync, if any, which is closed along with the session.
+        _master: Option<ssh::ControlMaster>,
+        /// Released once the session and master connection are closed, since fields are
+        /// dropped in order.
+        _permit: Option<OwnedSemaphorePermit>,
+    },
+    /// The node is the machine henix runs on (`location = "local"`).
+    Local,
+}
+
+/// A command built by `Remote::command`.
+pub enum RemoteCommand<'s> {
+    Ssh(openssh::Command<'s>),
+    Local(process::Command),
+}
+
+/// Connects to the node, unless it is the local machine.
+pub async fn connect(
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+) -> Result<Remote> {
+    if node_cfg.is_local() {
+        info!("Node is the local machine, not using SSH");
+        return Ok(Remote::Local);
+    }
+    let (session, permit) = ssh::connect_to_node(node_name, node_cfg, host_key_opts).await?;
+    let master = match ssh::ControlMaster::start(node_cfg, host_key_opts).await {
+        Ok(master) => master,
+        Err(e) => {
+            warn!("rsync will not reuse the SSH connection: {:?}", e);
+            None
+        }
+    };
+    finish_connect(
+        node_cfg,
+        Remote::Ssh {
+            session,
+            _master: master,
+            _permit: permit,
+        },
+    )
+    .await
+}
+
+/// Connects to the node again while the session from `connect` is still open. This doesn't
+/// wait for `--max-ssh-connections`, since the open session already holds a permit for the node,
+/// and doesn't start another control master, since the one of that session is still running.
+pub async fn reconnect(
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+) -> Result<Remote> {
+    if node_cfg.is_local() {
+        return Ok(Remote::Local);
+    }
+    let session = ssh::open_session(node_name, node_cfg, host_key_opts).await?;
+    finish_connect(
+        node_cfg,
+        Remote::Ssh {
+            session,
+            _master: None,
+            _permit: None,
+        },
+    )
+    .await
+}
+
+/// Sets up the rest of a connection to the node once its SSH session is open.
+async fn finish_connect(node_cfg: &NodeCfg, remote: Remote) -> Result<Remote> {
+    if let Some(tool) = escalation(node_cfg) {
+        check_escalation(&remote, node_cfg, tool).await?;
+    }
+    Ok(remote)
+}
+
+/// How long `henix ping` and `--preflight` wait for a node to be connected to.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to the node and disconnects again, returning how long connecting took. The timeout
+/// only starts once `--max-ssh-connections` allows the session, and connecting isn't retried.
+#[tracing::instrument(
+    name = "ping",
+    skip(node_name, node_cfg, host_key_opts),
+    fields(node = node_name)
+)]
+pub async fn ping(
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+) -> Result<Duration> {
+    let local = node_cfg.is_local();
+    let _permit = if local {
+        None
+    } else {
+        ssh::acquire_connection().await
+    };
+    let started = Instant::now();
+    let connect = async {
+        let remote = if local {
+            Remote::Local
+        } else {
+            Remote::Ssh {
+                session: ssh::try_connect(node_name, node_cfg, host_key_opts).await?,
+                _master: None,
+                _permit: None,
+            }
+        };
+        if let Some(tool) = escalation(node_cfg) {
+            check_escalation(&remote, node_cfg, tool).await?;
+        }
+        Ok::<_, anyhow::Error>(remote)
+    };
+    match time::timeout(PING_TIMEOUT, connect).await {
+        Ok(remote) => {
+            remote.context(HenixError::Connect {
+                node: node_name.to_owned(),
+            })?;
+            Ok(started.elapsed())
+        }
+        Err(_) => Err(anyhow!("Timed out after {}s", PING_TIMEOUT.as_secs())).context(
+            HenixError::Connect {
+                node: node_name.to_owned(),
+            },
+        ),
+    }
+}
+
+/// Whether henix runs as root.
+pub fn is_root() -> bool {
+    // SAFETY: `geteuid` has no preconditions and can't fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Returns the program that commands on the node are run through to get root, if any.
+/// Defaults to `sudo` on the local node if henix doesn't run as root, and to nothing otherwise,
+/// since henix connects to remote nodes as root unless told otherwise.
+pub fn escalation(node_cfg: &NodeCfg) -> Option<&'static str> {
+    match node_cfg.escalation {
+        Some(Escalation::Sudo) => Some("sudo"),
+        Some(Escalation::Doas) => Some("doas"),
+        Some(Escalation::None) => None,
+        None if node_cfg.is_local() && !is_root() => Some("sudo"),
+        None => None,
+    }
+}
+
+/// Checks that `tool` works without a password on the node, since there is no way to enter
+/// one for a remote command.
+async fn check_escalation(
+    remote: &dyn RemoteExecutor,
+    node_cfg: &NodeCfg,
+    tool: &str,
+) -> Result<()> {
+    let out = remote
+        .output(node_cfg, "true", &[])
+        .await
+        .context(format!("Could not check whether `{}` works", tool))?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "`{} -n true` failed on the node, passwordless `{}` must be set up for henix to use it: {}",
+            tool,
+            tool,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+impl Remote {
+    /// Builds the command `program args...` to be run on the node as root, using the node's
+    /// `escalation`, and wrapping it in the node's `remoteShell` if it has one.
+    fn command<'s, S: AsRef<str>>(
+        &'s self,
+        node_cfg: &NodeCfg,
+        program: &str,
+        args: &[S],
+    ) -> RemoteCommand<'s> {
+        let mut argv = Vec::new();
+        if let Some(tool) = escalation(node_cfg) {
+            argv.push(tool);
+            if let Remote::Ssh { .. } = self {
+                // Never prompt for a password, which would hang. Locally, it can be entered.
+                argv.push("-n");
+            }
+        }
+        argv.push(program);
+        argv.extend(args.iter().map(AsRef::as_ref));
+        match self {
+            Remote::Ssh { session, .. } => {
+                RemoteCommand::Ssh(ssh::node_command(session, node_cfg, argv[0], &argv[1..]))
+            }
+            Remote::Local => RemoteCommand::Local(local_command(node_cfg, &argv)),
+        }
+    }
+}
+
+impl RemoteExecutor for Remote {
+    fn output<'a>(
+        &'a self,
+        node_cfg: &'a NodeCfg,
+        program: &'a str,
+        args: &'a [&'a str],
+    ) -> BoxFuture<'a, Result<Output>> {
+        Box::pin(async move { self.command(node_cfg, program, args).output().await })
+    }
+
+    fn run_logged<'a>(
+        &'a self,
+        node_cfg: &'a NodeCfg,
+        name: &'a str,
+        program: &'a str,
+        args: &'a [&'a str],
+        sink: &'a OutputSink,
+    ) -> BoxFuture<'a, Result<ExitStatus>> {
+        Box::pin(async move {
+            self.command(node_cfg, program, args)
+                .proxy_output_to_logging(name, sink)
+                .await
+        })
+    }
+}
+
+fn local_command(node_cfg: &NodeCfg, argv: &[&str]) -> process::Command {
+    let argv = argv.iter().copied();
+    let argv = match &node_cfg.remote_shell {
+        None => argv.map(str::to_owned).collect::<Vec<_>>(),
+        Some(shell) => {
+            // Run it the same way the login shell on a remote node would.
+            let inner = argv.map(util::shell_quote).collect::<Vec<_>>().join(" ");
+            vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                format!("{} {}", shell, util::shell_quote(&inner)),
+            ]
+        }
+    };
+    let mut cmd = process::Command::new(&argv[0]);
+    cmd.args(&argv[1..]).kill_on_drop(true);
+    cmd
+}
+
+impl RemoteCommand<'_> {
+    pub async fn output(&mut self) -> Result<Output> {
+        match self {
+            RemoteCommand::Ssh(cmd) => Ok(cmd.output().await?),
+            RemoteCommand::Local(cmd) => Ok(cmd.output().await?),
+        }
+    }
+
+    /// Runs the command, sending its output to `sink` line-by-line.
+    pub async fn proxy_output_to_logging(
+        self,
+        program: &str,
+        sink: &OutputSink,
+    ) -> Result<ExitStatus> {
+        match self {
+            RemoteCommand::Ssh(cmd) => ssh::proxy_output_to_logging(program, cmd, sink).await,
+            RemoteCommand::Local(cmd) => util::proxy_output_to_logging(program, cmd, sink).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Remote, RemoteCommand};
+    use crate::NodeCfg;
+    use serde_json::json;
+
+    /// The command line that `Remote::Local` runs `program args...` with, for the node `node_cfg`.
+    fn local_argv(node_cfg: serde_json::Value, program: &str, args: &[&str]) -> Vec<String> {
+        let node_cfg: NodeCfg = serde_json::from_value(node_cfg).unwrap();
+        match Remote::Local.command(&node_cfg, program, args) {
+            RemoteCommand::Local(cmd) => {
+                let cmd = cmd.as_std();
+                std::iter::once(cmd.get_program())
+                    .chain(cmd.get_args())
+                    .map(|arg| arg.to_string_lossy().into_owned())
+                    .collect()
+            }
+            RemoteCommand::Ssh(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn local_command() {
+        assert_eq!(
+            local_argv(
+                json!({ "location": "local", "escalation": "none" }),
+                "nix-env",
+                &["--set", "/nix/store/a b"]
+            ),
+            ["nix-env", "--set", "/nix/store/a b"]
+        );
+        // No `-n`, since a password can be entered locally.
+        assert_eq!(
+            local_argv(
+                json!({ "location": "local", "escalation": "doas" }),
+                "touch",
+                &["/etc/henix/4a8ff2c0.copied"]
+            ),
+            ["doas", "touch", "/etc/henix/4a8ff2c0.copied"]
+        );
+        assert_eq!(
+            local_argv(
+                json!({ "location": "local", "escalation": "sudo", "remoteShell": "bash -lc" }),
+                "sh",
+                &["-c", "command -v nix"]
+            ),
+            [
+                "sh",
+                "-c",
+                r#"bash -lc 'sudo sh -c '\''command -v nix'\'''"#
+            ]
+        );
+    }
+}
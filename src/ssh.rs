@@ -1,40 +1,687 @@
 use std::process::Stdio;
 
 /// SSH utilities.
-use crate::NodeCfg;
-use anyhow::{Context, Result};
+use crate::{
+    error::HenixError,
+    output::{Heartbeat, OutputSink},
+    util, HostKeyOpts, NodeCfg,
+};
+use anyhow::{anyhow, Context, Result};
+use openssh::KnownHosts;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::NamedTempFile;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tracing::{info, warn};
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use tracing::{info, warn, Instrument};
 
-pub async fn connect_to_node(node_name: &str, node_cfg: &NodeCfg) -> Result<openssh::Session> {
-    info!("Establishing SSH session");
-    let mut builder = openssh::SessionBuilder::default();
-    if let Some(ssh_port) = node_cfg.ssh_port {
-        builder.port(ssh_port);
-    }
-    let remote = builder
-        .user("root".to_string())
-        .control_directory("/tmp") // Default is "./", which is not nice to nix-hash.
-        .connect(&node_cfg.location)
-        .await
-        .context(format!(
-            "Could not connect to node with name `{}`",
-            node_name
+/// A way to authenticate to a node, in the node's `authMethods`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SshAuthMethod {
+    /// The keys of the SSH agent and the default identity files, as `ssh` does by default.
+    Agent,
+    /// Only this private key.
+    IdentityFile(PathBuf),
+    /// A password. This isn't supported, since `ssh` runs in batch mode and can't be given one,
+    /// so nodes with one are rejected.
+    // Never logged.
+    Password(#[allow(dead_code)] String),
+}
+
+impl fmt::Display for SshAuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SshAuthMethod::Agent => write!(f, "the SSH agent"),
+            SshAuthMethod::IdentityFile(path) => write!(f, "the key `{}`", path.display()),
+            SshAuthMethod::Password(_) => write!(f, "a password"),
+        }
+    }
+}
+
+impl SshAuthMethod {
+    /// The method as it is configured, but without the password, for `--dump-config`.
+    pub fn redacted(&self) -> serde_json::Value {
+        match self {
+            SshAuthMethod::Agent => serde_json::json!("agent"),
+            SshAuthMethod::IdentityFile(path) => serde_json::json!({ "identityFile": path }),
+            SshAuthMethod::Password(_) => serde_json::json!({ "password": "<redacted>" }),
+        }
+    }
+}
+
+/// The node's `authMethods`, which default to the SSH agent.
+pub fn auth_methods(node_cfg: &NodeCfg) -> &[SshAuthMethod] {
+    match &node_cfg.auth_methods {
+        Some(methods) if !methods.is_empty() => methods,
+        _ => &[SshAuthMethod::Agent],
+    }
+}
+
+/// Whether connecting failed because the node didn't accept the authentication, so that the
+/// next method can be tried.
+fn is_auth_failure(e: &openssh::Error) -> bool {
+    // ssh only tells this apart in its message.
+    e.to_string().contains("Permission denied")
+}
+
+/// Whether connecting failed because the node's host key is unknown or changed.
+fn is_host_key_failure(e: &openssh::Error) -> bool {
+    e.to_string().contains("Host key verification failed")
+}
+
+/// Returns how the host key of the node should be checked.
+/// The node's `strictHostChecking` takes precedence over the command line flags.
+fn known_hosts_policy(node_cfg: &NodeCfg, host_key_opts: &HostKeyOpts) -> KnownHosts {
+    if host_key_checking_disabled() {
+        KnownHosts::Accept
+    } else if strict_host_checking(node_cfg, host_key_opts) {
+        KnownHosts::Strict
+    } else {
+        KnownHosts::Add
+    }
+}
+
+/// The `StrictHostKeyChecking` option that makes the ssh commands henix runs itself check the
+/// host key like `known_hosts_policy` does for the session.
+fn strict_host_key_checking_option(node_cfg: &NodeCfg, host_key_opts: &HostKeyOpts) -> String {
+    let value = if host_key_checking_disabled() {
+        "no"
+    } else if strict_host_checking(node_cfg, host_key_opts) {
+        "yes"
+    } else {
+        "accept-new"
+    };
+    format!("StrictHostKeyChecking={}", value)
+}
+
+/// Whether the host key of the node has to be known already, rather than being added.
+pub fn strict_host_checking(node_cfg: &NodeCfg, host_key_opts: &HostKeyOpts) -> bool {
+    !host_key_checking_disabled()
+        && node_cfg
+            .strict_host_checking
+            .unwrap_or(host_key_opts.no_update_known_hosts)
+}
+
+/// The known hosts file given with `--known-hosts`, if any.
+static KNOWN_HOSTS: OnceCell<PathBuf> = OnceCell::const_new();
+
+/// Uses `path` as the known hosts file instead of `~/.ssh/known_hosts`, after checking that it
+/// can be read (or created). `/dev/null` disables host key checking. Only the first call has an
+/// effect.
+pub fn set_known_hosts(path: &Path) -> Result<()> {
+    let path = if path.is_absolute() {
+        path.to_owned()
+    } else {
+        std::env::current_dir()
+            .context("Could not get the current directory")?
+            .join(path)
+    };
+    if path.exists() {
+        std::fs::File::open(&path).context(format!(
+            "Could not read known hosts file `{}`",
+            path.display()
         ))?;
+    } else {
+        match path.parent() {
+            Some(dir) if dir.is_dir() => {}
+            _ => {
+                return Err(anyhow!(
+                    "Neither known hosts file `{}` nor its directory exist",
+                    path.display()
+                ))
+            }
+        }
+    }
+    let _ = KNOWN_HOSTS.set(path);
+    if host_key_checking_disabled() {
+        warn!("Host key checking is DISABLED (--known-hosts /dev/null): connections to nodes can be intercepted without any warning");
+    }
+    Ok(())
+}
+
+/// Whether host keys aren't checked at all, because the known hosts file is `/dev/null`.
+fn host_key_checking_disabled() -> bool {
+    KNOWN_HOSTS.get().map(PathBuf::as_path) == Some(Path::new("/dev/null"))
+}
+
+/// Checks that `proxy` is of the form `host:port`, where `host` may be a bracketed IPv6 address.
+fn validate_socks_proxy(proxy: &str) -> Result<()> {
+    let invalid = || {
+        anyhow!(
+            "Invalid socksProxy `{}`, expected `host:port` (e.g. `localhost:1080` or `[::1]:1080`)",
+            proxy
+        )
+    };
+    let colon = proxy.rfind(':').ok_or_else(invalid)?;
+    let (host, port) = (&proxy[..colon], &proxy[colon + 1..]);
+    port.parse::<u16>().map_err(|_| invalid())?;
+    let host_valid = if host.starts_with('[') && host.ends_with(']') && host.len() > 2 {
+        host[1..host.len() - 1].parse::<Ipv6Addr>().is_ok()
+    } else {
+        !host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+    };
+    if !host_valid {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Returns the `ProxyCommand` that connects through the node's `socksProxy`, if it has one.
+/// This needs the OpenBSD `nc` on the local machine.
+fn socks_proxy_command(node_cfg: &NodeCfg) -> Result<Option<String>> {
+    match &node_cfg.socks_proxy {
+        Some(proxy) => {
+            validate_socks_proxy(proxy)?;
+            Ok(Some(format!("nc -X 5 -x {} %h %p", proxy)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The `ControlPath` template given with `--ssh-control-path`, if any.
+static CONTROL_PATH: OnceCell<String> = OnceCell::const_new();
+
+/// Sets the `ControlPath` template (e.g. `~/.ssh/henix-%r@%h:%p`) of the SSH master connections
+/// that rsync reuses. Only the first call has an effect.
+pub fn set_control_path(template: String) {
+    let _ = CONTROL_PATH.set(template);
+}
+
+/// Writes an SSH config file with the lines `options` (e.g. `ProxyCommand ...`),
+/// and otherwise uses the user's and the system's configuration. The file is only readable by
+/// the user, and is removed when the returned value is dropped; ssh only reads it when the
+/// session is established.
+fn write_ssh_config(options: &[String]) -> Result<NamedTempFile> {
+    // The first value obtained for an option wins, so the options go first.
+    let config = format!(
+        "{}\nInclude ~/.ssh/config\nInclude /etc/ssh/ssh_config\n",
+        options.join("\n")
+    );
+    let mut file = tempfile::Builder::new()
+        .prefix("henix-ssh-config-")
+        .tempfile()
+        .context("Could not create the SSH config file")?;
+    file.write_all(config.as_bytes()).context(format!(
+        "Could not write SSH config to `{}`",
+        file.path().display()
+    ))?;
+    Ok(file)
+}
+
+/// Whether `host` is an IPv6 address, which (unlike host names and IPv4 addresses) contains `:`.
+fn is_ipv6(host: &str) -> bool {
+    host.contains(':')
+}
+
+/// The user, host and port to connect to a node with. The SSH session, the master connection
+/// and rsync all derive their destination from this, so that they can't reach different places.
+pub struct RemoteTarget<'a> {
+    /// `None` leaves it to the SSH config, with `useSshConfig`.
+    pub user: Option<&'a str>,
+    /// Without brackets, even for IPv6 addresses.
+    pub host: &'a str,
+    pub port: Option<u16>,
+}
+
+impl<'a> RemoteTarget<'a> {
+    /// Parses the node's `location`, which is `[user@]host[:port]`, where `host` may be a
+    /// (bracketed, if followed by a port) IPv6 address. The user defaults to `root` (or to the
+    /// SSH config's, with `useSshConfig`), and the port to the node's `sshPort`, which must agree
+    /// with it if both are given.
+    pub fn of(node_cfg: &'a NodeCfg) -> Result<Self> {
+        let location = node_cfg.location.as_str();
+        let invalid = |reason: &str| {
+            anyhow!(
+                "Invalid location `{}` ({}), expected `[user@]host[:port]`",
+                location,
+                reason
+            )
+        };
+        let (user, address) = match location.split_once('@') {
+            Some(("", _)) => return Err(invalid("the user is empty")),
+            Some((user, address)) => (Some(user), address),
+            None if node_cfg.use_ssh_config => (None, location),
+            None => (Some("root"), location),
+        };
+        let (host, port) = match address.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| invalid("the `[` is never closed"))?;
+                match rest {
+                    "" => (host, None),
+                    _ => match rest.strip_prefix(':') {
+                        Some(port) => (host, Some(port)),
+                        None => return Err(invalid("unexpected characters after `]`")),
+                    },
+                }
+            }
+            None => match address.split_once(':') {
+                // More than one `:` means an IPv6 address without a port.
+                Some((_, rest)) if rest.contains(':') => (address, None),
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        if host.is_empty() || host.contains('@') {
+            return Err(invalid("the host is invalid"));
+        }
+        let port = match port {
+            Some(port) => Some(
+                port.parse::<u16>()
+                    .map_err(|_| invalid("the port is invalid"))?,
+            ),
+            None => None,
+        };
+        let port = match (port, node_cfg.ssh_port) {
+            (Some(port), Some(ssh_port)) if port != ssh_port => {
+                return Err(anyhow!(
+                    "The location `{}` has port {}, but sshPort is {}",
+                    location,
+                    port,
+                    ssh_port
+                ))
+            }
+            (port, ssh_port) => port.or(ssh_port),
+        };
+        Ok(RemoteTarget { user, host, port })
+    }
+
+    /// Returns the destination that `openssh` should connect to.
+    /// `openssh` splits the port off at the last `:`, so IPv6 addresses must not be bracketed.
+    pub fn ssh_destination(&self) -> String {
+        match self.port {
+            Some(port) => format!("ssh://{}{}:{}", self.user_prefix(), self.host, port),
+            None => self.ssh_cli_destination(),
+        }
+    }
+
+    /// Returns the destination for the `ssh` command, which gets the port from `ssh_args`.
+    fn ssh_cli_destination(&self) -> String {
+        format!("{}{}", self.user_prefix(), self.host)
+    }
+
+    /// Returns `{user}@`, or nothing if the user is left to the SSH config.
+    fn user_prefix(&self) -> String {
+        self.user
+            .map(|user| format!("{}@", user))
+            .unwrap_or_default()
+    }
+
+    /// Returns the rsync destination for `path` on the node. IPv6 addresses have to be
+    /// bracketed, since rsync splits the path off at the first `:`. The port is passed to rsync
+    /// with the `ssh_args` in `rsync_ssh_command`.
+    pub fn rsync_destination(&self, path: &str) -> String {
+        if is_ipv6(self.host) {
+            format!("{}[{}]:{}", self.user_prefix(), self.host, path)
+        } else {
+            format!("{}{}:{}", self.user_prefix(), self.host, path)
+        }
+    }
+}
+
+/// The `sshKeepaliveInterval` (in seconds) and `sshKeepaliveCountMax` of the node, with their
+/// defaults.
+pub fn keepalive(node_cfg: &NodeCfg) -> (u64, u32) {
+    (
+        node_cfg.ssh_keepalive_interval.unwrap_or(30),
+        node_cfg.ssh_keepalive_count_max.unwrap_or(3),
+    )
+}
+
+/// Returns the arguments `ssh` needs to connect to the node, other than the destination.
+fn ssh_args(node_cfg: &NodeCfg) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    if let Some(port) = RemoteTarget::of(node_cfg)?.port {
+        args.push("-p".to_owned());
+        args.push(port.to_string());
+    }
+    let (interval, count_max) = keepalive(node_cfg);
+    args.push("-o".to_owned());
+    args.push(format!("ServerAliveInterval={}", interval));
+    args.push("-o".to_owned());
+    args.push(format!("ServerAliveCountMax={}", count_max));
+    if let Some(proxy_command) = socks_proxy_command(node_cfg)? {
+        args.push("-o".to_owned());
+        args.push(format!("ProxyCommand={}", proxy_command));
+    }
+    // ssh tries all of them by itself.
+    let methods = auth_methods(node_cfg);
+    for method in methods {
+        if let SshAuthMethod::IdentityFile(path) = method {
+            args.push("-i".to_owned());
+            args.push(path.display().to_string());
+        }
+    }
+    if !methods.contains(&SshAuthMethod::Agent) {
+        args.push("-o".to_owned());
+        args.push("IdentitiesOnly=yes".to_owned());
+    }
+    if let Some(control_path) = CONTROL_PATH.get() {
+        args.push("-o".to_owned());
+        args.push(format!("ControlPath={}", control_path));
+    }
+    if let Some(known_hosts) = KNOWN_HOSTS.get() {
+        args.push("-o".to_owned());
+        args.push(format!("UserKnownHostsFile={}", known_hosts.display()));
+        if host_key_checking_disabled() {
+            args.push("-o".to_owned());
+            args.push("StrictHostKeyChecking=no".to_owned());
+        }
+    }
+    Ok(args)
+}
+
+/// Returns the SSH command that rsync should use (using `rsync -e`) to connect to the node. It
+/// checks the host key the same way as the session does.
+pub fn rsync_ssh_command(node_cfg: &NodeCfg, host_key_opts: &HostKeyOpts) -> Result<String> {
+    let mut ssh = "ssh".to_owned();
+    let args = ssh_args(node_cfg)?.into_iter().chain([
+        "-o".to_owned(),
+        strict_host_key_checking_option(node_cfg, host_key_opts),
+    ]);
+    for arg in args {
+        ssh.push(' ');
+        ssh.push_str(&util::shell_quote(&arg));
+    }
+    if CONTROL_PATH.get().is_some() {
+        // Still works if the master connection couldn't be started.
+        ssh.push_str(" -o ControlMaster=auto");
+    }
+    Ok(ssh)
+}
+
+/// An SSH master connection to a node at the `--ssh-control-path`, which rsync reuses instead of
+/// connecting again. It is closed when dropped.
+pub struct ControlMaster {
+    args: Vec<String>,
+    destination: String,
+}
+
+impl ControlMaster {
+    /// Starts a master connection to the node, if `--ssh-control-path` was given. It checks the
+    /// host key the same way as the session does.
+    pub async fn start(node_cfg: &NodeCfg, host_key_opts: &HostKeyOpts) -> Result<Option<Self>> {
+        if CONTROL_PATH.get().is_none() {
+            return Ok(None);
+        }
+        let master = ControlMaster {
+            args: ssh_args(node_cfg)?,
+            destination: RemoteTarget::of(node_cfg)?.ssh_cli_destination(),
+        };
+        // `-f` makes ssh go to the background once it is connected.
+        let out = tokio::process::Command::new("ssh")
+            .args(&master.args)
+            .args([
+                "-M",
+                "-N",
+                "-f",
+                "-o",
+                "ControlPersist=yes",
+                "-o",
+                "BatchMode=yes",
+                "-o",
+                &strict_host_key_checking_option(node_cfg, host_key_opts),
+            ])
+            .arg(&master.destination)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .context("Could not execute ssh to start the master connection")?;
+        if !out.status.success() {
+            return Err(anyhow!(
+                "Could not start the master connection: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        info!("Started SSH master connection");
+        Ok(Some(master))
+    }
+}
+
+impl Drop for ControlMaster {
+    fn drop(&mut self) {
+        // Waiting for ssh here would block the runtime, so it is only waited for in the
+        // background. It is started right away, so that it still runs if henix exits first.
+        let child = tokio::process::Command::new("ssh")
+            .args(&self.args)
+            .args(["-O", "exit"])
+            .arg(&self.destination)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(_) => {
+                warn!("Could not stop the SSH master connection");
+                return;
+            }
+        };
+        tokio::spawn(
+            async move {
+                match child.wait().await {
+                    Ok(status) if status.success() => {}
+                    _ => warn!("Could not stop the SSH master connection"),
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Limits the SSH sessions that are open at the same time (`--max-ssh-connections`).
+static CONNECTIONS: OnceCell<Arc<Semaphore>> = OnceCell::const_new();
+
+/// Allows at most `max` SSH sessions to be open at the same time. Only the first call has an
+/// effect.
+pub fn set_max_connections(max: usize) {
+    let _ = CONNECTIONS.set(Arc::new(Semaphore::new(max)));
+}
+
+/// Waits until another SSH session may be opened.
+pub async fn acquire_connection() -> Option<OwnedSemaphorePermit> {
+    let connections = CONNECTIONS.get()?;
+    match connections.clone().try_acquire_owned() {
+        Ok(permit) => Some(permit),
+        Err(_) => {
+            info!("Waiting for another SSH session to close (--max-ssh-connections)");
+            connections.clone().acquire_owned().await.ok()
+        }
+    }
+}
+
+/// Connects to the node with each of its `authMethods` in turn, until one is accepted.
+async fn connect_with_fallback(
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+    destination: &str,
+) -> Result<openssh::Session> {
+    let methods = auth_methods(node_cfg);
+    let mut last_error = None;
+    for method in methods {
+        let mut options = Vec::new();
+        match method {
+            SshAuthMethod::Agent => {}
+            SshAuthMethod::IdentityFile(path) => {
+                options.push(format!("IdentityFile \"{}\"", path.display()));
+                options.push("IdentitiesOnly yes".to_owned());
+            }
+            SshAuthMethod::Password(_) => {
+                return Err(anyhow!("Password authentication isn't supported"));
+            }
+        }
+        if methods.len() > 1 {
+            info!("Authenticating with {}", method);
+        }
+        let mut builder = openssh::SessionBuilder::default();
+        builder.known_hosts_check(known_hosts_policy(node_cfg, host_key_opts));
+        let (interval, count_max) = keepalive(node_cfg);
+        if interval > 0 {
+            builder.server_alive_interval(Duration::from_secs(interval));
+            options.push(format!("ServerAliveCountMax {}", count_max));
+        }
+        if let Some(proxy_command) = socks_proxy_command(node_cfg)? {
+            options.push(format!("ProxyCommand {}", proxy_command));
+        }
+        if let Some(control_path) = CONTROL_PATH.get() {
+            options.push("ControlMaster auto".to_owned());
+            options.push(format!("ControlPath {}", control_path));
+        }
+        if let Some(known_hosts) = KNOWN_HOSTS.get() {
+            options.push(format!("UserKnownHostsFile {}", known_hosts.display()));
+        }
+        // Kept until the session is established.
+        let config_file = if options.is_empty() {
+            None
+        } else {
+            Some(write_ssh_config(&options)?)
+        };
+        if let Some(config_file) = &config_file {
+            builder.config_file(config_file.path());
+        }
+        builder.control_directory("/tmp"); // Default is "./", which is not nice to nix-hash.
+        match builder.connect(destination).await {
+            Ok(session) => return Ok(session),
+            Err(e) if is_auth_failure(&e) => {
+                warn!("Could not authenticate with {}: {}", method, e);
+                last_error = Some(
+                    anyhow::Error::new(e)
+                        .context(format!("Could not authenticate with {}", method)),
+                );
+            }
+            Err(e) if is_host_key_failure(&e) => {
+                return Err(anyhow::Error::new(e).context(HenixError::HostKey {
+                    node: node_name.to_owned(),
+                }))
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(match last_error {
+        Some(e) => e.context(HenixError::Auth {
+            node: node_name.to_owned(),
+        }),
+        None => anyhow!("None of the node's `authMethods` is supported"),
+    })
+}
+
+/// Connects to the node, once `--max-ssh-connections` allows another session. The returned
+/// permit must be kept until the session is closed.
+pub async fn connect_to_node(
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+) -> Result<(openssh::Session, Option<OwnedSemaphorePermit>)> {
+    connect_retrying(node_name, node_cfg, host_key_opts, true).await
+}
+
+/// Tries to connect to the node once, without retrying, and without waiting for
+/// `--max-ssh-connections`, which is up to the caller.
+pub async fn try_connect(
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+) -> Result<openssh::Session> {
+    let destination = RemoteTarget::of(node_cfg)?.ssh_destination();
+    connect_with_fallback(node_name, node_cfg, host_key_opts, &destination).await
+}
+
+/// Connects to the node without waiting for `--max-ssh-connections`, for when henix already
+/// has a session open to it, whose permit also covers this one.
+pub async fn open_session(
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+) -> Result<openssh::Session> {
+    let (session, _) = connect_retrying(node_name, node_cfg, host_key_opts, false).await?;
+    Ok(session)
+}
+
+/// Connects to the node, retrying as its `retry` policy says. With `limited`, each attempt
+/// waits for `--max-ssh-connections`, and only the successful one keeps its permit, so that
+/// none is held while waiting to retry.
+async fn connect_retrying(
+    node_name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+    limited: bool,
+) -> Result<(openssh::Session, Option<OwnedSemaphorePermit>)> {
+    info!("Establishing SSH session");
+    let destination = &RemoteTarget::of(node_cfg)?.ssh_destination();
+    let connected = util::retry(
+        node_cfg.retry_policy(),
+        HenixError::is_retryable,
+        move || async move {
+            let permit = if limited {
+                acquire_connection().await
+            } else {
+                None
+            };
+            let session =
+                connect_with_fallback(node_name, node_cfg, host_key_opts, destination).await?;
+            Ok((session, permit))
+        },
+    )
+    .await
+    .context(HenixError::Connect {
+        node: node_name.to_owned(),
+    })?;
     info!("SSH session established");
-    Ok(remote)
+    Ok(connected)
+}
+
+/// Builds the command `program args...` to be run on the node,
+/// wrapping it in the node's `remoteShell` if it has one.
+pub fn node_command<'s, S: AsRef<str>>(
+    remote: &'s openssh::Session,
+    node_cfg: &NodeCfg,
+    program: &str,
+    args: &[S],
+) -> openssh::Command<'s> {
+    match &node_cfg.remote_shell {
+        None => {
+            let mut cmd = remote.command(program);
+            cmd.args(args);
+            cmd
+        }
+        Some(shell) => {
+            // The shell prefix is used verbatim, so it can contain its own arguments.
+            let mut cmd = remote.raw_command(shell);
+            let inner = std::iter::once(program)
+                .chain(args.iter().map(AsRef::as_ref))
+                .map(util::shell_quote)
+                .collect::<Vec<_>>()
+                .join(" ");
+            // `arg` quotes it again for the login shell.
+            cmd.arg(inner);
+            cmd
+        }
+    }
 }
 
 /// This proxies the output of an SSH command (`openssh::Command`)
 /// to the tracing logger, line-by-line.
-/// The child's stdout and stderr are both sent to `info!`.
+/// The child's stdout and stderr are both sent to `sink`.
 /// This is extremely similar to `util::proxy_output_to_logging`,
 /// but must be redone because `openssh::Command` and `tokio::process::Command`
 /// don't share a trait for this.
-#[tracing::instrument(name = "ssh_exec", skip(cmd))]
+#[tracing::instrument(name = "ssh_exec", skip(cmd, sink))]
 pub async fn proxy_output_to_logging<'a>(
     program: &str,
     mut cmd: openssh::Command<'a>,
+    sink: &OutputSink,
 ) -> Result<std::process::ExitStatus> {
     let mut child = cmd
         .stdin(Stdio::null())
@@ -66,17 +713,29 @@ pub async fn proxy_output_to_logging<'a>(
     let mut stdout_lines = stdout.lines();
     let mut stderr_lines = stderr.lines();
 
+    let mut stdout_done = false;
+    let mut stderr_done = false;
+    let mut heartbeat = Heartbeat::new();
     // While there is still output...
     loop {
         // race both streams
         // and process whichever one returns first.
         tokio::select! {
-            Ok(Some(line)) = stdout_lines.next_line() => {
-                info!("stdout: {}", line);
-            }
-            Ok(Some(line)) = stderr_lines.next_line() => {
-                info!("stderr: {}", line);
-            }
+            line = stdout_lines.next_line(), if !stdout_done => match line {
+                Ok(Some(line)) => {
+                    heartbeat.output();
+                    sink.line(program, "stdout", &line);
+                }
+                _ => stdout_done = true,
+            },
+            line = stderr_lines.next_line(), if !stderr_done => match line {
+                Ok(Some(line)) => {
+                    heartbeat.output();
+                    sink.line(program, "stderr", &line);
+                }
+                _ => stderr_done = true,
+            },
+            _ = heartbeat.beat(program), if !(stdout_done && stderr_done) => {}
             else => break
         }
     }
@@ -87,3 +746,166 @@ pub async fn proxy_output_to_logging<'a>(
         .await
         .context("Could not wait for child status")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ssh_args, RemoteTarget};
+    use crate::NodeCfg;
+    use serde_json::json;
+
+    fn node_cfg(location: &str, ssh_port: Option<u16>, use_ssh_config: bool) -> NodeCfg {
+        serde_json::from_value(json!({
+            "location": location,
+            "sshPort": ssh_port,
+            "useSshConfig": use_ssh_config,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn remote_target() {
+        // location, sshPort, useSshConfig, SSH destination, rsync destination of `/etc/henix`
+        let table: &[(&str, Option<u16>, bool, &str, &str)] = &[
+            (
+                "web-01.example.com",
+                None,
+                false,
+                "root@web-01.example.com",
+                "root@web-01.example.com:/etc/henix",
+            ),
+            (
+                "deploy@web-01.example.com:2222",
+                None,
+                false,
+                "ssh://deploy@web-01.example.com:2222",
+                "deploy@web-01.example.com:/etc/henix",
+            ),
+            ("web-01", None, true, "web-01", "web-01:/etc/henix"),
+            (
+                "web-01:2222",
+                None,
+                true,
+                "ssh://web-01:2222",
+                "web-01:/etc/henix",
+            ),
+            (
+                "10.0.0.12",
+                None,
+                false,
+                "root@10.0.0.12",
+                "root@10.0.0.12:/etc/henix",
+            ),
+            (
+                "10.0.0.12",
+                Some(2222),
+                false,
+                "ssh://root@10.0.0.12:2222",
+                "root@10.0.0.12:/etc/henix",
+            ),
+            (
+                "deploy@10.0.0.12:2222",
+                Some(2222),
+                false,
+                "ssh://deploy@10.0.0.12:2222",
+                "deploy@10.0.0.12:/etc/henix",
+            ),
+            (
+                "2001:db8::12",
+                None,
+                false,
+                "root@2001:db8::12",
+                "root@[2001:db8::12]:/etc/henix",
+            ),
+            (
+                "::1",
+                Some(2222),
+                false,
+                "ssh://root@::1:2222",
+                "root@[::1]:/etc/henix",
+            ),
+            (
+                "[2001:db8::12]",
+                None,
+                false,
+                "root@2001:db8::12",
+                "root@[2001:db8::12]:/etc/henix",
+            ),
+            (
+                "deploy@[2001:db8::12]:2222",
+                None,
+                false,
+                "ssh://deploy@2001:db8::12:2222",
+                "deploy@[2001:db8::12]:/etc/henix",
+            ),
+            (
+                "[fe80::1%eth0]",
+                None,
+                true,
+                "fe80::1%eth0",
+                "[fe80::1%eth0]:/etc/henix",
+            ),
+        ];
+        for &(location, ssh_port, use_ssh_config, ssh, rsync) in table {
+            let node_cfg = node_cfg(location, ssh_port, use_ssh_config);
+            let target = RemoteTarget::of(&node_cfg).unwrap();
+            assert_eq!(target.ssh_destination(), ssh, "{}", location);
+            assert_eq!(
+                target.rsync_destination("/etc/henix"),
+                rsync,
+                "{}",
+                location
+            );
+            // rsync connects with `ssh_args`, so it has to get the same port from them.
+            let args = ssh_args(&node_cfg).unwrap();
+            let rsync_port = args
+                .iter()
+                .position(|arg| arg == "-p")
+                .map(|i| args[i + 1].parse::<u16>().unwrap());
+            assert_eq!(rsync_port, target.port, "{}", location);
+            match target.port {
+                Some(port) => assert!(ssh.ends_with(&format!(":{}", port)), "{}", location),
+                None => assert!(!ssh.starts_with("ssh://"), "{}", location),
+            }
+            // And the same user and host, bracketed for IPv6.
+            let host = if target.host.contains(':') {
+                format!("[{}]", target.host)
+            } else {
+                target.host.to_owned()
+            };
+            assert_eq!(
+                rsync,
+                format!("{}{}:/etc/henix", target.user_prefix(), host),
+                "{}",
+                location
+            );
+            assert_eq!(
+                target.ssh_cli_destination(),
+                format!("{}{}", target.user_prefix(), target.host),
+                "{}",
+                location
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_remote_target() {
+        let table: &[(&str, Option<u16>)] = &[
+            ("", None),
+            ("@web-01", None),
+            ("deploy@", None),
+            ("a@b@web-01", None),
+            ("web-01:", None),
+            ("web-01:ssh", None),
+            ("web-01:65536", None),
+            ("[2001:db8::12", None),
+            ("[2001:db8::12]2222", None),
+            ("[]:2222", None),
+            ("web-01:2222", Some(22)),
+            ("[2001:db8::12]:2222", Some(22)),
+        ];
+        for &(location, ssh_port) in table {
+            let node_cfg = node_cfg(location, ssh_port, false);
+            assert!(RemoteTarget::of(&node_cfg).is_err(), "{}", location);
+        }
+    }
+}
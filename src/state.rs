@@ -0,0 +1,154 @@
+/// Local state about what was deployed where, so that it is known without contacting the nodes.
+/// It is stored in `$XDG_STATE_HOME/henix/state.json`, keyed by configuration directory and
+/// node name.
+use crate::history::NodeResult;
+use crate::util::{self, FileLock};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The last known deployment of a node.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeState {
+    pub hash: String,
+    /// The store path of the deployed system.
+    pub toplevel: String,
+    pub deployed_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    /// (configuration directory, (node name, state))
+    pub configs: BTreeMap<String, BTreeMap<String, NodeState>>,
+    /// The result of every node in the last deployment, for `--retry-failed`.
+    /// (configuration directory, (node name, result))
+    #[serde(default)]
+    pub last_runs: BTreeMap<String, BTreeMap<String, NodeResult>>,
+}
+
+impl State {
+    /// Returns the state of the nodes of the configuration in `cfg_dir`.
+    pub fn nodes(&self, cfg_dir: &Path) -> Option<&BTreeMap<String, NodeState>> {
+        self.configs.get(&config_key(cfg_dir))
+    }
+
+    /// Merges `nodes` into the state of the configuration in `cfg_dir`,
+    /// overwriting the state of nodes that already have one.
+    pub fn merge(&mut self, cfg_dir: &Path, nodes: BTreeMap<String, NodeState>) {
+        self.configs
+            .entry(config_key(cfg_dir))
+            .or_default()
+            .extend(nodes);
+    }
+
+    /// Returns the result of every node in the last deployment of the configuration in `cfg_dir`.
+    pub fn last_run(&self, cfg_dir: &Path) -> Option<&BTreeMap<String, NodeResult>> {
+        self.last_runs.get(&config_key(cfg_dir))
+    }
+}
+
+/// The same configuration directory should always have the same key, however it was specified.
+fn config_key(cfg_dir: &Path) -> String {
+    cfg_dir
+        .canonicalize()
+        .unwrap_or_else(|_| cfg_dir.to_owned())
+        .display()
+        .to_string()
+}
+
+fn state_dir() -> Result<PathBuf> {
+    let base = match std::env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            std::env::var_os("HOME")
+                .ok_or_else(|| anyhow!("Neither $XDG_STATE_HOME nor $HOME is set"))?,
+        )
+        .join(".local/state"),
+    };
+    Ok(base.join("henix"))
+}
+
+pub fn path() -> Result<PathBuf> {
+    Ok(state_dir()?.join("state.json"))
+}
+
+/// Locks the state file, releasing the lock when the returned value is dropped.
+/// The lock is held on a separate file, since saving replaces `state.json`.
+fn lock(exclusive: bool) -> Result<FileLock> {
+    let dir = state_dir()?;
+    std::fs::create_dir_all(&dir).context(format!(
+        "Could not create state directory `{}`",
+        dir.display()
+    ))?;
+    FileLock::acquire(&dir.join("state.lock"), exclusive)
+}
+
+/// Reads the state file. The caller must hold a lock.
+fn load_unlocked() -> Result<State> {
+    let path = path()?;
+    match std::fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .context(format!("State file `{}` is invalid", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(State::default()),
+        Err(e) => Err(e).context(format!("Could not read state file `{}`", path.display())),
+    }
+}
+
+/// Replaces the state file atomically. The caller must hold an exclusive lock.
+fn save_unlocked(state: &State) -> Result<()> {
+    let path = path()?;
+    let tmp_path = path.with_extension("json.tmp");
+    let contents = serde_json::to_vec_pretty(state).context("Could not serialize state")?;
+    std::fs::write(&tmp_path, contents)
+        .context(format!("Could not write `{}`", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, &path)
+        .context(format!("Could not replace state file `{}`", path.display()))
+}
+
+/// Reads the state file, returning an empty state if there is none.
+pub async fn load() -> Result<State> {
+    util::blocking(|| {
+        let _lock = lock(false)?;
+        load_unlocked()
+    })
+    .await
+}
+
+/// Replaces the state file with `state`.
+#[allow(dead_code)]
+pub async fn save(state: State) -> Result<()> {
+    util::blocking(move || {
+        let _lock = lock(true)?;
+        save_unlocked(&state)
+    })
+    .await
+}
+
+/// Merges `nodes` into the state of the configuration in `cfg_dir` in the state file.
+/// The whole read-modify-write is done under one lock, so that concurrent deployments don't
+/// overwrite each other's state.
+pub async fn merge(cfg_dir: &Path, nodes: BTreeMap<String, NodeState>) -> Result<()> {
+    let cfg_dir = cfg_dir.to_owned();
+    util::blocking(move || {
+        let _lock = lock(true)?;
+        let mut state = load_unlocked()?;
+        state.merge(&cfg_dir, nodes);
+        save_unlocked(&state)
+    })
+    .await
+}
+
+/// Records `results` as the last deployment of the configuration in `cfg_dir` in the state file.
+pub async fn record_run(cfg_dir: &Path, results: BTreeMap<String, NodeResult>) -> Result<()> {
+    let key = config_key(cfg_dir);
+    util::blocking(move || {
+        let _lock = lock(true)?;
+        let mut state = load_unlocked()?;
+        state.last_runs.insert(key, results);
+        save_unlocked(&state)
+    })
+    .await
+}
@@ -0,0 +1,254 @@
+/// A live view of a deployment, for `henix top`.
+use crate::events::{Event, EventKind, Phase};
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Utc};
+use crossterm::{
+    cursor,
+    event::{Event as TermEvent, EventStream as TermEventStream, KeyCode, KeyEvent, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+use futures::StreamExt;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+
+/// How many lines of output are kept for each node.
+const LOG_LINES: usize = 100;
+
+struct NodeView {
+    phase: Phase,
+    started: DateTime<Utc>,
+    finished: Option<DateTime<Utc>>,
+    log: VecDeque<String>,
+}
+
+#[derive(Default)]
+struct App {
+    nodes: BTreeMap<String, NodeView>,
+    /// The index of the selected node in `nodes`.
+    selected: usize,
+    /// The node whose log is shown.
+    expanded: Option<String>,
+}
+
+impl App {
+    fn apply(&mut self, event: Event) {
+        let timestamp = event.timestamp;
+        // Events about the whole deployment aren't shown.
+        let name = match event.node {
+            Some(name) => name,
+            None => return,
+        };
+        let node = self.nodes.entry(name).or_insert_with(|| NodeView {
+            phase: Phase::Connecting,
+            started: timestamp,
+            finished: None,
+            log: VecDeque::new(),
+        });
+        match event.kind {
+            EventKind::Phase { phase } => {
+                node.phase = phase;
+                if phase.is_final() {
+                    node.finished = Some(timestamp);
+                }
+            }
+            EventKind::Output { line } => {
+                if node.log.len() == LOG_LINES {
+                    node.log.pop_front();
+                }
+                node.log.push_back(line);
+            }
+            EventKind::RunStarted { .. }
+            | EventKind::PhaseFinished { .. }
+            | EventKind::NodeResult { .. }
+            | EventKind::RunFinished { .. } => {}
+        }
+    }
+
+    /// Handles a key press, returning whether to quit.
+    fn key(&mut self, key: KeyEvent) -> bool {
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            // Raw mode turns Ctrl-C into a key press.
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return true,
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => {
+                self.selected = (self.selected + 1).min(self.nodes.len().saturating_sub(1))
+            }
+            KeyCode::Enter => {
+                let selected = self.nodes.keys().nth(self.selected).cloned();
+                self.expanded = if self.expanded == selected {
+                    None
+                } else {
+                    selected
+                };
+            }
+            _ => {}
+        }
+        false
+    }
+
+    fn render(&self, out: &mut impl Write) -> io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        let now = Utc::now();
+        // (line, whether it is highlighted)
+        let mut lines = vec![(
+            format!(
+                "{:<24} {:<11} {:>8}  LAST OUTPUT",
+                "NODE", "PHASE", "ELAPSED"
+            ),
+            false,
+        )];
+        for (i, (name, node)) in self.nodes.iter().enumerate() {
+            let elapsed = node.finished.unwrap_or(now) - node.started;
+            lines.push((
+                format!(
+                    "{:<24} {:<11} {:>8}  {}",
+                    name,
+                    node.phase.name(),
+                    format_elapsed(elapsed),
+                    node.log.back().map_or("", String::as_str)
+                ),
+                i == self.selected,
+            ));
+            if self.expanded.as_ref() == Some(name) {
+                let skip = node.log.len().saturating_sub(height / 2);
+                for line in node.log.iter().skip(skip) {
+                    lines.push((format!("    {}", line), false));
+                }
+            }
+        }
+        let count = |phase| {
+            self.nodes
+                .values()
+                .filter(|node| node.phase == phase)
+                .count()
+        };
+        let footer = format!(
+            "{} nodes, {} done, {} failed, {} skipped, {} aborted | q: quit, up/down: select, enter: show log",
+            self.nodes.len(),
+            count(Phase::Done),
+            count(Phase::Failed),
+            count(Phase::Skipped),
+            count(Phase::Aborted)
+        );
+
+        queue!(out, terminal::Clear(ClearType::All))?;
+        for (row, (line, highlighted)) in lines.iter().take(height.saturating_sub(1)).enumerate() {
+            let line = line.chars().take(width).collect::<String>();
+            queue!(out, cursor::MoveTo(0, row as u16))?;
+            if *highlighted {
+                queue!(
+                    out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(line),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            } else {
+                queue!(out, Print(line))?;
+            }
+        }
+        let footer = footer.chars().take(width).collect::<String>();
+        queue!(
+            out,
+            cursor::MoveTo(0, height.saturating_sub(1) as u16),
+            Print(footer)
+        )?;
+        out.flush()
+    }
+}
+
+fn format_elapsed(elapsed: chrono::Duration) -> String {
+    let secs = elapsed.num_seconds().max(0);
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Sends the events in `file` to `tx`, and keeps waiting for more, like `tail -f`.
+async fn follow(file: tokio::fs::File, tx: mpsc::UnboundedSender<Event>) -> Result<()> {
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    loop {
+        let read = reader
+            .read_line(&mut line)
+            .await
+            .context("Could not read the event stream")?;
+        // A line without a newline is still being written, so wait for the rest of it.
+        if read == 0 || !line.ends_with('\n') {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            continue;
+        }
+        // Lines that aren't events are skipped, since there's nowhere to show the error.
+        if let Ok(event) = serde_json::from_str(&line) {
+            if tx.send(event).is_err() {
+                return Ok(());
+            }
+        }
+        line.clear();
+    }
+}
+
+/// Puts the terminal in raw mode on the alternate screen, restoring it when dropped.
+struct Terminal;
+
+impl Terminal {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode().context("Could not put the terminal in raw mode")?;
+        let terminal = Terminal;
+        execute!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)
+            .context("Could not switch to the alternate screen")?;
+        Ok(terminal)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let _ = execute!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Shows the progress of the deployment writing to `event_stream` until the user quits.
+pub async fn run(event_stream: &Path) -> Result<()> {
+    // If it is a named pipe, this waits for the deployment to open it.
+    let file = tokio::fs::File::open(event_stream).await.context(format!(
+        "Could not open event stream `{}`",
+        event_stream.display()
+    ))?;
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut reader = tokio::spawn(follow(file, tx));
+
+    let _terminal = Terminal::enter()?;
+    let mut app = App::default();
+    let mut stdout = io::stdout();
+    let mut keys = TermEventStream::new();
+    let mut ticks = tokio::time::interval(Duration::from_millis(500));
+    loop {
+        tokio::select! {
+            Some(event) = rx.recv() => app.apply(event),
+            _ = ticks.tick() => app.render(&mut stdout).context("Could not draw")?,
+            key = keys.next() => match key {
+                Some(Ok(TermEvent::Key(key))) => {
+                    if app.key(key) {
+                        break;
+                    }
+                    app.render(&mut stdout).context("Could not draw")?;
+                }
+                Some(Ok(_)) => app.render(&mut stdout).context("Could not draw")?,
+                Some(Err(e)) => return Err(e).context("Could not read from the terminal"),
+                None => break,
+            },
+            res = &mut reader => {
+                res.map_err(|e| anyhow!("The event stream reader panicked: {}", e))??;
+                break;
+            }
+        }
+    }
+    reader.abort();
+    Ok(())
+}
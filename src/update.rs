@@ -0,0 +1,167 @@
+/// Updating the flake inputs of the configuration, for `henix update`.
+use crate::{output::OutputSink, util};
+use anyhow::{anyhow, Context, Result};
+use chrono::{TimeZone, Utc};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use tokio::process;
+use tracing::{error, info};
+
+#[derive(Deserialize)]
+struct FlakeLock {
+    nodes: BTreeMap<String, LockNode>,
+    root: String,
+}
+
+#[derive(Deserialize)]
+struct LockNode {
+    locked: Option<Locked>,
+}
+
+/// The part of a locked input that tells versions apart.
+#[derive(Deserialize, PartialEq, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Locked {
+    rev: Option<String>,
+    nar_hash: Option<String>,
+    last_modified: Option<i64>,
+}
+
+impl fmt::Display for Locked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.rev, &self.nar_hash) {
+            (Some(rev), _) => write!(f, "{}", &rev[..rev.len().min(12)])?,
+            (None, Some(nar_hash)) => write!(f, "{}", nar_hash)?,
+            (None, None) => write!(f, "<unknown>")?,
+        }
+        let last_modified = self
+            .last_modified
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single());
+        if let Some(last_modified) = last_modified {
+            write!(f, " ({})", last_modified.format("%Y-%m-%d"))?;
+        }
+        Ok(())
+    }
+}
+
+/// An input of the flake whose locked version changed.
+#[derive(Debug)]
+pub struct InputChange {
+    pub name: String,
+    old: Option<Locked>,
+    new: Option<Locked>,
+}
+
+impl fmt::Display for InputChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, "{}: {} -> {}", self.name, old, new),
+            (None, Some(new)) => write!(f, "{}: added at {}", self.name, new),
+            (Some(old), None) => write!(f, "{}: removed (was {})", self.name, old),
+            (None, None) => write!(f, "{}: unchanged", self.name),
+        }
+    }
+}
+
+/// Returns the locked inputs in `lock` (the contents of a `flake.lock`), by name.
+fn locked_inputs(lock: &[u8]) -> Result<BTreeMap<String, Locked>> {
+    let lock: FlakeLock = serde_json::from_slice(lock).context("Could not parse `flake.lock`")?;
+    let root = lock.root;
+    Ok(lock
+        .nodes
+        .into_iter()
+        .filter(|(name, _)| *name != root)
+        .filter_map(|(name, node)| Some((name, node.locked?)))
+        .collect())
+}
+
+/// Compares the inputs locked in `old` and `new`.
+fn diff(mut old: BTreeMap<String, Locked>, new: BTreeMap<String, Locked>) -> Vec<InputChange> {
+    let mut changes = Vec::new();
+    for (name, new) in new {
+        let old = old.remove(&name);
+        if old.as_ref() != Some(&new) {
+            changes.push(InputChange {
+                name,
+                old,
+                new: Some(new),
+            });
+        }
+    }
+    changes.extend(old.into_iter().map(|(name, old)| InputChange {
+        name,
+        old: Some(old),
+        new: None,
+    }));
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    changes
+}
+
+/// Updates the inputs of the flake in `cfg_dir` (all of them if `inputs` is empty), and returns
+/// the ones whose locked version changed. If the update fails, `flake.lock` is restored.
+pub async fn update_inputs(cfg_dir: &Path, inputs: &[String]) -> Result<Vec<InputChange>> {
+    let lock_path = cfg_dir.join("flake.lock");
+    let before = match std::fs::read(&lock_path) {
+        Ok(before) => Some(before),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            return Err(e).context(format!("Could not read `{}`", lock_path.display()));
+        }
+    };
+
+    let mut args = Vec::new();
+    if inputs.is_empty() {
+        args.extend(["flake", "update"]);
+    } else {
+        args.extend(["flake", "lock"]);
+        for input in inputs {
+            args.extend(["--update-input", input.as_str()]);
+        }
+    }
+    let mut cmd = process::Command::new(util::bin("nix"));
+    cmd.current_dir(cfg_dir).args(&args);
+    info!("Running `nix {}`", args.join(" "));
+    let status = util::proxy_output_to_logging("nix", cmd, &OutputSink::Log)
+        .await
+        .context(format!("Could not execute `nix {}`", args.join(" ")));
+    let failed = match status {
+        Ok(status) if status.success() => None,
+        Ok(status) => Some(anyhow!("`nix {}` failed with {}", args.join(" "), status)),
+        Err(e) => Some(e),
+    };
+    if let Some(e) = failed {
+        // Don't leave a partially updated lock file behind without saying so.
+        let after = std::fs::read(&lock_path).ok();
+        if after == before {
+            return Err(e.context("Could not update the flake inputs (`flake.lock` is unchanged)"));
+        }
+        let restored = match &before {
+            Some(before) => std::fs::write(&lock_path, before),
+            None => std::fs::remove_file(&lock_path),
+        };
+        return Err(match restored {
+            Ok(()) => e.context("Could not update the flake inputs (`flake.lock` was restored)"),
+            Err(restore_error) => {
+                error!(
+                    "Could not restore `{}`, check it for partial changes (e.g. with `git diff`): {}",
+                    lock_path.display(),
+                    restore_error
+                );
+                e.context(format!(
+                    "Could not update the flake inputs, and `{}` may be partially updated",
+                    lock_path.display()
+                ))
+            }
+        });
+    }
+
+    let after =
+        std::fs::read(&lock_path).context(format!("Could not read `{}`", lock_path.display()))?;
+    let old = match before {
+        Some(before) => locked_inputs(&before)?,
+        None => BTreeMap::new(),
+    };
+    Ok(diff(old, locked_inputs(&after)?))
+}
@@ -1,20 +1,326 @@
-use anyhow::{Context, Result};
-use std::process::Stdio;
+use crate::output::{Heartbeat, OutputSink, StderrTail};
+use anyhow::{anyhow, Context, Result};
+use futures::future::BoxFuture;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncBufReadExt;
 use tokio::io::BufReader;
 use tokio::process;
-use tracing::{info, warn};
+use tokio::sync::Mutex;
+use tokio::time::{self, Interval, MissedTickBehavior};
+use tracing::warn;
+
+/// Returns the directory that henix caches things in, `$XDG_CACHE_HOME/henix`.
+pub fn cache_dir() -> Result<PathBuf> {
+    let base = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            std::env::var_os("HOME")
+                .ok_or_else(|| anyhow!("Neither $XDG_CACHE_HOME nor $HOME is set"))?,
+        )
+        .join(".cache"),
+    };
+    Ok(base.join("henix"))
+}
+
+/// Quotes `s` so that a POSIX shell reads it back as a single word.
+pub fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@+,%".contains(c))
+    {
+        return s.to_owned();
+    }
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+/// Formats `d` for humans, e.g. `2m 34s` or `45s`.
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Runs `f`, which may block (e.g. waiting for a `FileLock`), on a thread where blocking is
+/// fine, so that it doesn't hold up other tasks.
+pub async fn blocking<T, F>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .context("A blocking task failed")?
+}
+
+/// An advisory lock (using `flock`) on a file, released when dropped.
+pub struct FileLock(File);
+
+impl FileLock {
+    /// Locks the file at `path`, creating it if needed, and waits until the lock is acquired.
+    pub fn acquire(path: &Path, exclusive: bool) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .context(format!("Could not open lock file `{}`", path.display()))?;
+        Self::lock(file, path, exclusive)
+    }
+
+    /// Like `acquire`, but doesn't create the file, and returns `None` if it doesn't exist.
+    pub fn acquire_existing(path: &Path, exclusive: bool) -> Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).context(format!("Could not open lock file `{}`", path.display()))
+            }
+        };
+        Self::lock(file, path, exclusive).map(Some)
+    }
+
+    fn lock(file: File, path: &Path, exclusive: bool) -> Result<Self> {
+        let operation = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        // SAFETY: `file` is open for the duration of the call.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("Could not lock `{}`", path.display()));
+        }
+        Ok(FileLock(file))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // SAFETY: `self.0` is still open.
+        unsafe { libc::flock(self.0.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Hands out at most a fixed number of tokens per second, shared between tasks.
+pub struct RateLimiter {
+    interval: Mutex<Interval>,
+}
+
+impl RateLimiter {
+    /// `per_second` must be positive and finite.
+    pub fn new(per_second: f64) -> Self {
+        let mut interval = time::interval(Duration::from_secs_f64(1.0 / per_second));
+        // Don't burst to catch up if nobody asked for a token for a while.
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        RateLimiter {
+            interval: Mutex::new(interval),
+        }
+    }
+
+    /// Waits until a token is available.
+    pub async fn acquire(&self) {
+        self.interval.lock().await.tick().await;
+    }
+}
+
+/// How often, and how patiently, an operation that can fail transiently (e.g. connecting to a
+/// node) is attempted.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RetryPolicy {
+    /// How many times the operation is attempted in total, including the first time.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. The wait doubles after every retry.
+    pub initial_backoff_ms: u64,
+    /// The longest wait between two attempts.
+    pub max_backoff_ms: u64,
+    /// Waits a random time between half of the backoff and all of it, so that many nodes
+    /// failing at once don't all retry at the same moment.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Attempts the operation only once.
+    pub const NONE: RetryPolicy = RetryPolicy {
+        max_attempts: 1,
+        initial_backoff_ms: 0,
+        max_backoff_ms: 0,
+        jitter: false,
+    };
+
+    /// How long to wait after the failed attempt number `attempt` (starting at 1).
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX);
+        let ms = self
+            .initial_backoff_ms
+            .saturating_mul(factor)
+            .min(self.max_backoff_ms);
+        if !self.jitter || ms == 0 {
+            return Duration::from_millis(ms);
+        }
+        // Not worth a dependency on `rand`, the clock is random enough to spread out retries.
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|now| now.subsec_nanos() as u64)
+            .unwrap_or(0);
+        Duration::from_millis(ms / 2 + nanos % (ms - ms / 2 + 1))
+    }
+}
+
+/// Runs `f` until it succeeds, at most `policy.max_attempts` times, waiting between attempts
+/// as `policy` says. Errors that `retryable` returns `false` for aren't retried. Returns the
+/// error of the last attempt if none succeeded.
+pub async fn retry<F, Fut, T, E>(
+    policy: &RetryPolicy,
+    retryable: impl Fn(&E) -> bool,
+    mut f: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, E>>,
+    E: fmt::Display,
+{
+    let max_attempts = policy.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Err(e) if attempt < max_attempts && retryable(&e) => {
+                let backoff = policy.backoff(attempt);
+                warn!(
+                    "Attempt {}/{} failed, retrying in {}ms: {:#}",
+                    attempt,
+                    max_attempts,
+                    backoff.as_millis(),
+                    e
+                );
+                time::sleep(backoff).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Asks `question` on the terminal, and returns the answer, trimmed and in lowercase. No answer
+/// (e.g. when stdin is not a terminal) is an empty one.
+pub fn ask(question: &str) -> Result<String> {
+    use std::io::Write;
+    eprint!("{} ", question);
+    io::stderr().flush().ok();
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .context("Could not read the answer")?;
+    Ok(answer.trim().to_lowercase())
+}
+
+/// Asks `question` on the terminal, and returns whether it was answered with yes. Anything else,
+/// including no answer, counts as no.
+pub fn confirm(question: &str) -> Result<bool> {
+    let answer = ask(&format!("{} [y/N]", question))?;
+    Ok(matches!(answer.as_str(), "y" | "yes"))
+}
+
+/// Returns the local program to run for `name` (e.g. `nix-hash`), which can be overridden with
+/// `HENIX_{NAME}_BIN` (e.g. `HENIX_NIX_HASH_BIN`), e.g. to pin a store path.
+pub fn bin(name: &str) -> OsString {
+    let var = format!("HENIX_{}_BIN", name.to_uppercase().replace('-', "_"));
+    std::env::var_os(var).unwrap_or_else(|| name.into())
+}
+
+/// Runs local commands to completion. Code that only needs the output of a command runs it
+/// through this, so that it can be run differently, e.g. with canned outputs.
+pub trait CommandRunner: fmt::Debug + Send + Sync {
+    fn output(&self, cmd: process::Command) -> BoxFuture<'static, io::Result<Output>>;
+}
+
+/// Runs commands with `tokio::process`.
+#[derive(Debug)]
+pub struct TokioRunner;
+
+impl CommandRunner for TokioRunner {
+    fn output(&self, mut cmd: process::Command) -> BoxFuture<'static, io::Result<Output>> {
+        Box::pin(async move { cmd.output().await })
+    }
+}
+
+/// Runs commands like `proxy_output_to_logging`, sending their output to a sink as it comes,
+/// e.g. to show build logs. The output only has the lines on stdout, and the last lines on
+/// stderr.
+pub struct LoggingRunner(pub OutputSink);
+
+impl fmt::Debug for LoggingRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LoggingRunner")
+    }
+}
+
+impl CommandRunner for LoggingRunner {
+    fn output(&self, cmd: process::Command) -> BoxFuture<'static, io::Result<Output>> {
+        let stdout = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = OutputSink::Capture(Box::new(self.0.clone()), stdout.clone());
+        let (sink, stderr_tail) = StderrTail::wrap(&sink);
+        Box::pin(async move {
+            let program = Path::new(cmd.as_std().get_program())
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let status = proxy_output_to_logging(&program, cmd, &sink)
+                .await
+                .map_err(|e| {
+                    e.downcast::<io::Error>()
+                        .unwrap_or_else(|e| io::Error::other(format!("{:#}", e)))
+                })?;
+            let join = |lines: Vec<String>| lines.join("\n").into_bytes();
+            let stdout = std::mem::take(&mut *stdout.lock().unwrap());
+            Ok(Output {
+                status,
+                stdout: join(stdout),
+                stderr: join(stderr_tail.lines()),
+            })
+        })
+    }
+}
 
 /// This proxies the output of a Tokio command (`tokio::process::Command`)
 /// to the tracing logger, line-by-line.
-/// The child's stdout and stderr are both sent to `info!`.
+/// The child's stdout and stderr are both sent to `sink`.
 /// This is extremely similar to `ssh::proxy_output_to_logging`,
 /// but must be redone because `openssh::Command` and `tokio::process::Command`
 /// don't share a trait for this.
-#[tracing::instrument(name = "exec", skip(cmd))]
+#[tracing::instrument(name = "exec", skip(cmd, sink))]
 pub async fn proxy_output_to_logging(
     program: &str,
     mut cmd: process::Command,
+    sink: &OutputSink,
 ) -> Result<std::process::ExitStatus> {
     let mut child = cmd
         .stdin(Stdio::null())
@@ -46,17 +352,29 @@ pub async fn proxy_output_to_logging(
     let mut stdout_lines = stdout.lines();
     let mut stderr_lines = stderr.lines();
 
+    let mut stdout_done = false;
+    let mut stderr_done = false;
+    let mut heartbeat = Heartbeat::new();
     // While there is still output...
     loop {
         // race both streams
         // and process whichever one returns first.
         tokio::select! {
-            Ok(Some(line)) = stdout_lines.next_line() => {
-                info!("stdout: {}", line);
-            }
-            Ok(Some(line)) = stderr_lines.next_line() => {
-                info!("stderr: {}", line);
-            }
+            line = stdout_lines.next_line(), if !stdout_done => match line {
+                Ok(Some(line)) => {
+                    heartbeat.output();
+                    sink.line(program, "stdout", &line);
+                }
+                _ => stdout_done = true,
+            },
+            line = stderr_lines.next_line(), if !stderr_done => match line {
+                Ok(Some(line)) => {
+                    heartbeat.output();
+                    sink.line(program, "stderr", &line);
+                }
+                _ => stderr_done = true,
+            },
+            _ = heartbeat.beat(program), if !(stdout_done && stderr_done) => {}
             else => break
         }
     }
@@ -67,3 +385,68 @@ pub async fn proxy_output_to_logging(
         .await
         .context("Could not wait for child status")
 }
+
+/// Whether `addr` is a loopback address, or in the subnet of one of the machine's network
+/// interfaces.
+pub fn is_on_local_subnet(addr: IpAddr) -> bool {
+    if addr.is_loopback() {
+        return true;
+    }
+    let mut ifaddrs = std::ptr::null_mut();
+    // SAFETY: `ifaddrs` is only used if `getifaddrs` succeeds, and freed below.
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        warn!(
+            "Could not list the network interfaces: {}",
+            std::io::Error::last_os_error()
+        );
+        return false;
+    }
+    let mut found = false;
+    let mut cur = ifaddrs;
+    while !cur.is_null() {
+        // SAFETY: `cur` is an entry of the list returned by `getifaddrs`, which isn't freed yet.
+        let ifa = unsafe { &*cur };
+        if let (Some(ip), Some(mask)) = (sockaddr_ip(ifa.ifa_addr), sockaddr_ip(ifa.ifa_netmask)) {
+            let in_subnet = match (addr, ip, mask) {
+                (IpAddr::V4(addr), IpAddr::V4(ip), IpAddr::V4(mask)) => {
+                    u32::from(addr) & u32::from(mask) == u32::from(ip) & u32::from(mask)
+                }
+                (IpAddr::V6(addr), IpAddr::V6(ip), IpAddr::V6(mask)) => {
+                    u128::from(addr) & u128::from(mask) == u128::from(ip) & u128::from(mask)
+                }
+                _ => false,
+            };
+            if in_subnet {
+                found = true;
+                break;
+            }
+        }
+        cur = ifa.ifa_next;
+    }
+    // SAFETY: `ifaddrs` was returned by `getifaddrs`, and isn't used anymore.
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    found
+}
+
+/// Returns the IP address in `addr`, if it is an IPv4 or IPv6 address.
+fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    // SAFETY: `addr` is non-null, and `sa_family` says which kind of address it points to.
+    unsafe {
+        match i32::from((*addr).sa_family) {
+            libc::AF_INET => {
+                let addr = &*(addr as *const libc::sockaddr_in);
+                Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    addr.sin_addr.s_addr,
+                ))))
+            }
+            libc::AF_INET6 => {
+                let addr = &*(addr as *const libc::sockaddr_in6);
+                Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+            }
+            _ => None,
+        }
+    }
+}
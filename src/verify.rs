@@ -0,0 +1,112 @@
+/// Drift detection, for `henix verify`.
+use crate::{
+    meta,
+    remote::{self, RemoteExecutor},
+    HostKeyOpts, NodeCfg,
+};
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::path::Path;
+
+pub enum NodeStatus {
+    /// The running system is the one henix last deployed, with where it was deployed from.
+    InSync { origin: meta::Origin },
+    /// The running system is not the one henix last deployed,
+    /// e.g. because someone ran `nixos-rebuild` by hand.
+    Drifted {
+        expected: String,
+        actual: String,
+        origin: meta::Origin,
+    },
+    /// There is no `/etc/henix/latest` on the node.
+    NeverDeployed,
+    /// The last deployment has no metadata, e.g. because it was deployed by an older henix.
+    MetadataMissing { cfg_hash: String },
+    /// The node could not be checked.
+    Error(anyhow::Error),
+}
+
+impl NodeStatus {
+    /// Whether this status should make `henix verify` fail.
+    pub fn is_failure(&self) -> bool {
+        matches!(self, NodeStatus::Drifted { .. } | NodeStatus::Error(_))
+    }
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeStatus::InSync { origin } => {
+                write!(f, "in sync")?;
+                if let Some(label) = &origin.label {
+                    write!(f, " (`{}`)", label)?;
+                }
+                match origin.summary() {
+                    Some(summary) => write!(f, ", {}", summary),
+                    None => Ok(()),
+                }
+            }
+            NodeStatus::Drifted {
+                expected,
+                actual,
+                origin,
+            } => {
+                write!(f, "drifted (expected {}, running {})", expected, actual)?;
+                match origin.summary() {
+                    Some(summary) => write!(f, "; last {}", summary),
+                    None => Ok(()),
+                }
+            }
+            NodeStatus::NeverDeployed => write!(f, "never deployed by henix"),
+            NodeStatus::MetadataMissing { cfg_hash } => {
+                write!(f, "no metadata for the latest deployment ({})", cfg_hash)
+            }
+            NodeStatus::Error(e) => write!(f, "could not be checked: {:#}", e),
+        }
+    }
+}
+
+/// Compares the running system of the node against the one henix last deployed,
+/// using an existing connection.
+pub async fn check(remote: &dyn RemoteExecutor, node_cfg: &NodeCfg) -> Result<NodeStatus> {
+    let latest =
+        match meta::remote_output(remote, node_cfg, "readlink", &["/etc/henix/latest"]).await? {
+            Some(latest) => latest,
+            None => return Ok(NodeStatus::NeverDeployed),
+        };
+    let cfg_hash = Path::new(&latest)
+        .file_name()
+        .and_then(|hash| hash.to_str())
+        .ok_or_else(|| anyhow!("/etc/henix/latest points to `{}`", latest))?
+        .to_owned();
+    let (expected, origin) = match meta::read(remote, node_cfg, &cfg_hash).await? {
+        Some(meta) => (meta.toplevel, meta.origin),
+        None => return Ok(NodeStatus::MetadataMissing { cfg_hash }),
+    };
+    let actual = meta::remote_output(remote, node_cfg, "readlink", &["-f", "/run/current-system"])
+        .await?
+        .ok_or_else(|| anyhow!("Could not resolve /run/current-system"))?;
+    if actual == expected {
+        Ok(NodeStatus::InSync { origin })
+    } else {
+        Ok(NodeStatus::Drifted {
+            expected,
+            actual,
+            origin,
+        })
+    }
+}
+
+/// Compares the running system of the node against the one henix last deployed.
+#[tracing::instrument(name = "verify", skip(name, node_cfg, host_key_opts), fields(node = name))]
+pub async fn verify_node(
+    name: &str,
+    node_cfg: &NodeCfg,
+    host_key_opts: &HostKeyOpts,
+) -> NodeStatus {
+    let status = match remote::connect(name, node_cfg, host_key_opts).await {
+        Ok(remote) => check(&remote, node_cfg).await,
+        Err(e) => Err(e),
+    };
+    status.unwrap_or_else(NodeStatus::Error)
+}
@@ -0,0 +1,229 @@
+//! Parses outputs of real Nix commands, captured in `tests/fixtures`, and checks how failing
+//! ones are reported.
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::{ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+
+use futures::future::BoxFuture;
+use henix::nix::{self, CommandRunner, NixError, NixOpts};
+use tokio::process;
+
+/// Answers every command with the same output, and records the commands it was given.
+#[derive(Debug)]
+struct FakeNix {
+    exit_code: i32,
+    stdout: &'static str,
+    stderr: &'static str,
+    commands: Mutex<Vec<String>>,
+}
+
+impl FakeNix {
+    fn opts(stdout: &'static str) -> (NixOpts, Arc<FakeNix>) {
+        FakeNix::with(0, stdout, "")
+    }
+
+    fn with(exit_code: i32, stdout: &'static str, stderr: &'static str) -> (NixOpts, Arc<FakeNix>) {
+        let fake = Arc::new(FakeNix {
+            exit_code,
+            stdout,
+            stderr,
+            commands: Mutex::new(Vec::new()),
+        });
+        let opts = NixOpts {
+            runner: fake.clone(),
+            ..NixOpts::default()
+        };
+        (opts, fake)
+    }
+
+    fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+impl CommandRunner for FakeNix {
+    fn output(&self, cmd: process::Command) -> BoxFuture<'static, io::Result<Output>> {
+        let cmd = cmd.as_std();
+        let command = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.commands.lock().unwrap().push(command);
+        let out = Output {
+            status: ExitStatus::from_raw(self.exit_code << 8),
+            stdout: self.stdout.as_bytes().to_vec(),
+            stderr: self.stderr.as_bytes().to_vec(),
+        };
+        Box::pin(async move { Ok(out) })
+    }
+}
+
+#[tokio::test]
+async fn eval_deploy_cfg() {
+    let (opts, fake) = FakeNix::opts(include_str!("fixtures/nix-eval-deploy.json"));
+    let cfg: henix::DeployCfg = nix::eval(&opts, ".#deploy", None).await.unwrap();
+    assert_eq!(fake.commands(), ["nix eval --json -- .#deploy"]);
+    assert!(cfg.policy.require_change_ref);
+    assert_eq!(cfg.nodes.keys().collect::<Vec<_>>(), ["db-01", "web-01"]);
+    let db = &cfg.nodes["db-01"];
+    assert_eq!(db.location, "10.0.0.12");
+    assert_eq!(db.ssh_port, Some(2222));
+    assert_eq!(db.escalation, Some(henix::Escalation::Sudo));
+    assert_eq!(db.log_level.as_deref(), Some("debug"));
+    let retry_policy = db.retry_policy.as_ref().unwrap();
+    assert_eq!(retry_policy.max_attempts, 5);
+    assert_eq!(retry_policy.initial_backoff_ms, 1000);
+    let web = &cfg.nodes["web-01"];
+    assert_eq!(web.location, "web-01.example.com");
+    assert_eq!(web.ssh_port, None);
+    assert_eq!(web.activation_timeout_secs, Some(600));
+    assert!(web.retry_policy.is_none());
+}
+
+#[tokio::test]
+async fn flake_metadata() {
+    let (opts, fake) = FakeNix::opts(include_str!("fixtures/nix-flake-metadata.json"));
+    let metadata = nix::flake_metadata(&opts, ".").await.unwrap();
+    assert_eq!(fake.commands(), ["nix flake metadata --json ."]);
+    assert_eq!(
+        metadata.description.as_deref(),
+        Some("Deployment of the example.com servers")
+    );
+    assert_eq!(
+        metadata.url,
+        "git+file:///home/ops/servers?ref=refs/heads/main&rev=0f8c6e8a2d4b1c9e7a5f3d2b1a0c9e8d7f6a5b4c"
+    );
+    assert_eq!(
+        metadata.path,
+        "/nix/store/7xw4ivd5ah7lnm6zdmhfnqba4n6pp4qv-source"
+    );
+    assert_eq!(
+        metadata.revision.as_deref(),
+        Some("0f8c6e8a2d4b1c9e7a5f3d2b1a0c9e8d7f6a5b4c")
+    );
+    assert_eq!(metadata.last_modified, Some(1709290133));
+    assert_eq!(
+        metadata.input_nar_hash("nixpkgs"),
+        Some("sha256-cy6ArO4k5qTx+l5o+0mL9f5fa86tYUX3ozE1S+Txlds=")
+    );
+    // `utils` follows `henix/flake-utils`, so it has no node of its own.
+    assert_eq!(metadata.input_nar_hash("utils"), None);
+    assert_eq!(metadata.input_nar_hash("systems"), None);
+}
+
+#[tokio::test]
+async fn build() {
+    let (opts, fake) = FakeNix::opts(include_str!("fixtures/nix-build.json"));
+    let toplevel = nix::toplevel_installable("web-01");
+    let built = nix::build(&opts, &[&toplevel]).await.unwrap();
+    assert_eq!(
+        fake.commands(),
+        ["nix build --json --no-link .#nixosConfigurations.\"web-01\".config.system.build.toplevel"]
+    );
+    assert_eq!(built.len(), 1);
+    assert_eq!(
+        built[0].drv_path,
+        "/nix/store/q3h4d8yw9r2f1m0l5k7j6x8c9v0b1n2a-nixos-system-web-01-24.05.20240301.1536926.drv"
+    );
+    assert_eq!(
+        built[0].outputs["out"],
+        "/nix/store/h8k2d5yw3r9f1m6l0k7j4x8c2v5b1n9z-nixos-system-web-01-24.05.20240301.1536926"
+    );
+}
+
+#[tokio::test]
+async fn eval_fails() {
+    let stderr = "error: flake 'path:/home/ops/servers' does not provide attribute 'packages.x86_64-linux.deploy', 'legacyPackages.x86_64-linux.deploy' or 'deploy'\n";
+    let (opts, _) = FakeNix::with(1, "", stderr);
+    let e = nix::eval::<serde_json::Value>(&opts, ".#deploy", None)
+        .await
+        .unwrap_err();
+    match &e {
+        NixError::Failed {
+            command, exit_code, ..
+        } => {
+            assert_eq!(command, "nix eval --json -- .#deploy");
+            assert_eq!(*exit_code, Some(1));
+        }
+        _ => panic!("Not a failed command: {:?}", e),
+    }
+    assert_eq!(e.stderr(), Some(stderr));
+}
+
+#[tokio::test]
+async fn eval_malformed_json() {
+    // Cut off, e.g. because Nix was killed.
+    let (opts, _) = FakeNix::with(
+        0,
+        "{\"nodes\":{\"web-01\":{\"loca",
+        "warning: Git tree is dirty\n",
+    );
+    let e = nix::eval::<henix::DeployCfg>(&opts, ".#deploy", None)
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(e, NixError::Json { .. }), "{:?}", e);
+    assert_eq!(e.stderr(), Some("warning: Git tree is dirty\n"));
+}
+
+#[tokio::test]
+async fn eval_json_of_the_wrong_shape() {
+    let (opts, _) = FakeNix::opts("{\"nodes\":[\"web-01\"]}");
+    let e = nix::eval::<henix::DeployCfg>(&opts, ".#deploy", None)
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(e, NixError::Json { .. }), "{:?}", e);
+}
+
+#[tokio::test]
+async fn flake_metadata_fails() {
+    let (opts, _) = FakeNix::with(1, "", "error: path '/home/ops/servers' is not a flake\n");
+    let e = nix::flake_metadata(&opts, ".").await.unwrap_err();
+    assert!(
+        matches!(
+            e,
+            NixError::Failed {
+                exit_code: Some(1),
+                ..
+            }
+        ),
+        "{:?}",
+        e
+    );
+}
+
+#[tokio::test]
+async fn eval_many() {
+    #[derive(Debug, serde::Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    struct Record {
+        web_host_name: String,
+        web_ports: Vec<u16>,
+        db_state_version: String,
+    }
+    let (opts, fake) = FakeNix::opts(include_str!("fixtures/nix-eval-many.json"));
+    let record: Record = nix::eval_many(
+        &opts,
+        ".#nixosConfigurations",
+        &[
+            ("web-host-name", "\"web-01\".config.networking.hostName"),
+            (
+                "web-ports",
+                "\"web-01\".config.networking.firewall.allowedTCPPorts",
+            ),
+            ("db-state-version", "\"db-01\".config.system.stateVersion"),
+        ],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        fake.commands(),
+        ["nix eval --json --apply x: { \"web-host-name\" = x.\"web-01\".config.networking.hostName; \"web-ports\" = x.\"web-01\".config.networking.firewall.allowedTCPPorts; \"db-state-version\" = x.\"db-01\".config.system.stateVersion; } -- .#nixosConfigurations"]
+    );
+    assert_eq!(record.web_host_name, "web-01");
+    assert_eq!(record.web_ports, [22, 80, 443]);
+    assert_eq!(record.db_state_version, "23.11");
+}
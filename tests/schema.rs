@@ -0,0 +1,13 @@
+//! Checks that `docs/deploy.schema.json` is what `henix schema` prints, so that it changes along
+//! with the configuration.
+
+#[test]
+fn committed_schema_is_up_to_date() {
+    let schema = serde_json::to_value(schemars::schema_for!(henix::DeployCfg)).unwrap();
+    let committed: serde_json::Value =
+        serde_json::from_str(include_str!("../docs/deploy.schema.json")).unwrap();
+    assert!(
+        schema == committed,
+        "docs/deploy.schema.json is out of date, regenerate it with `henix schema > docs/deploy.schema.json`"
+    );
+}